@@ -0,0 +1,286 @@
+use http_types::headers::AUTHORIZATION;
+use serde::Deserialize;
+use tide::{Response, StatusCode};
+
+// The info! logging macro comes from crate::azure_function::logger
+use crate::azure_function::{AzureFnLoggerExt, AzureFnOutputs, AzureFnRequestExt};
+use crate::config::ReloadableConfig;
+use crate::ipn_handler::{process_ipn, AUDIT_BINDING};
+use crate::AppRequest;
+
+/// Check that the request carries the configured admin secret, as a bearer token.
+///
+/// Admin endpoints are disabled entirely when no secret is configured.
+pub(crate) fn check_admin_secret(req: &AppRequest) -> tide::Result<()> {
+    let Some(secret) = req.state().admin_secret.as_deref() else {
+        return Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            "Admin endpoints are disabled",
+        ));
+    };
+
+    let token = req
+        .header(AUTHORIZATION)
+        .and_then(|auth| auth.last().as_str().strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), secret.as_bytes()) => Ok(()),
+        _ => Err(tide::Error::from_str(
+            StatusCode::Unauthorized,
+            "Missing or incorrect admin secret",
+        )),
+    }
+}
+
+/// Compare secrets without returning early on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Re-run membership processing for a PayPal IPN, for support cases.
+///
+/// Takes the raw IPN body, as found in PayPal's IPN history, as JSON: `{ "ipn": "..." }`.
+/// The IPN is verified with PayPal exactly as if PayPal had sent it.
+pub async fn admin_resync(mut req: AppRequest) -> tide::Result<Response> {
+    let mut logger = req.logger();
+
+    check_admin_secret(&req)?;
+
+    #[derive(Debug, Deserialize)]
+    struct Incoming {
+        ipn: String,
+    }
+
+    let Incoming { ipn } = req.body_json().await?;
+
+    if req.state().config().read_only {
+        info!(logger, "Admin resync: deferred, READ_ONLY is on");
+        return Ok(StatusCode::ServiceUnavailable.into());
+    }
+
+    // Only the transaction id, as the IPN is full of the payer's personal information. See `LOG_RAW_IPN`.
+    #[derive(Debug, Deserialize)]
+    struct TxnIdOnly {
+        txn_id: Option<String>,
+    }
+    let txn_id = serde_qs::Config::new(5, false)
+        .deserialize_str::<TxnIdOnly>(&ipn)
+        .ok()
+        .and_then(|ipn| ipn.txn_id);
+    info!(
+        logger,
+        "Admin resync: re-processing IPN for Transaction ID \"{}\"",
+        txn_id.as_deref().unwrap_or("(none)")
+    );
+
+    let mut res = process_ipn(logger.clone(), req.state(), ipn, false).await;
+
+    // Changes made by a resync are the admin's, rather than PayPal's.
+    if let Ok(res) = &mut res {
+        if let Some(AzureFnOutputs(mut outputs)) = res.ext::<AzureFnOutputs>().cloned() {
+            if let Some(event) = outputs.get_mut(AUDIT_BINDING) {
+                event["actor"] = "admin_resync".into();
+                res.insert_ext(AzureFnOutputs(outputs));
+            }
+        }
+    }
+
+    match &res {
+        Ok(res) => info!(logger, "Admin resync: finished - status: {}", res.status()),
+        Err(error) => info!(
+            logger,
+            "Admin resync: failed - status: {} - error: {}",
+            error.status(),
+            error
+        ),
+    }
+    res
+}
+
+/// Re-read the reloadable configuration from the environment, such as thresholds & email templates.
+///
+/// Http clients & api keys are not reloaded, those still require a restart.
+/// Invalid configuration is refused, leaving the current configuration in place.
+pub async fn admin_reload(req: AppRequest) -> tide::Result<Response> {
+    let mut logger = req.logger();
+
+    check_admin_secret(&req)?;
+
+    let config = match ReloadableConfig::from_env() {
+        Ok(config) => config,
+        Err(error) => {
+            info!(logger, "Admin reload: invalid configuration: {}", error);
+            return Ok(Response::builder(StatusCode::BadRequest)
+                .body(format!("Invalid configuration: {}", error))
+                .into());
+        }
+    };
+
+    // As at startup, queued IPNs have no output bindings for audit events.
+    if config.audit_queue && req.state().ipn_queue.is_some() {
+        info!(
+            logger,
+            "Admin reload: invalid configuration: AUDIT_QUEUE with IPN_QUEUE_SIZE"
+        );
+        return Ok(Response::builder(StatusCode::BadRequest)
+            .body("Invalid configuration: AUDIT_QUEUE can't be used with IPN_QUEUE_SIZE")
+            .into());
+    }
+
+    info!(logger, "Admin reload: configuration reloaded");
+    req.state().set_config(config);
+
+    Ok(StatusCode::Ok.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{
+        azure_server, fake_upstreams, get_member, invocation, ipn, post, server, test_config,
+        test_state, MockEmail, LIST_ID,
+    };
+
+    fn resync(ipn: &str, secret: Option<&str>) -> http_types::Request {
+        let mut req = post(
+            "/Admin-Resync",
+            "application/json",
+            &json!({ "ipn": ipn }).to_string(),
+        );
+        if let Some(secret) = secret {
+            req.insert_header(AUTHORIZATION, format!("Bearer {}", secret));
+        }
+        req
+    }
+
+    #[async_std::test]
+    async fn resync_verifies_then_upserts_the_member() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+
+        let res: http_types::Response = server(state)
+            .respond(resync(
+                &ipn("TXN1", "ada@example.com", "20.00"),
+                Some("admin-secret"),
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(http.sent_to("paypal.test").len(), 1);
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["FNAME"], "Ada");
+        assert_ne!(member["merge_fields"]["EXPIRES"], "");
+    }
+
+    #[async_std::test]
+    async fn resync_of_an_invalid_ipn_changes_nothing() {
+        let (http, members) = fake_upstreams("INVALID");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.paypal_verify_attempts = 1;
+        state.set_config(config);
+
+        let res: http_types::Response = server(state)
+            .respond(resync(
+                &ipn("TXN1", "ada@example.com", "20.00"),
+                Some("admin-secret"),
+            ))
+            .await
+            .expect("response");
+
+        assert!(!res.status().is_success());
+        // Only looked up, while verifying.
+        assert!(http
+            .sent_to("mailchimp.test")
+            .iter()
+            .all(|sent| sent.method == http_types::Method::Get));
+        assert!(members.lock().expect("members").is_empty());
+    }
+
+    #[async_std::test]
+    async fn reload_changes_the_effective_configuration() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let server = server(test_state(&http, &MockEmail::default()));
+        let reload = || {
+            let mut req = post("/Admin-Reload", "application/json", "");
+            req.insert_header(AUTHORIZATION, "Bearer admin-secret");
+            req
+        };
+        assert_eq!(server.state().config().min_amount_for(None), 10.0);
+
+        // The only test which reads the configuration from the environment, see `test_vars`.
+        env::set_var("TEMPLATE_MEMBERSHIP_CHECK", "d-check");
+        env::set_var("TEMPLATE_MEMBERSHIP_NOTFOUND", "d-notfound");
+        env::set_var("MIN_AMOUNT", "15");
+        let reloaded: http_types::Response = server.respond(reload()).await.expect("response");
+        env::set_var("MEMBERSHIP_CHECK_REDIRECT_URL", "https://evil.example.com/");
+        let refused: http_types::Response = server.respond(reload()).await.expect("response");
+        for var in [
+            "TEMPLATE_MEMBERSHIP_CHECK",
+            "TEMPLATE_MEMBERSHIP_NOTFOUND",
+            "MIN_AMOUNT",
+            "MEMBERSHIP_CHECK_REDIRECT_URL",
+        ] {
+            env::remove_var(var);
+        }
+
+        assert_eq!(reloaded.status(), StatusCode::Ok);
+        // Invalid configuration leaves the reloaded configuration in place.
+        assert_eq!(refused.status(), StatusCode::BadRequest);
+        let config = server.state().config();
+        assert_eq!(config.min_amount_for(None), 15.0);
+        assert_eq!(
+            config.membership_check_redirect.as_str(),
+            "https://squamishaccess.ca/membership-check-response"
+        );
+    }
+
+    #[async_std::test]
+    async fn resync_requires_the_admin_secret() {
+        for secret in [None, Some("wrong-secret")] {
+            let (http, _) = fake_upstreams("VERIFIED");
+            let state = test_state(&http, &MockEmail::default());
+
+            let res: http_types::Response = server(state)
+                .respond(resync(&ipn("TXN1", "ada@example.com", "20.00"), secret))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert!(http.sent().is_empty());
+        }
+    }
+
+    #[async_std::test]
+    async fn resync_changes_are_audited_as_the_admin() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.audit_queue = true;
+        state.set_config(config);
+
+        let mut res: http_types::Response = azure_server(state)
+            .respond(invocation(
+                "/Admin-Resync",
+                "POST",
+                json!({
+                    "Content-Type": ["application/json"],
+                    "Authorization": ["Bearer admin-secret"],
+                }),
+                &json!({ "ipn": ipn("TXN1", "ada@example.com", "20.00") }).to_string(),
+            ))
+            .await
+            .expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 200);
+        let event = &out["Outputs"][AUDIT_BINDING];
+        assert_eq!(event["actor"], "admin_resync");
+        assert_eq!(event["email"], "ada@example.com");
+        assert_eq!(event["txn_id"], "TXN1");
+    }
+}
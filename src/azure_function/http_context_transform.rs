@@ -1,11 +1,16 @@
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 
+use async_std::io::ReadExt;
 use async_std::sync::RwLock;
+use futures::FutureExt;
 use serde_json::{json, Map, Value};
 use tide::http::headers::CONTENT_TYPE;
-use tide::{Body, Middleware, Next, Request, Result, StatusCode};
+use tide::http::Method;
+use tide::{Body, Middleware, Next, Request, Response, Result, StatusCode};
+use tracing::Instrument;
 
-use super::AzureFnLoggerInner;
+use super::{AzureFnLoggerInner, LocalLog};
 
 /// Middleware for non-forwarding Azure Functions
 ///
@@ -36,7 +41,8 @@ use super::AzureFnLoggerInner;
 /// ```
 #[derive(Clone, Debug, Default)]
 pub struct AzureFnMiddleware {
-    _priv: (),
+    max_envelope_len: Option<usize>,
+    local_log: Option<LocalLog>,
 }
 
 struct AzureFnMiddlewareHasBeenRun;
@@ -45,7 +51,24 @@ impl AzureFnMiddleware {
     /// Create a new instance of `AzureFnMiddleware`.
     #[must_use]
     pub fn new() -> Self {
-        Self { _priv: () }
+        Self {
+            max_envelope_len: None,
+            local_log: None,
+        }
+    }
+
+    /// Also write each invocation's log lines to `local_log`.
+    #[must_use]
+    pub fn local_log(mut self, local_log: LocalLog) -> Self {
+        self.local_log = Some(local_log);
+        self
+    }
+
+    /// Reject invocation payloads over `len` bytes with a `413`, rather than buffering them whole.
+    #[must_use]
+    pub fn max_envelope_len(mut self, len: usize) -> Self {
+        self.max_envelope_len = Some(len);
+        self
     }
 
     /// Log a request and a response.
@@ -66,7 +89,27 @@ impl AzureFnMiddleware {
 
         let mut logs = vec![];
 
-        let azure_function_payload: Value = req.body_json().await?;
+        let payload = match self.max_envelope_len {
+            Some(max_len) => {
+                // A declared length can be rejected before reading anything, others are read up to just over the cap.
+                let mut payload = vec![];
+                if req.len().is_none_or(|len| len <= max_len) {
+                    req.take_body()
+                        .take(max_len as u64 + 1)
+                        .read_to_end(&mut payload)
+                        .await?;
+                }
+                if req.len().is_some_and(|len| len > max_len) || payload.len() > max_len {
+                    return oversized_envelope(&invocation_id, max_len);
+                }
+                payload
+            }
+            None => req.body_bytes().await?,
+        };
+        let azure_function_payload: Value = serde_json::from_slice(&payload)?;
+        if let Some(diagnostic) = check_envelope(&azure_function_payload) {
+            logs.push(diagnostic);
+        }
         if let Some(external_req_body) = azure_function_payload.pointer("/Data/req/Body") {
             if let Value::String(body) = external_req_body {
                 // Re-write the request body to the extracted external request body.
@@ -77,24 +120,108 @@ impl AzureFnMiddleware {
                         .to_string(),
                 );
             }
-        } else {
+        } else if azure_function_payload.pointer("/Data/req").is_some() {
             logs.push(
                 "AzureFnMiddleware Error: \"/Data/req/Body\" not found, check function.json"
                     .to_string(),
             );
+        } else {
+            // Non-http triggers, such as timers, have no request.
+            logs.push(
+                "AzureFnMiddleware: \"/Data/req\" not found, not an http trigger or check function.json"
+                    .to_string(),
+            );
+        }
+
+        // Re-write the request headers to the extracted external request headers.
+        // Without this, handlers would only see the headers of the request from the Azure host.
+        if let Some(Value::Object(external_req_headers)) =
+            azure_function_payload.pointer("/Data/req/Headers")
+        {
+            for (name, values) in external_req_headers {
+                let values = match values {
+                    Value::Array(values) => values
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    Value::String(value) => value.to_owned(),
+                    _ => continue,
+                };
+                req.insert_header(name.as_str(), values);
+            }
+        }
+
+        // Re-write the request method to the external request method.
+        // The Azure host always POSTs to us, so routes must still be registered as POST.
+        if let Some(method) = azure_function_payload
+            .pointer("/Data/req/Method")
+            .and_then(Value::as_str)
+        {
+            match method.parse::<Method>() {
+                Ok(method) => AsMut::<tide::http::Request>::as_mut(&mut req).set_method(method),
+                Err(_) => logs.push(format!(
+                    "AzureFnMiddleware Error: \"/Data/req/Method\" not a valid method: {}",
+                    method
+                )),
+            }
         }
 
         let logger = AzureFnLoggerInner {
             logs,
-            invocation_id,
+            invocation_id: invocation_id.clone(),
         };
         let logger = Arc::new(RwLock::new(logger));
         req.set_ext(logger.clone());
 
-        let mut res = next.run(req).await; // Continue middleware stack.
+        // A span for the whole invocation, for tracing subscribers.
+        // Handlers may add their own spans within this one.
+        let span = tracing::info_span!(
+            "azure_function",
+            invocation_id = %invocation_id,
+            outcome = tracing::field::Empty,
+        );
+
+        // Continue middleware stack.
+        //
+        // A panicking handler would otherwise leave Azure without a response or any logs.
+        let mut panic_log = None;
+        let mut res = match AssertUnwindSafe(next.run(req).instrument(span.clone()))
+            .catch_unwind()
+            .await
+        {
+            Ok(res) => res,
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| (*message).to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "(no message)".to_string());
+                panic_log = Some(format!(
+                    "{} AzureFnMiddleware Error: handler panicked: {}",
+                    invocation_id, message
+                ));
+                Response::new(StatusCode::InternalServerError)
+            }
+        };
+
+        span.record("outcome", u16::from(res.status()));
+
+        // Log lines are already prefixed with the invocation id, this lets an error response be matched up with them.
+        if res.status().is_client_error() || res.status().is_server_error() {
+            res.insert_header(INVOCATION_ID_HEADER, invocation_id.as_str());
+        }
 
         let logger =
             Arc::try_unwrap(logger).expect("Logger not being free here is a fundimental logic bug");
+        let mut logs = logger.into_inner().logs;
+        logs.extend(panic_log);
+
+        if let Some(local_log) = &self.local_log {
+            if let Err(error) = local_log.write(&logs).await {
+                log::warn!("LOCAL_LOG could not be written: {}", error);
+            }
+        }
 
         // Transform our headers into an iterator of JSON key/value pairs, and then construct a JSON object from it.
         let headers_iter = res.iter().map(|(name, values)| {
@@ -110,7 +237,7 @@ impl AzureFnMiddleware {
             )
         });
 
-        let out = json!({
+        let mut out = json!({
             "Outputs": {
                 "res": {
                     // The external response status code.
@@ -122,8 +249,14 @@ impl AzureFnMiddleware {
                 }
             },
             // This is currently the only way to log from a custom handler.
-            "Logs": logger.into_inner().logs,
+            "Logs": logs,
         });
+        // Other output bindings, e.g. queues, which must also be declared in function.json.
+        if let Some(AzureFnOutputs(outputs)) = res.ext::<AzureFnOutputs>().cloned() {
+            if let Some(Value::Object(out)) = out.get_mut("Outputs") {
+                out.extend(outputs);
+            }
+        }
 
         res.set_body(Body::from_json(&out)?);
         res.remove_header(CONTENT_TYPE);
@@ -136,9 +269,241 @@ impl AzureFnMiddleware {
     }
 }
 
+/// Values for output bindings other than the http response, by binding name, set as a response extension.
+#[derive(Clone, Debug, Default)]
+pub struct AzureFnOutputs(pub Map<String, Value>);
+
+/// Response header with the invocation id, on error responses.
+pub const INVOCATION_ID_HEADER: &str = "X-Invocation-Id";
+
+/// The response to Azure for a payload over the `max_envelope_len` cap, with a `413` for the external response.
+fn oversized_envelope(invocation_id: &str, max_len: usize) -> Result {
+    let out = json!({
+        "Outputs": {
+            "res": {
+                "statusCode": StatusCode::PayloadTooLarge,
+                "headers": {},
+                "body": "",
+            }
+        },
+        "Logs": [format!(
+            "{} AzureFnMiddleware Error: invocation payload is over the {} byte limit, not handled",
+            invocation_id, max_len
+        )],
+    });
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(Body::from_json(&out)?);
+    Ok(res)
+}
+
+/// Top-level keys of the payload Azure sends to custom handlers.
+const ENVELOPE_KEYS: &[&str] = &["Data", "Metadata"];
+
+/// Describe what is missing from, or unexpected in, the Azure invocation payload, if anything.
+///
+/// Changes to Azure's contract otherwise only show up as handlers misbehaving.
+fn check_envelope(payload: &Value) -> Option<String> {
+    let Value::Object(payload) = payload else {
+        return Some(
+            "AzureFnMiddleware Error: invocation payload is not a JSON object".to_string(),
+        );
+    };
+
+    let missing: Vec<_> = ENVELOPE_KEYS
+        .iter()
+        .filter(|key| !payload.contains_key(**key))
+        .copied()
+        .collect();
+    let unexpected: Vec<_> = payload
+        .keys()
+        .filter(|key| !ENVELOPE_KEYS.contains(&key.as_str()))
+        .map(String::as_str)
+        .collect();
+
+    if missing.is_empty() && unexpected.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "AzureFnMiddleware: unexpected invocation payload - missing keys: [{}] - unexpected keys: [{}]",
+        missing.join(", "),
+        unexpected.join(", ")
+    ))
+}
+
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for AzureFnMiddleware {
     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
         self.transform(req, next).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Url};
+
+    use super::*;
+    use crate::azure_function::AzureFnLogMiddleware;
+
+    #[allow(clippy::panic, reason = "A handler bug, for the middleware to catch")]
+    async fn panics(_: Request<()>) -> Result<Response> {
+        panic!("handler bug")
+    }
+
+    #[async_std::test]
+    async fn panicking_handlers_get_a_wrapped_500() {
+        let mut server = tide::new();
+        server.with(AzureFnMiddleware::new());
+        server.at("/Panics").post(panics);
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/Panics").expect("url"),
+        );
+        req.insert_header("X-Azure-Functions-InvocationId", "invocation-1");
+        req.set_body(json!({"Data": {"req": {"Body": ""}}, "Metadata": {}}));
+        let mut res: tide::http::Response = server.respond(req).await.expect("response");
+
+        // Azure only takes a 200, the external response is in the envelope.
+        assert_eq!(res.status(), StatusCode::Ok);
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 500);
+        assert_eq!(
+            out["Outputs"]["res"]["headers"][INVOCATION_ID_HEADER.to_lowercase()],
+            "invocation-1"
+        );
+        let logs = out["Logs"].as_array().expect("logs");
+        assert!(logs.iter().any(|line| line
+            .as_str()
+            .is_some_and(|line| line.ends_with("handler panicked: handler bug"))));
+    }
+
+    #[test]
+    fn envelope_diagnostics_list_missing_and_unexpected_keys() {
+        assert_eq!(check_envelope(&json!({"Data": {}, "Metadata": {}})), None);
+        assert_eq!(
+            check_envelope(&json!({"Data": {}, "Extra": 1})).as_deref(),
+            Some("AzureFnMiddleware: unexpected invocation payload - missing keys: [Metadata] - unexpected keys: [Extra]")
+        );
+        assert!(check_envelope(&json!([]))
+            .is_some_and(|diagnostic| diagnostic.contains("not a JSON object")));
+    }
+
+    #[async_std::test]
+    async fn malformed_envelopes_are_still_processed() {
+        let mut server = tide::new();
+        server.with(AzureFnMiddleware::new());
+        server.at("/Ok").post(|_: Request<()>| async { Ok("") });
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/Ok").expect("url"),
+        );
+        req.set_body(json!({"Data": {"req": {"Body": ""}}}));
+        let mut res: tide::http::Response = server.respond(req).await.expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 200);
+        assert!(out["Logs"].as_array().expect("logs").iter().any(|line| line
+            .as_str()
+            .is_some_and(|line| line.contains("missing keys: [Metadata]"))));
+    }
+
+    async fn fails(_: Request<()>) -> Result<Response> {
+        Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "upstream unavailable",
+        ))
+    }
+
+    #[async_std::test]
+    async fn errors_carry_the_invocation_id() {
+        let mut server = tide::new();
+        server.with(AzureFnMiddleware::new());
+        server.with(AzureFnLogMiddleware::new());
+        server.at("/Fails").post(fails);
+
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/Fails").expect("url"),
+        );
+        req.insert_header("X-Azure-Functions-InvocationId", "invocation-2");
+        req.set_body(json!({"Data": {"req": {"Body": ""}}, "Metadata": {}}));
+        let mut res: tide::http::Response = server.respond(req).await.expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 500);
+        assert_eq!(
+            out["Outputs"]["res"]["headers"][INVOCATION_ID_HEADER.to_lowercase()],
+            "invocation-2"
+        );
+        let logs = out["Logs"].as_array().expect("logs");
+        assert!(logs.iter().any(|line| line
+            .as_str()
+            .is_some_and(|line| line.starts_with("invocation-2")
+                && line.contains("Internal error")
+                && line.contains("upstream unavailable"))));
+    }
+
+    fn capped_server() -> tide::Server<()> {
+        let mut server = tide::new();
+        server.with(AzureFnMiddleware::new().max_envelope_len(64));
+        server
+            .at("/Ok")
+            .post(|_: Request<()>| async { Ok("handled") });
+        server
+    }
+
+    fn envelope_request(body: Body) -> tide::http::Request {
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://localhost/Ok").expect("url"),
+        );
+        req.insert_header("X-Azure-Functions-InvocationId", "invocation-3");
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn over_cap_envelopes_are_rejected_cleanly() {
+        let envelope =
+            json!({"Data": {"req": {"Body": "x".repeat(100)}}, "Metadata": {}}).to_string();
+        // With a declared length, and streamed without one.
+        for body in [
+            Body::from_string(envelope.clone()),
+            Body::from_reader(
+                async_std::io::Cursor::new(envelope.clone().into_bytes()),
+                None,
+            ),
+        ] {
+            let mut res: tide::http::Response = capped_server()
+                .respond(envelope_request(body))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::Ok);
+            let out: Value = res.body_json().await.expect("envelope");
+            assert_eq!(out["Outputs"]["res"]["statusCode"], 413);
+            assert_eq!(out["Outputs"]["res"]["body"], "");
+            assert_eq!(
+                out["Logs"],
+                json!(["invocation-3 AzureFnMiddleware Error: invocation payload is over the 64 byte limit, not handled"])
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn envelopes_within_the_cap_are_handled() {
+        let envelope = json!({"Data": {"req": {"Body": ""}}, "Metadata": {}}).to_string();
+        let mut res: tide::http::Response = capped_server()
+            .respond(envelope_request(Body::from_reader(
+                async_std::io::Cursor::new(envelope.into_bytes()),
+                None,
+            )))
+            .await
+            .expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 200);
+        assert_eq!(out["Outputs"]["res"]["body"], "handled");
+    }
+}
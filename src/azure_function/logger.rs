@@ -1,6 +1,6 @@
 use tide::{Middleware, Next, Request, Result};
 
-use super::{AzureFnLogger, AzureFnLoggerExt};
+use super::{AzureFnLoggerExt, AzureFnRequestExt};
 
 #[macro_export]
 macro_rules! info {
@@ -37,14 +37,27 @@ impl LogMiddleware {
         }
         req.set_ext(LogMiddlewareHasBeenRun);
 
-        let mut logger = req
-            .ext_mut::<AzureFnLogger>()
-            .expect("Must install AzureFnMiddleware")
-            .clone();
+        let mut logger = req.logger();
 
         let start = std::time::Instant::now();
         let response = next.run(req).await; // Continue middleware stack.
         let status = response.status();
+        let duration = start.elapsed();
+
+        // The same as fields, for querying in e.g. App Insights, via a tracing subscriber.
+        if status.is_server_error() || status.is_client_error() {
+            tracing::warn!(
+                status_code = u16::from(status),
+                duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+                error_type = response.error().and_then(tide::Error::type_name),
+                "{} error",
+                if status.is_server_error() {
+                    "Internal"
+                } else {
+                    "Client"
+                },
+            );
+        }
 
         if status.is_server_error() {
             if let Some(error) = response.error() {
@@ -52,14 +65,14 @@ impl LogMiddleware {
                     error,
                     error.type_name(),
                     format_args!("{} - {}", status as u16, status.canonical_reason()),
-                    start.elapsed(),
+                    duration,
                 )).await;
             } else {
                 logger
                     .log(format!(
                         "Internal error. status: {}, duration: {:?}",
                         format_args!("{} - {}", status as u16, status.canonical_reason()),
-                        start.elapsed(),
+                        duration,
                     ))
                     .await;
             }
@@ -71,7 +84,7 @@ impl LogMiddleware {
                         error,
                         error.type_name(),
                         format_args!("{} - {}", status as u16, status.canonical_reason()),
-                        start.elapsed(),
+                        duration,
                     ))
                     .await;
             } else {
@@ -79,7 +92,7 @@ impl LogMiddleware {
                     .log(format!(
                         "Client error. status: {}, duration: {:?}",
                         format_args!("{} - {}", status as u16, status.canonical_reason()),
-                        start.elapsed(),
+                        duration,
                     ))
                     .await;
             }
@@ -94,3 +107,47 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for LogMiddleware {
         self.log(req, next).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Url};
+    use tide::{Response, StatusCode};
+
+    use super::*;
+    use crate::test_support::TraceRecorder;
+
+    async fn rejects(_: Request<()>) -> Result<Response> {
+        Err(tide::Error::new(
+            StatusCode::Conflict,
+            std::io::Error::other("already processing"),
+        ))
+    }
+
+    #[async_std::test]
+    async fn errors_are_traced_with_structured_fields() {
+        let recorder = TraceRecorder::default();
+        let _default = recorder.set_default();
+        let mut server = tide::new();
+        server.with(LogMiddleware::new());
+        server.at("/").post(rejects);
+
+        let req =
+            tide::http::Request::new(Method::Post, Url::parse("http://localhost/").expect("url"));
+        let res: tide::http::Response = server.respond(req).await.expect("response");
+
+        assert_eq!(res.status(), StatusCode::Conflict);
+        let events = recorder.events();
+        let event = events
+            .iter()
+            .find(|event| event.get("message").map(String::as_str) == Some("Client error"))
+            .expect("error event");
+        assert_eq!(event.get("status_code").map(String::as_str), Some("409"));
+        assert!(event
+            .get("duration_ms")
+            .is_some_and(|duration| duration.parse::<u64>().is_ok()));
+        assert_eq!(
+            event.get("error_type").map(String::as_str),
+            Some("std::io::error::Error")
+        );
+    }
+}
@@ -1,14 +1,19 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use async_std::fs::OpenOptions;
+use async_std::io::{self, WriteExt};
 use async_std::sync::RwLock;
 
 mod http_context_transform;
 
 #[macro_use]
 pub mod logger;
+mod require_https;
 
-pub use http_context_transform::AzureFnMiddleware;
+pub use http_context_transform::{AzureFnMiddleware, AzureFnOutputs, INVOCATION_ID_HEADER};
 pub use logger::LogMiddleware as AzureFnLogMiddleware;
+pub use require_https::RequireHttpsMiddleware as AzureFnRequireHttpsMiddleware;
 
 pub type AzureFnLogger = Arc<RwLock<AzureFnLoggerInner>>;
 
@@ -18,6 +23,75 @@ pub struct AzureFnLoggerInner {
     invocation_id: String,
 }
 
+/// Access to the `AzureFnLogger` of a request.
+pub trait AzureFnRequestExt {
+    /// The request's logger.
+    ///
+    /// Without `AzureFnMiddleware`, e.g. in tests, this is a detached logger whose lines only go to `tracing`.
+    fn logger(&self) -> AzureFnLogger;
+}
+
+impl<State> AzureFnRequestExt for tide::Request<State> {
+    fn logger(&self) -> AzureFnLogger {
+        self.ext::<AzureFnLogger>()
+            .cloned()
+            .unwrap_or_else(|| detached_logger("(no AzureFnMiddleware)"))
+    }
+}
+
+/// A logger which isn't part of an Azure invocation, e.g. for background work.
+///
+/// Its lines only go to `tracing`, until taken with [`take_logs`].
+pub fn detached_logger(invocation_id: &str) -> AzureFnLogger {
+    Arc::new(RwLock::new(AzureFnLoggerInner {
+        logs: vec![],
+        invocation_id: invocation_id.to_string(),
+    }))
+}
+
+/// Take the lines logged so far, prefixed with the invocation id.
+pub async fn take_logs(logger: &AzureFnLogger) -> Vec<String> {
+    std::mem::take(&mut logger.write().await.logs)
+}
+
+/// Where each invocation's log lines are also written, see `LOCAL_LOG`.
+///
+/// For local development, where there is no Azure host to show the `Logs` of the envelope.
+#[derive(Clone, Debug)]
+pub enum LocalLog {
+    Stdout,
+    File(PathBuf), // appended to
+}
+
+impl From<&str> for LocalLog {
+    /// `stdout`, or otherwise a file path.
+    fn from(destination: &str) -> Self {
+        match destination {
+            "stdout" => Self::Stdout,
+            path => Self::File(path.into()),
+        }
+    }
+}
+
+impl LocalLog {
+    /// Write an invocation's lines in one go, so that concurrent invocations don't interleave.
+    pub async fn write(&self, lines: &[String]) -> io::Result<()> {
+        let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        match self {
+            Self::Stdout => io::stdout().write_all(text.as_bytes()).await,
+            Self::File(path) => {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?
+                    .write_all(text.as_bytes())
+                    .await
+            }
+        }
+    }
+}
+
 /// Makes logging to the AzureFnLogger less code-verbose.
 #[tide::utils::async_trait]
 pub trait AzureFnLoggerExt {
@@ -26,8 +100,9 @@ pub trait AzureFnLoggerExt {
 
 #[tide::utils::async_trait]
 impl AzureFnLoggerExt for AzureFnLogger {
-    #[must_use = "requires await"]
     async fn log(&mut self, log_line: String) {
+        // Also an event within the current tracing span, if there is a subscriber.
+        tracing::info!("{}", log_line);
         let mut inner = self.write().await;
         let line = format!("{} {}", inner.invocation_id, log_line);
         inner.logs.push(line);
@@ -36,10 +111,107 @@ impl AzureFnLoggerExt for AzureFnLogger {
 
 #[tide::utils::async_trait]
 impl AzureFnLoggerExt for &'_ mut AzureFnLogger {
-    #[must_use = "requires await"]
     async fn log(&mut self, log_line: String) {
+        // Also an event within the current tracing span, if there is a subscriber.
+        tracing::info!("{}", log_line);
         let mut inner = self.write().await;
         let line = format!("{} {}", inner.invocation_id, log_line);
         inner.logs.push(line);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::{json, Value};
+    use tide::http::{Method, Url};
+    use tide::{Request, Response, StatusCode};
+
+    use super::*;
+
+    async fn logs_a_line(req: Request<()>) -> tide::Result<Response> {
+        let mut logger = req.logger();
+        info!(logger, "Handled");
+        Ok(StatusCode::Ok.into())
+    }
+
+    fn post(body: &str) -> tide::http::Request {
+        let mut req =
+            tide::http::Request::new(Method::Post, Url::parse("http://localhost/").expect("url"));
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn handlers_can_log_without_the_middleware() {
+        let mut server = tide::new();
+        server.with(AzureFnLogMiddleware::new());
+        server.at("/").post(logs_a_line);
+
+        let res: tide::http::Response = server.respond(post("")).await.expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn handlers_log_to_the_invocation_with_the_middleware() {
+        let mut server = tide::new();
+        server.with(AzureFnMiddleware::new());
+        server.at("/").post(logs_a_line);
+
+        let envelope = json!({"Data": {"req": {"Body": ""}}, "Metadata": {}});
+        let mut res: tide::http::Response = server
+            .respond(post(&envelope.to_string()))
+            .await
+            .expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert!(out["Logs"]
+            .as_array()
+            .expect("logs")
+            .iter()
+            .any(|line| line.as_str().is_some_and(|line| line.ends_with(" Handled"))));
+    }
+
+    #[async_std::test]
+    async fn detached_loggers_keep_their_lines_until_taken() {
+        let mut logger = detached_logger("background");
+        info!(logger, "First");
+        info!(logger, "Second");
+
+        assert_eq!(
+            take_logs(&logger).await,
+            ["background First", "background Second"]
+        );
+        assert!(take_logs(&logger).await.is_empty());
+    }
+
+    #[test]
+    fn local_log_destinations_are_parsed() {
+        assert!(matches!(LocalLog::from("stdout"), LocalLog::Stdout));
+        assert!(
+            matches!(LocalLog::from("logs/local.log"), LocalLog::File(path) if path == Path::new("logs/local.log"))
+        );
+    }
+
+    #[async_std::test]
+    async fn invocation_logs_are_appended_to_the_local_log() {
+        let path = std::env::temp_dir().join(format!("local-log-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = tide::new();
+        server.with(AzureFnMiddleware::new().local_log(LocalLog::File(path.clone())));
+        server.at("/").post(logs_a_line);
+
+        for invocation_id in ["invocation-1", "invocation-2"] {
+            let envelope = json!({"Data": {"req": {"Body": ""}}, "Metadata": {}});
+            let mut req = post(&envelope.to_string());
+            req.insert_header("X-Azure-Functions-InvocationId", invocation_id);
+            let _: tide::http::Response = server.respond(req).await.expect("response");
+        }
+
+        let logged = std::fs::read_to_string(&path).expect("local log");
+        std::fs::remove_file(&path).expect("removed");
+        assert_eq!(logged, "invocation-1 Handled\ninvocation-2 Handled\n");
+    }
+}
@@ -0,0 +1,108 @@
+use tide::{Middleware, Next, Request, Result, StatusCode};
+
+use super::{AzureFnLoggerExt, AzureFnRequestExt};
+
+/// The header with the scheme of the request to Azure's front end.
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+
+/// Rejects requests which reached Azure's front end over plain http, with a `400`.
+///
+/// Judged by `X-Forwarded-Proto`. Requests without it, such as timer invocations from the
+/// Functions host itself, are let through. Must be used with `AzureFnMiddleware`.
+#[derive(Debug, Default, Clone)]
+pub struct RequireHttpsMiddleware {
+    _priv: (),
+}
+
+impl RequireHttpsMiddleware {
+    /// Create a new instance of `RequireHttpsMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self { _priv: () }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequireHttpsMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
+        // Each proxy appends its own, so the first is the client's.
+        let proto = req.header(X_FORWARDED_PROTO).and_then(|values| {
+            values
+                .iter()
+                .flat_map(|value| value.as_str().split(','))
+                .next()
+                .map(|proto| proto.trim().to_lowercase())
+        });
+
+        match proto {
+            Some(proto) if proto != "https" => {
+                let mut logger = req.logger();
+                info!(
+                    logger,
+                    "REQUIRE_HTTPS: rejected a request forwarded over \"{}\": {} {}",
+                    proto,
+                    req.method(),
+                    req.url().path()
+                );
+                Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    "HTTPS is required",
+                ))
+            }
+            _ => Ok(next.run(req).await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::azure_function::AzureFnMiddleware;
+    use crate::test_support::invocation;
+
+    async fn forwarded(headers: Value) -> Value {
+        let mut server = tide::new();
+        server.with(AzureFnMiddleware::new());
+        server.with(RequireHttpsMiddleware::new());
+        server.at("/").post(|_| async { Ok("ok") });
+
+        let mut res: tide::http::Response = server
+            .respond(invocation("/", "POST", headers, ""))
+            .await
+            .expect("response");
+        res.body_json().await.expect("envelope")
+    }
+
+    #[async_std::test]
+    async fn requests_forwarded_over_https_are_let_through() {
+        for headers in [
+            json!({X_FORWARDED_PROTO: ["https"]}),
+            json!({X_FORWARDED_PROTO: ["HTTPS"]}),
+            json!({X_FORWARDED_PROTO: ["https, http"]}),
+            json!({}),
+        ] {
+            let out = forwarded(headers.clone()).await;
+
+            assert_eq!(out["Outputs"]["res"]["statusCode"], 200, "{}", headers);
+        }
+    }
+
+    #[async_std::test]
+    async fn requests_forwarded_over_http_are_rejected() {
+        for headers in [
+            json!({X_FORWARDED_PROTO: ["http"]}),
+            json!({X_FORWARDED_PROTO: ["http, https"]}),
+        ] {
+            let out = forwarded(headers.clone()).await;
+
+            assert_eq!(out["Outputs"]["res"]["statusCode"], 400, "{}", headers);
+            assert!(out["Logs"].as_array().expect("logs").iter().any(|line| line
+                .as_str()
+                .is_some_and(|line| line.contains(
+                    "REQUIRE_HTTPS: rejected a request forwarded over \"http\": POST /"
+                ))));
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use surf::Url;
+
+// The info! logging macro comes from crate::azure_function::logger
+use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt};
+use crate::mailchimp::{MailchimpMember, MembershipStatus};
+use crate::timing::TimedExt;
+use crate::AppState;
+
+/// The header carrying the hex HMAC-SHA256 signature of the callback body.
+pub const SIGNATURE_HEADER: &str = "X-Signature-SHA256";
+
+/// An external endpoint, e.g. a CRM, notified of every processed membership.
+pub struct SubscriptionCallback {
+    pub url: Url,
+    pub secret: String,
+}
+
+impl fmt::Debug for SubscriptionCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriptionCallback")
+            .field("url", &self.url)
+            .field("secret", &"(redacted)")
+            .finish()
+    }
+}
+
+/// The JSON body of a subscription callback.
+#[derive(Debug, Serialize)]
+pub struct SubscriptionEvent<'event> {
+    pub txn_id: &'event str,
+    pub email: &'event str,
+    pub first_name: &'event str,
+    pub last_name: &'event str,
+    pub status: MembershipStatus,
+    pub expires: &'event str,
+}
+
+/// Sign a callback body with the shared secret, as lowercase hex HMAC-SHA256.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Notify the configured subscription callback, if any, of a successful upsert.
+///
+/// Failures are logged and otherwise ignored, they must not fail the IPN.
+pub(crate) async fn notify_subscription(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+    txn_id: &str,
+    member: &MailchimpMember,
+) {
+    let Some(callback) = &state.subscription_callback else {
+        return;
+    };
+
+    let event = SubscriptionEvent {
+        txn_id,
+        email: &member.email_address,
+        first_name: &member.merge_fields.first_name,
+        last_name: &member.merge_fields.last_name,
+        status: member.status,
+        expires: &member.merge_fields.expires,
+    };
+
+    match send(logger, state, callback, &event).await {
+        Ok(()) => info!(logger, "Subscription callback: sent for: {}", event.email),
+        Err(error) => info!(
+            logger,
+            "Subscription callback: failed for: {} - error: {}", event.email, error
+        ),
+    }
+}
+
+async fn send(
+    logger: &AzureFnLogger,
+    state: &AppState,
+    callback: &SubscriptionCallback,
+    event: &SubscriptionEvent<'_>,
+) -> tide::Result<()> {
+    let body = serde_json::to_vec(event)?;
+    let signature = sign(&callback.secret, &body);
+
+    let mut res = state
+        .callback
+        .post(callback.url.as_str())
+        .header(SIGNATURE_HEADER, signature)
+        .content_type(tide::http::mime::JSON)
+        .body_bytes(body)
+        .timed(logger, state, "Subscription callback")
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(tide::Error::from_str(
+            res.status(),
+            format!(
+                "status: {} - body: \"{}\"",
+                res.status(),
+                state.log_body(&res.body_string().await?)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tide::StatusCode;
+
+    use super::*;
+    use crate::azure_function::{detached_logger, take_logs};
+    use crate::test_support::{test_state, text, MockEmail, MockHttp};
+
+    fn member() -> MailchimpMember {
+        serde_json::from_value(json!({
+            "email_address": "ada@example.com",
+            "status": "subscribed",
+            "merge_fields": {"FNAME": "Ada", "LNAME": "Lovelace", "EXPIRES": "2025-06-15"},
+        }))
+        .expect("member")
+    }
+
+    fn callback_state(http: &MockHttp) -> AppState {
+        let mut state = test_state(http, &MockEmail::default());
+        state.subscription_callback = Some(SubscriptionCallback {
+            url: Url::parse("https://callback.test/memberships").expect("url"),
+            secret: "callback-secret".to_string(),
+        });
+        state
+    }
+
+    #[test]
+    fn signatures_are_hex_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[async_std::test]
+    async fn callbacks_are_signed_json_of_the_member() {
+        let http = MockHttp::new(|_| text(StatusCode::Ok, ""));
+        let state = callback_state(&http);
+
+        notify_subscription(&mut detached_logger("test"), &state, "TXN1", &member()).await;
+
+        let sent = http.sent_to("callback.test");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].method, tide::http::Method::Post);
+        assert_eq!(sent[0].url.path(), "/memberships");
+        let body: Value = serde_json::from_str(&sent[0].body).expect("callback JSON");
+        assert_eq!(
+            body,
+            json!({
+                "txn_id": "TXN1",
+                "email": "ada@example.com",
+                "first_name": "Ada",
+                "last_name": "Lovelace",
+                "status": "subscribed",
+                "expires": "2025-06-15",
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn callbacks_carry_the_signature_of_their_body() {
+        let http = MockHttp::new(|_| text(StatusCode::Ok, ""));
+        let state = callback_state(&http);
+
+        notify_subscription(&mut detached_logger("test"), &state, "TXN1", &member()).await;
+
+        let sent = http.sent_to("callback.test");
+        let signature = sent[0].headers.get(SIGNATURE_HEADER).expect("signature");
+        assert_eq!(
+            signature.as_str(),
+            sign("callback-secret", sent[0].body.as_bytes())
+        );
+        assert_ne!(
+            signature.as_str(),
+            sign("another-secret", sent[0].body.as_bytes())
+        );
+    }
+
+    #[async_std::test]
+    async fn failed_callbacks_are_logged() {
+        let http = MockHttp::new(|_| text(StatusCode::BadGateway, "down"));
+        let state = callback_state(&http);
+        let logger = detached_logger("test");
+
+        notify_subscription(&mut logger.clone(), &state, "TXN1", &member()).await;
+
+        assert!(take_logs(&logger)
+            .await
+            .iter()
+            .any(|line| line.contains("Subscription callback: failed for: ada@example.com")));
+    }
+}
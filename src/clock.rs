@@ -0,0 +1,56 @@
+use std::fmt::Debug;
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// The current time, so that time-dependent logic such as membership expiry can be run against a fixed "now".
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Today's date, in UTC.
+    fn today(&self) -> NaiveDate {
+        self.now().date_naive()
+    }
+}
+
+/// The system clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock which is always at the same instant, for tests.
+#[derive(Debug)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn fixed_clocks_stay_put() {
+        let now = Utc
+            .with_ymd_and_hms(2024, 12, 31, 23, 59, 59)
+            .single()
+            .expect("valid date");
+        let clock = FixedClock(now);
+
+        assert_eq!(clock.now(), now);
+        assert_eq!(clock.now(), now);
+        assert_eq!(
+            clock.today(),
+            NaiveDate::from_ymd_opt(2024, 12, 31).expect("valid date")
+        );
+    }
+}
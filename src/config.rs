@@ -0,0 +1,560 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use surf::Url;
+use tide::StatusCode;
+
+/// Configuration which can be changed without a restart, via the admin reload endpoint.
+///
+/// Http clients, api keys and the like are set once at startup, and are not in here.
+#[derive(Clone, Debug)]
+pub struct ReloadableConfig {
+    pub audit_queue: bool, // queue an audit event for each membership change
+    pub dead_letter: DeadLetterSink, // where IPNs with an unhandled txn_type are recorded
+    pub expires_unknown_text: String, // membership check email text for a missing EXPIRES
+    pub ipn_enabled: bool,
+    pub ipn_json_field: Option<String>, // unwrap IPNs posted as JSON, from this string field
+    pub ipn_outcome_statuses: HashMap<IpnOutcome, StatusCode>, // rather than 200, for IPNs which are acknowledged & ignored
+    pub ipn_strict_content_type: bool, // reject IPNs which aren't form-encoded, rather than warn
+    pub max_expiry_years: u32,         // cap on how far out a payment sets EXPIRES
+    pub membership_check_enabled: bool,
+    pub membership_check_redirect: Url, // where browsers go once the email is sent
+    pub membership_grace_days: i64,     // days a membership stays active past EXPIRES
+    pub membership_types: HashMap<String, MembershipType>, // PayPal `item_number` -> membership type
+    pub min_amount: f64, // minimum membership payment, for currencies not in min_amounts
+    pub min_amounts: HashMap<String, f64>, // currency -> minimum membership payment
+    pub name_normalization: NameNormalization, // of PayPal names, before they are written to FNAME & LNAME
+    pub new_member_merge_fields: Map<String, Value>, // static merge fields for members we add to the list
+    pub paypal_success_statuses: Vec<String>, // IPN `payment_status` values which grant membership
+    pub paypal_verify_attempts: u32,          // includes the first attempt
+    pub preregister_pending: bool, // add new members on a `Pending` payment, without an EXPIRES
+    pub read_only: bool,           // defer MailChimp writes, reads still work
+    pub qa_redirect_email: Option<String>, // send all membership check emails here instead, for QA
+    pub reconciliation_lookback_days: i64,
+    pub renewal_window_days: i64, // a repeat payment within this doesn't extend EXPIRES
+    pub retry_budget_secs: u64,   // total time for retries in one invocation, 0 for no limit
+    pub subscr_payment_period_days: i64, // days a recurring payment is good for
+    pub subscr_payment_stacks: bool, // recurring payments extend from the existing EXPIRES
+    pub subscribe_policy: SubscribePolicy,
+    pub suppressed_emails: HashSet<String>, // lowercase, never sent membership check emails
+    pub template_locales: HashMap<String, LocaleTemplates>, // by lowercase language, e.g. "fr"
+    pub template_membership_active: Option<String>, // twilio email template id
+    pub template_membership_check: String,  // twilio email template id
+    pub template_membership_expired: Option<String>, // twilio email template id
+    pub template_membership_notfound: String, // twilio email template id
+    pub template_welcome: Option<String>,   // twilio email template id, for new members
+    pub web_accept_period_days: i64,        // days a one-off payment is good for
+}
+
+impl ReloadableConfig {
+    /// Read the configuration from the environment, describing the first problem found if it is invalid.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Read the configuration from variables by name, as [`ReloadableConfig::from_env`] does from the environment.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        // Endpoints can be switched off for maintenance, without removing them.
+        let ipn_enabled = parse_var(&var, "IPN_ENABLED", true, "true or false")?;
+        let membership_check_enabled =
+            parse_var(&var, "MEMBERSHIP_CHECK_ENABLED", true, "true or false")?;
+        // Statuses for IPNs which are ignored, which PayPal retries unless they are 2xx.
+        let ipn_outcome_statuses = var("IPN_OUTCOME_STATUSES").map_or(Ok(HashMap::new()), |v| {
+            serde_json::from_str::<HashMap<IpnOutcome, u16>>(&v)
+                .map_err(|error| error.to_string())
+                .and_then(|statuses| {
+                    statuses
+                        .into_iter()
+                        .map(|(outcome, status)| {
+                            StatusCode::try_from(status)
+                                .map(|status| (outcome, status))
+                                .map_err(|_| format!("{} is not an http status code", status))
+                        })
+                        .collect()
+                })
+                .map_err(|error| {
+                    format!(
+                        "IPN_OUTCOME_STATUSES must be a JSON object of outcome to status code: {}",
+                        error
+                    )
+                })
+        })?;
+
+        // IPNs which aren't form-encoded are logged, and optionally rejected.
+        let ipn_strict_content_type =
+            parse_var(&var, "IPN_STRICT_CONTENT_TYPE", false, "true or false")?;
+        // Writes only, e.g. during MailChimp audience changes.
+        let read_only = parse_var(&var, "READ_ONLY", false, "true or false")?;
+
+        // Twilio email templates
+        let template_membership_check = required_var(&var, "TEMPLATE_MEMBERSHIP_CHECK")?;
+        let template_membership_notfound = required_var(&var, "TEMPLATE_MEMBERSHIP_NOTFOUND")?;
+        let template_membership_active = var("TEMPLATE_MEMBERSHIP_ACTIVE");
+        let template_membership_expired = var("TEMPLATE_MEMBERSHIP_EXPIRED");
+        let template_welcome = var("TEMPLATE_WELCOME");
+
+        // Templates in other languages, for bilingual chapters.
+        let template_locales = var("TEMPLATE_LOCALES")
+            .map_or(Ok(HashMap::new()), |v| {
+                serde_json::from_str::<HashMap<String, LocaleTemplates>>(&v).map_err(|error| {
+                    format!(
+                        "TEMPLATE_LOCALES must be a JSON object of language to templates: {}",
+                        error
+                    )
+                })
+            })?
+            .into_iter()
+            .map(|(language, templates)| (language.to_lowercase(), templates))
+            .collect();
+
+        // Years from today beyond which a payment won't set EXPIRES.
+        let max_expiry_years = parse_var(&var, "MAX_EXPIRY_YEARS", 2, "a number")?;
+
+        // Membership products, e.g. individual, family & student, by PayPal button.
+        let membership_types = var("MEMBERSHIP_TYPES").map_or(Ok(HashMap::new()), |v| {
+            serde_json::from_str(&v).map_err(|error| {
+                format!(
+                    "MEMBERSHIP_TYPES must be a JSON object of item number to membership type: {}",
+                    error
+                )
+            })
+        })?;
+
+        // IPNs we don't handle yet, to find out which PayPal events we're missing.
+        let dead_letter = parse_var(
+            &var,
+            "DEAD_LETTER",
+            DeadLetterSink::Off,
+            "off, log or queue",
+        )?;
+
+        // Requires the `audit` output binding, see `Paypal-IPN/function.json`.
+        let audit_queue = parse_var(&var, "AUDIT_QUEUE", false, "true or false")?;
+
+        // PayPal names are as the payer typed them.
+        let name_normalization = parse_var(
+            &var,
+            "NAME_NORMALIZATION",
+            NameNormalization::Off,
+            "off, whitespace or title_case",
+        )?;
+
+        // Merge fields for new members only, e.g. {"SOURCE": "paypal"}.
+        let new_member_merge_fields = var("NEW_MEMBER_MERGE_FIELDS")
+            .map_or(Ok(Map::new()), |v| serde_json::from_str(&v))
+            .map_err(|error| {
+                format!(
+                    "NEW_MEMBER_MERGE_FIELDS must be a JSON object of merge field to value: {}",
+                    error
+                )
+            })?;
+
+        // Days a membership stays active after it expires.
+        let membership_grace_days = parse_var(&var, "MEMBERSHIP_GRACE_DAYS", 0, "a number")?;
+
+        // Shown in membership check emails for members without an EXPIRES on record.
+        let expires_unknown_text =
+            var("EXPIRES_UNKNOWN_TEXT").unwrap_or_else(|| "unknown".to_string());
+
+        let reconciliation_lookback_days =
+            parse_var(&var, "RECONCILIATION_LOOKBACK_DAYS", 7, "a number")?;
+
+        // Days after a payment in which another payment is treated as an accidental double payment.
+        let renewal_window_days = parse_var(&var, "RENEWAL_WINDOW_DAYS", 0, "a number")?;
+
+        // Membership periods, by PayPal `txn_type`.
+        let web_accept_period_days = parse_var(&var, "WEB_ACCEPT_PERIOD_DAYS", 365, "a number")?;
+        let subscr_payment_period_days =
+            parse_var(&var, "SUBSCR_PAYMENT_PERIOD_DAYS", 365, "a number")?;
+        let subscr_payment_stacks =
+            parse_var(&var, "SUBSCR_PAYMENT_STACKS", false, "true or false")?;
+
+        // Whether a renewal by a pending member confirms their email subscription.
+        let subscribe_policy = parse_var(
+            &var,
+            "SUBSCRIBE_POLICY",
+            SubscribePolicy::Pending,
+            "pending or direct_subscribe",
+        )?;
+
+        // Retries within one invocation stop once this many seconds have passed, to stay within Azure's timeout.
+        let retry_budget_secs = parse_var(&var, "RETRY_BUDGET_SECS", 0, "a number")?;
+
+        // Whether `Pending` payments, e.g. eChecks, put new members on the list before the payment completes.
+        let preregister_pending = parse_var(&var, "PREREGISTER_PENDING", false, "true or false")?;
+
+        // Attempts at verifying an IPN, in case PayPal returns INVALID transiently.
+        let paypal_verify_attempts = parse_var(&var, "PAYPAL_VERIFY_ATTEMPTS", 3, "a number")?;
+
+        // Some gateways wrap the form body in a JSON object, e.g. `{"body": "txn_type=..."}`.
+        let ipn_json_field = var("IPN_JSON_FIELD").filter(|field| !field.is_empty());
+
+        // QA: membership check emails go to this inbox rather than to the member.
+        let qa_redirect_email = var("QA_REDIRECT_EMAIL").filter(|email| !email.is_empty());
+
+        // Only redirect to our own site, so that a misconfiguration can't make an open redirect.
+        let redirect_allowed_hosts: Vec<String> = var("REDIRECT_ALLOWED_HOSTS")
+            .unwrap_or_else(|| "squamishaccess.ca".to_string())
+            .split(',')
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+        let membership_check_redirect =
+            Url::parse(&var("MEMBERSHIP_CHECK_REDIRECT_URL").unwrap_or_else(|| {
+                "https://squamishaccess.ca/membership-check-response".to_string()
+            }))
+            .map_err(|_| "MEMBERSHIP_CHECK_REDIRECT_URL must be a valid url.".to_string())?;
+        if !(membership_check_redirect.scheme() == "https"
+            && membership_check_redirect
+                .host_str()
+                .is_some_and(|host| redirect_allowed_hosts.iter().any(|allowed| allowed == host)))
+        {
+            return Err(format!(
+                "MEMBERSHIP_CHECK_REDIRECT_URL must be https, to one of REDIRECT_ALLOWED_HOSTS ({}): {}",
+                redirect_allowed_hosts.join(", "),
+                membership_check_redirect
+            ));
+        }
+
+        // Minimum membership payments, which differ by currency.
+        let min_amount = parse_var(&var, "MIN_AMOUNT", 10.0, "a number")?;
+        let min_amounts = var("MIN_AMOUNTS")
+            .map_or(Ok(HashMap::new()), |v| {
+                serde_json::from_str::<HashMap<String, f64>>(&v).map_err(|error| {
+                    format!(
+                        "MIN_AMOUNTS must be a JSON object of currency to minimum amount: {}",
+                        error
+                    )
+                })
+            })?
+            .into_iter()
+            .map(|(currency, amount)| (currency.to_uppercase(), amount))
+            .collect();
+
+        // IPN payment statuses which grant membership.
+        let paypal_success_statuses = var("PAYPAL_SUCCESS_STATUSES")
+            .unwrap_or_else(|| "Completed".to_string())
+            .split(',')
+            .map(|status| status.trim().to_string())
+            .filter(|status| !status.is_empty())
+            .collect();
+
+        // Addresses never to send membership check emails to, e.g. an internal do-not-contact list.
+        let mut suppressed_emails = var("SUPPRESSED_EMAILS").unwrap_or_default();
+        if let Some(path) = var("SUPPRESSED_EMAILS_FILE") {
+            let file = fs::read_to_string(&path)
+                .map_err(|error| format!("SUPPRESSED_EMAILS_FILE must be readable: {}", error))?;
+            suppressed_emails.push('\n');
+            suppressed_emails.push_str(&file);
+        }
+        let suppressed_emails = suppressed_emails
+            .split([',', '\n'])
+            .map(|email| email.trim().to_lowercase())
+            .filter(|email| !email.is_empty())
+            .collect();
+
+        Ok(Self {
+            audit_queue,
+            dead_letter,
+            expires_unknown_text,
+            ipn_enabled,
+            ipn_json_field,
+            ipn_outcome_statuses,
+            ipn_strict_content_type,
+            max_expiry_years,
+            membership_check_enabled,
+            membership_check_redirect,
+            membership_grace_days,
+            membership_types,
+            min_amount,
+            min_amounts,
+            name_normalization,
+            new_member_merge_fields,
+            paypal_success_statuses,
+            paypal_verify_attempts,
+            preregister_pending,
+            read_only,
+            qa_redirect_email,
+            reconciliation_lookback_days,
+            renewal_window_days,
+            retry_budget_secs,
+            subscr_payment_period_days,
+            subscr_payment_stacks,
+            subscribe_policy,
+            suppressed_emails,
+            template_locales,
+            template_membership_active,
+            template_membership_check,
+            template_membership_expired,
+            template_membership_notfound,
+            template_welcome,
+            web_accept_period_days,
+        })
+    }
+
+    /// The minimum membership payment for a payment currency, falling back to the default minimum.
+    pub fn min_amount_for(&self, currency: Option<&str>) -> f64 {
+        currency
+            .and_then(|currency| self.min_amounts.get(&currency.to_uppercase()))
+            .copied()
+            .unwrap_or(self.min_amount)
+    }
+
+    /// The status for an IPN which is acknowledged & ignored, `200` unless set in `IPN_OUTCOME_STATUSES`.
+    pub fn ipn_outcome_status(&self, outcome: IpnOutcome) -> StatusCode {
+        self.ipn_outcome_statuses
+            .get(&outcome)
+            .copied()
+            .unwrap_or(StatusCode::Ok)
+    }
+
+    /// The templates for the most preferred language in an `Accept-Language` header which has any, see `TEMPLATE_LOCALES`.
+    ///
+    /// Only the primary language is compared, so `fr-CA` picks `fr`. `None` is the default templates.
+    pub fn locale_for_accept_language(&self, accept_language: &str) -> Option<&LocaleTemplates> {
+        let mut languages: Vec<(&str, f32)> = accept_language
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let language = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(1.0, |q| q.parse().unwrap_or(0.0));
+                Some((language, quality))
+            })
+            .filter(|(language, quality)| *quality > 0.0 && !language.is_empty())
+            .collect();
+        // Stable, so equally preferred languages keep the header's order.
+        languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        languages.into_iter().find_map(|(language, _)| {
+            let primary = language.split('-').next().unwrap_or(language);
+            self.template_locales.get(&primary.to_lowercase())
+        })
+    }
+
+    /// The templates for a PayPal `residence_country`, see `TEMPLATE_LOCALES`. `None` is the default templates.
+    pub fn locale_for_country(&self, country: &str) -> Option<&LocaleTemplates> {
+        self.template_locales.values().find(|templates| {
+            templates
+                .countries
+                .iter()
+                .any(|locale_country| locale_country.eq_ignore_ascii_case(country))
+        })
+    }
+}
+
+/// Email templates in one language, see `TEMPLATE_LOCALES`.
+///
+/// Each falls back to the default template, so a language may leave some out, or all of them for the default templates' own language.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LocaleTemplates {
+    pub membership_check: Option<String>,
+    pub membership_active: Option<String>,
+    pub membership_expired: Option<String>,
+    pub membership_notfound: Option<String>,
+    pub welcome: Option<String>,
+    pub countries: Vec<String>, // PayPal `residence_country` codes for welcome emails in this language
+}
+
+/// IPNs which are valid, but which don't grant a membership.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpnOutcome {
+    /// A refund or reversal of an earlier payment.
+    Refund,
+    /// No `txn_type`, and not a refund.
+    NoTxnType,
+    /// A `txn_type` we don't handle, see `DEAD_LETTER`.
+    UnhandledTxnType,
+    /// A `payment_status` which isn't in `PAYPAL_SUCCESS_STATUSES`.
+    NotSuccessStatus,
+    /// No `mc_gross`.
+    NoAmount,
+    /// An `mc_gross` which isn't a number.
+    UnparseableAmount,
+    /// Less than the minimum payment.
+    AmountTooLow,
+}
+
+/// Where IPNs with a `txn_type` we don't handle are recorded, with their full body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeadLetterSink {
+    /// Not recorded, only the `txn_type` is logged.
+    Off,
+    /// A `DEAD LETTER` line in the function logs.
+    Log,
+    /// The `paypal-ipn-deadletter` Azure storage queue, via the `deadletter` output binding.
+    Queue,
+}
+
+impl FromStr for DeadLetterSink {
+    type Err = ();
+
+    fn from_str(sink: &str) -> Result<Self, Self::Err> {
+        match sink {
+            "off" => Ok(Self::Off),
+            "log" => Ok(Self::Log),
+            "queue" => Ok(Self::Queue),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How PayPal's `first_name` & `last_name` are tidied up for MailChimp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameNormalization {
+    /// As PayPal sends them.
+    Off,
+    /// Trimmed, with runs of whitespace collapsed to a single space.
+    Whitespace,
+    /// As `Whitespace`, and title cased, e.g. `  joHN   dOE ` to `John Doe`.
+    TitleCase,
+}
+
+impl FromStr for NameNormalization {
+    type Err = ();
+
+    fn from_str(normalization: &str) -> Result<Self, Self::Err> {
+        match normalization {
+            "off" => Ok(Self::Off),
+            "whitespace" => Ok(Self::Whitespace),
+            "title_case" => Ok(Self::TitleCase),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A membership product, identified by the PayPal button's `item_number`, see `MEMBERSHIP_TYPES`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MembershipType {
+    pub name: String,             // the MEMBERTYPE merge field, e.g. "family"
+    pub period_days: Option<i64>, // rather than the period for the `txn_type`
+}
+
+/// The MailChimp status a paying member who is `pending` (awaiting opt-in) is given.
+///
+/// Unsubscribed members are never re-subscribed, whatever the policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscribePolicy {
+    /// Stay pending, so that MailChimp keeps asking them to opt in.
+    Pending,
+    /// A renewal is taken as continued consent, so a pending member who renews is subscribed.
+    DirectSubscribe,
+}
+
+impl FromStr for SubscribePolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "pending" => Ok(Self::Pending),
+            "direct_subscribe" => Ok(Self::DirectSubscribe),
+            _ => Err(()),
+        }
+    }
+}
+
+fn required_var(var: impl Fn(&str) -> Option<String>, name: &str) -> Result<String, String> {
+    var(name).ok_or_else(|| format!("{} is required.", name))
+}
+
+fn parse_var<T: FromStr>(
+    var: impl Fn(&str) -> Option<String>,
+    name: &str,
+    default: T,
+    expected: &str,
+) -> Result<T, String> {
+    var(name).map_or(Ok(default), |v| {
+        v.parse()
+            .map_err(|_| format!("{} must be {}.", name, expected))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{test_config, test_vars};
+
+    fn bilingual_config() -> ReloadableConfig {
+        let mut config = test_config();
+        config.template_locales = serde_json::from_value(json!({
+            "fr": {"membership_check": "d-fr", "countries": ["FR", "be"]},
+            "en": {},
+        }))
+        .expect("TEMPLATE_LOCALES");
+        config
+    }
+
+    /// The `membership_check` template of the language picked, or `None` for the defaults.
+    fn picked(config: &ReloadableConfig, accept_language: &str) -> Option<Option<String>> {
+        config
+            .locale_for_accept_language(accept_language)
+            .map(|locale| locale.membership_check.clone())
+    }
+
+    #[test]
+    fn the_most_preferred_configured_language_is_picked() {
+        let config = bilingual_config();
+        let french = Some(Some("d-fr".to_string()));
+        let english = Some(None);
+
+        assert_eq!(picked(&config, "fr"), french);
+        assert_eq!(picked(&config, "fr-CA,en;q=0.8"), french);
+        assert_eq!(picked(&config, "FR-ca"), french);
+        assert_eq!(picked(&config, "en-US,fr;q=0.9"), english);
+        assert_eq!(picked(&config, "fr;q=0.5, en;q=0.7"), english);
+        assert_eq!(picked(&config, "de, fr;q=0.1"), french);
+        assert_eq!(picked(&config, "de, fr;q=0"), None);
+        assert_eq!(picked(&config, "de-DE"), None);
+        assert_eq!(picked(&config, ""), None);
+        assert_eq!(picked(&config, "*"), None);
+    }
+
+    #[test]
+    fn countries_pick_their_language() {
+        let config = bilingual_config();
+        let check_template = |country| {
+            config
+                .locale_for_country(country)
+                .and_then(|locale| locale.membership_check.as_deref())
+        };
+
+        assert_eq!(check_template("FR"), Some("d-fr"));
+        assert_eq!(check_template("BE"), Some("d-fr"));
+        assert_eq!(check_template("CA"), None);
+        assert!(config.locale_for_country("CA").is_none());
+    }
+
+    #[test]
+    fn currencies_without_a_minimum_use_the_default() {
+        let mut config = test_config();
+        config.min_amounts = HashMap::from([("JPY".to_string(), 1500.0)]);
+        assert_eq!(config.min_amount_for(Some("jpy")), 1500.0);
+        assert_eq!(config.min_amount_for(Some("USD")), config.min_amount);
+        assert_eq!(config.min_amount_for(None), config.min_amount);
+    }
+
+    #[test]
+    fn redirects_off_site_are_refused() {
+        for (redirect, allowed) in [
+            ("https://squamishaccess.ca/checked", true),
+            ("https://evil.example.com/", false),
+            ("http://squamishaccess.ca/checked", false),
+        ] {
+            let config = ReloadableConfig::from_vars(test_vars(&[(
+                "MEMBERSHIP_CHECK_REDIRECT_URL",
+                redirect,
+            )]));
+
+            assert_eq!(config.is_ok(), allowed, "{}", redirect);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt::Debug;
+
+use surf::Client;
+use tide::{Body, StatusCode};
+
+// The info! logging macro comes from crate::azure_function::logger
+use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt};
+use crate::sendgrid::SendGridMail;
+use crate::timing::TimedExt;
+use crate::AppState;
+
+/// SendGrid's id for an accepted mail.
+const MESSAGE_ID_HEADER: &str = "X-Message-Id";
+
+/// A transactional email provider, selected by `EMAIL_PROVIDER`.
+///
+/// Mails are built as a [`SendGridMail`], which other providers translate into their own format.
+#[surf::utils::async_trait]
+pub trait EmailSender: Debug + Send + Sync {
+    /// Send a templated mail, erroring with the provider's response if it was not accepted.
+    async fn send(
+        &self,
+        logger: &AzureFnLogger,
+        state: &AppState,
+        mail: &SendGridMail,
+    ) -> tide::Result<()>;
+
+    /// Check that the provider is reachable & accepts our credentials.
+    async fn ping(&self) -> tide::Result<()>;
+}
+
+/// Sends via the SendGrid (Twilio) v3 api. The default.
+#[derive(Debug)]
+pub struct SendGridSender {
+    pub client: Client, // with the base url & auth set
+}
+
+#[surf::utils::async_trait]
+impl EmailSender for SendGridSender {
+    async fn send(
+        &self,
+        logger: &AzureFnLogger,
+        state: &AppState,
+        mail: &SendGridMail,
+    ) -> tide::Result<()> {
+        let mut res = self
+            .client
+            .post("v3/mail/send")
+            .body(Body::from_json(mail)?)
+            .timed(logger, state, "SendGrid")
+            .await?;
+
+        if res.status() == StatusCode::Accepted {
+            // For finding the email in SendGrid's activity feed.
+            if let Some(message_id) = res.header(MESSAGE_ID_HEADER) {
+                let mut logger = logger.clone();
+                info!(logger, "SendGrid message id: {}", message_id.last());
+            }
+            Ok(())
+        } else {
+            Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "Twilio error: {}",
+                    state.log_body(&res.body_string().await?)
+                ),
+            ))
+        }
+    }
+
+    async fn ping(&self) -> tide::Result<()> {
+        let res = self.client.get("v3/scopes").await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("SendGrid ping - status: {}", res.status()),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_types::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::azure_function::{detached_logger, take_logs};
+    use crate::sendgrid::MembershipTemplateData;
+    use crate::test_support::{json, test_state, text, MockEmail, MockHttp};
+
+    fn mail() -> SendGridMail {
+        SendGridMail::template(
+            "ada@example.com",
+            "info@squamishaccess.ca",
+            "d-check",
+            Some(MembershipTemplateData {
+                member_name: "Ada".to_string(),
+                expires: "2025-06-15".to_string(),
+                status: "active".to_string(),
+                membership_type: String::new(),
+            }),
+        )
+    }
+
+    #[async_std::test]
+    async fn senders_are_used_as_trait_objects() {
+        let email = MockEmail::default();
+        let state = test_state(&MockHttp::new(|_| text(StatusCode::Ok, "")), &email);
+        let sender: &dyn EmailSender = state.email.as_ref();
+
+        sender
+            .send(&detached_logger("test"), &state, &mail())
+            .await
+            .expect("sent");
+
+        assert_eq!(email.sent().len(), 1);
+        assert_eq!(email.sent()[0]["template_id"], "d-check");
+    }
+
+    #[async_std::test]
+    async fn sendgrid_is_sent_the_mail_as_is() {
+        let http = MockHttp::new(|_| {
+            let mut res = text(StatusCode::Accepted, "");
+            res.insert_header(MESSAGE_ID_HEADER, "message-1");
+            res
+        });
+        let state = test_state(&http, &MockEmail::default());
+        let sender = SendGridSender {
+            client: http.client("https://sendgrid.test/"),
+        };
+
+        sender
+            .send(&detached_logger("test"), &state, &mail())
+            .await
+            .expect("sent");
+
+        let sent = http.sent_to("sendgrid.test");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].method, Method::Post);
+        assert_eq!(sent[0].url.path(), "/v3/mail/send");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&sent[0].body).expect("JSON"),
+            serde_json::to_value(mail()).expect("JSON")
+        );
+    }
+
+    #[async_std::test]
+    async fn sendgrid_errors_unless_accepted() {
+        let http = MockHttp::new(|_| {
+            json(
+                StatusCode::BadRequest,
+                &json!({"errors": [{"message": "bad template"}]}),
+            )
+        });
+        let state = test_state(&http, &MockEmail::default());
+        let sender = SendGridSender {
+            client: http.client("https://sendgrid.test/"),
+        };
+
+        let error = sender
+            .send(&detached_logger("test"), &state, &mail())
+            .await
+            .expect_err("not accepted");
+
+        assert!(error.to_string().contains("bad template"));
+    }
+
+    #[async_std::test]
+    async fn error_bodies_are_truncated_to_the_log_cap() {
+        let http = MockHttp::new(|_| text(StatusCode::BadRequest, &"x".repeat(100)));
+        let mut state = test_state(&http, &MockEmail::default());
+        state.log_body_max_len = 10;
+        let sender = SendGridSender {
+            client: http.client("https://sendgrid.test/"),
+        };
+
+        let error = sender
+            .send(&detached_logger("test"), &state, &mail())
+            .await
+            .expect_err("not accepted");
+
+        assert_eq!(
+            error.to_string(),
+            "Twilio error: xxxxxxxxxx… (100 bytes in total)"
+        );
+    }
+
+    #[async_std::test]
+    async fn sendgrid_message_ids_are_logged_when_present() {
+        for message_id in [Some("message-1"), None] {
+            let http = MockHttp::new(move |_| {
+                let mut res = text(StatusCode::Accepted, "");
+                if let Some(message_id) = message_id {
+                    res.insert_header(MESSAGE_ID_HEADER, message_id);
+                }
+                res
+            });
+            let state = test_state(&http, &MockEmail::default());
+            let sender = SendGridSender {
+                client: http.client("https://sendgrid.test/"),
+            };
+            let logger = detached_logger("test");
+
+            sender.send(&logger, &state, &mail()).await.expect("sent");
+
+            let logs = take_logs(&logger).await;
+            let logged = logs
+                .iter()
+                .any(|line| line.ends_with("SendGrid message id: message-1"));
+            assert_eq!(logged, message_id.is_some(), "{:?}", logs);
+        }
+    }
+}
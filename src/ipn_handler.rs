@@ -1,14 +1,55 @@
-use chrono::prelude::*;
-use chrono::Duration;
-use chrono::SecondsFormat::Secs;
-use serde::Deserialize;
-use serde_json::json;
-use tide::http::Method;
+use std::time;
+
+use async_std::{future, task};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tide::http::headers::RETRY_AFTER;
+use tide::http::{mime, Method};
 use tide::{Body, Response, StatusCode};
+use tracing::Span;
 
 // The info! logging macro comes from crate::azure_function::logger
-use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt};
-use crate::{AppRequest, MailchimpQuery, MailchimpResponse};
+use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt, AzureFnOutputs, AzureFnRequestExt};
+use crate::callback::notify_subscription;
+use crate::config::{
+    DeadLetterSink, IpnOutcome, MembershipType, NameNormalization, ReloadableConfig,
+    SubscribePolicy,
+};
+use crate::mailchimp::{
+    get_member, member_path, parse_expires, to_mailchimp_format, MailchimpError, MailchimpMember,
+    MailchimpQuery, MembershipStatus, MERGE_FIELD_MAX_LEN,
+};
+use crate::membership_check::FROM_EMAIL;
+use crate::paypal::{self, IpnVerification, IpnVerifyMethod};
+use crate::retry::RetryBudget;
+use crate::sendgrid::{MembershipTemplateData, SendGridMail};
+use crate::timing::TimedExt;
+use crate::{compute_expiry, AppRequest, AppState, ExpiryGrant, ExpiryPolicy};
+
+/// Seconds PayPal is asked to wait before retrying when the IPN handler is disabled.
+const DISABLED_RETRY_AFTER: &str = "3600";
+
+/// The merge field for the most recent payment date, which lists may not have.
+const LASTPAY_MERGE_FIELD: &str = "LASTPAY";
+
+/// How far back REST verification searches for an IPN's transaction, the most PayPal allows per search.
+const REST_VERIFY_LOOKBACK_DAYS: i64 = 31;
+
+/// The queue output binding for `DEAD_LETTER=queue`, in `Paypal-IPN/function.json`.
+const DEAD_LETTER_BINDING: &str = "deadletter";
+
+/// The queue output binding for `AUDIT_QUEUE`, in `Paypal-IPN/function.json` & `Admin-Resync/function.json`.
+pub(crate) const AUDIT_BINDING: &str = "audit";
+
+/// The merge field for the membership type, see `MEMBERSHIP_TYPES`.
+const MEMBERTYPE_MERGE_FIELD: &str = "MEMBERTYPE";
+
+/// The delay before the first retry of an `INVALID` IPN verification, doubled after each attempt.
+const VERIFY_RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+
+/// The merge field for the membership time each payment granted, see [`ExpiryGrant`].
+const GRANTS_MERGE_FIELD: &str = "GRANTS";
 
 #[allow(
     clippy::upper_case_acronyms,
@@ -16,18 +57,69 @@ use crate::{AppRequest, MailchimpQuery, MailchimpResponse};
 )]
 #[derive(Debug, Deserialize)]
 struct IPNTransationMessage {
+    // Required, we can't do anything meaningful without these.
     txn_id: String,
-    txn_type: String,
     payment_status: String,
     payer_email: String,
-    first_name: String,
-    last_name: String,
-    mc_currency: String,
-    mc_gross: String,
+    // PayPal omits some of these for some transaction types.
+    txn_type: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    mc_currency: Option<String>,
+    mc_gross: Option<String>,
     exchange_rate: Option<String>,
     payment_date: Option<String>,
+    // The original payment, on refunds & reversals.
+    parent_txn_id: Option<String>,
+    // Set on the PayPal button, e.g. a campaign id.
+    custom: Option<String>,
+    // Identify the PayPal button, see `MEMBERSHIP_TYPES`.
+    item_name: Option<String>,
+    item_number: Option<String>,
     // for debug purposes
     transaction_subject: Option<String>,
+    // `1` from the PayPal IPN simulator & sandbox.
+    test_ipn: Option<String>,
+    // For the welcome email's language, see `TEMPLATE_LOCALES`.
+    residence_country: Option<String>,
+}
+
+#[allow(
+    clippy::upper_case_acronyms,
+    reason = "The struct name uses a naming convention aligned with external API standards"
+)]
+#[derive(Debug, Deserialize)]
+struct IPNRestVerifyMessage {
+    // Subscription signups have no transaction.
+    txn_id: Option<String>,
+    payer_email: Option<String>,
+    mc_gross: Option<String>,
+    mc_currency: Option<String>,
+}
+
+#[allow(
+    clippy::upper_case_acronyms,
+    reason = "The struct name uses a naming convention aligned with external API standards"
+)]
+#[derive(Debug, Deserialize)]
+struct IPNRefundMessage {
+    payer_email: String,
+    mc_currency: Option<String>,
+    mc_gross: Option<String>, // negative, for a refund
+}
+
+#[allow(
+    clippy::upper_case_acronyms,
+    reason = "The struct name uses a naming convention aligned with external API standards"
+)]
+#[derive(Debug, Deserialize)]
+struct IPNSubscrSignupMessage {
+    subscr_id: Option<String>,
+    payer_email: String,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    mc_currency: Option<String>,
+    test_ipn: Option<String>,
 }
 
 #[allow(
@@ -37,14 +129,30 @@ struct IPNTransationMessage {
 #[derive(Debug, Deserialize)]
 struct IPNMessageTypeOnly {
     txn_type: Option<String>,
+    // Refunds & reversals may come without a `txn_type`.
+    payment_status: Option<String>,
+    parent_txn_id: Option<String>,
 }
 
 /// Handle a PayPal Instant Payment Notification (IPN) and attempt to subscribe to MailChimp.
 pub async fn ipn_handler(mut req: AppRequest) -> tide::Result<Response> {
-    let mut logger = req
-        .ext_mut::<AzureFnLogger>()
-        .expect("Must install AzureFnMiddleware")
-        .clone();
+    let mut logger = req.logger();
+
+    let config = req.state().config();
+    if !config.ipn_enabled || config.read_only {
+        info!(
+            logger,
+            "IPN handler is {}, asking PayPal to retry later",
+            if config.read_only {
+                "read-only"
+            } else {
+                "disabled"
+            }
+        );
+        let mut res: Response = StatusCode::ServiceUnavailable.into();
+        res.insert_header(RETRY_AFTER, DISABLED_RETRY_AFTER);
+        return Ok(res);
+    }
 
     if req.method() != Method::Post {
         info!(
@@ -62,67 +170,191 @@ pub async fn ipn_handler(mut req: AppRequest) -> tide::Result<Response> {
         "PayPal IPN Notification Event received successfully."
     );
 
-    let ipn_transaction_message_raw = req.body_string().await?;
-    let verification_body = ["cmd=_notify-validate&", &ipn_transaction_message_raw].concat();
+    // PayPal sends form-encoded IPNs, anything else is most likely a misrouted request.
+    // JSON is expected too if a gateway wraps them, see `IPN_JSON_FIELD`.
+    let content_type = req.content_type();
+    if content_type.as_ref().is_none_or(|mime| {
+        mime.essence() != mime::FORM.essence()
+            && (config.ipn_json_field.is_none() || mime.essence() != mime::JSON.essence())
+    }) {
+        let content_type =
+            content_type.map_or_else(|| "(none)".to_string(), |mime| mime.to_string());
+        if config.ipn_strict_content_type {
+            return Err(tide::Error::from_str(
+                StatusCode::UnsupportedMediaType,
+                format!("IPN: unexpected content type: {}", content_type),
+            ));
+        }
+        info!(
+            logger,
+            "Warning: IPN has an unexpected content type, processing it anyway: {}", content_type
+        );
+    }
+
+    let mut ipn_transaction_message_raw = req.body_string().await?;
+
+    if let Some(field) = &config.ipn_json_field {
+        ipn_transaction_message_raw =
+            unwrap_json_ipn(&mut logger, ipn_transaction_message_raw, field).await;
+    }
 
     // Must be done after we take the main request body.
     //
     // An atomic reference-counted pointer to our application state, with shared http clients.
     let state = req.state();
 
+    // Acknowledge PayPal straight away if IPNs are queued, unless the queue is full.
+    //
+    // Queued IPNs are verified first, so that PayPal still retries those which fail verification,
+    // and only the MailChimp update is left for the queue.
+    let verified = if let Some(queue) = &state.ipn_queue {
+        let retry_budget = RetryBudget::start(config.retry_budget_secs);
+        let verification = verify_ipn(
+            &mut logger,
+            state,
+            &ipn_transaction_message_raw,
+            &retry_budget,
+        )
+        .await?;
+        if verification != IpnVerification::Verified {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "Invalid IPN: not verified, not queued: \"{}\" - IPN: {}",
+                    state.log_body(&verification.to_string()),
+                    state.log_body(&ipn_transaction_message_raw)
+                ),
+            ));
+        }
+        match queue.try_send(ipn_transaction_message_raw) {
+            Ok(()) => {
+                info!(logger, "Verified IPN queued, {} in the queue", queue.len());
+                return Ok(StatusCode::Ok.into());
+            }
+            Err(error) => {
+                info!(logger, "IPN not queued ({}), processing it now", error);
+                ipn_transaction_message_raw = error.into_inner();
+            }
+        }
+        true
+    } else {
+        false
+    };
+
+    process_ipn(logger, state, ipn_transaction_message_raw, verified).await
+}
+
+/// The form body from an IPN wrapped in a JSON object by a gateway, or the body as-is if it isn't wrapped.
+///
+/// Only a JSON object with `field` as a string counts as wrapped, so plain form bodies pass through unchanged.
+async fn unwrap_json_ipn(logger: &mut AzureFnLogger, body: String, field: &str) -> String {
+    match serde_json::from_str::<Value>(&body)
+        .ok()
+        .as_ref()
+        .and_then(|json| json.get(field))
+        .and_then(Value::as_str)
+    {
+        Some(form) => {
+            info!(logger, "IPN: unwrapped from the JSON \"{}\" field", field);
+            form.to_string()
+        }
+        None => body,
+    }
+}
+
+/// Verify & process a raw IPN message, independently of how it was received.
+///
+/// Used by both the PayPal IPN handler and the admin resync endpoint.
+///
+/// Cheap local checks (`txn_type`, parsing, `payment_status`, amount & currency) come first, and reject
+/// without contacting PayPal. Only then is the IPN verified, and nothing is written to MailChimp unless it is `VERIFIED`.
+///
+/// `verified` skips verification, for IPNs which were verified before being queued.
+#[tracing::instrument(name = "process_ipn", skip_all, fields(txn_id, email))]
+pub(crate) async fn process_ipn(
+    mut logger: AzureFnLogger,
+    state: &AppState,
+    ipn_transaction_message_raw: String,
+    verified: bool,
+) -> tide::Result<Response> {
+    let retry_budget = RetryBudget::start(state.config().retry_budget_secs);
+
     if state.paypal_sandbox {
         info!(logger, "SANDBOX: Using PayPal sandbox environment");
     }
 
-    // Verify the IPN with PayPal. PayPal requires this.
-    let mut verify_response = state
-        .paypal
-        .post("/cgi-bin/webscr")
-        .body(verification_body)
-        .await?;
-
-    if !verify_response.status().is_success() {
-        return Err(tide::Error::from_str(
-            StatusCode::InternalServerError,
-            format!(
-                "PayPal IPN verification failed - status: {}",
-                verify_response.status()
-            ),
-        ));
+    if state.log_raw_ipn {
+        info!(
+            logger,
+            "Raw IPN: \"{}\"",
+            state.log_body(&ipn_transaction_message_raw)
+        );
     }
 
     let serde_qs_loose = serde_qs::Config::new(5, false);
 
-    // Check just the `txn_type` of the IPN message.
-    let txn_type =
+    // Check just the `txn_type` (& refund details) of the IPN message.
+    let message_type =
         match serde_qs_loose.deserialize_str::<IPNMessageTypeOnly>(&ipn_transaction_message_raw) {
-            Ok(msg) => msg.txn_type,
+            Ok(msg) => msg,
             Err(error) => {
                 return Err(tide::Error::from_str(
                     StatusCode::InternalServerError,
                     format!(
                         "(Message Type Check) Invalid IPN: unparseable IPN: \"{}\" - error: {}",
-                        ipn_transaction_message_raw, error
+                        state.log_body(&ipn_transaction_message_raw),
+                        error
                     ),
                 ));
             }
         };
 
     // PayPal buttons - we accept yearly subscriptions ("subscr_payment") and one-off yearly payments ("web_accept").
-    match txn_type.as_deref() {
+    match message_type.txn_type.as_deref() {
         Some("web_accept" | "subscr_payment") => (), // Ok
+        Some("subscr_signup") => {
+            // Arrives before the first "subscr_payment", and has no payment details.
+            return subscr_signup(
+                logger,
+                state,
+                &ipn_transaction_message_raw,
+                &retry_budget,
+                verified,
+            )
+            .await;
+        }
         Some(txn_type) => {
-            return Err(tide::Error::from_str(
-                StatusCode::Ok, // Don't want PayPal to retry.
-                format!("IPN: txn_type was not acceptable: {}", txn_type),
-            ));
+            return dead_letter(logger, state, txn_type, &ipn_transaction_message_raw).await;
         }
         None => {
+            if let Some(parent_txn_id) = &message_type.parent_txn_id {
+                if is_refund(message_type.payment_status.as_deref()) {
+                    return reverse_grant(
+                        logger,
+                        state,
+                        &ipn_transaction_message_raw,
+                        parent_txn_id,
+                        &retry_budget,
+                        verified,
+                    )
+                    .await;
+                }
+                info!(
+                    logger,
+                    "IPN: {} of original payment \"{}\", membership not adjusted",
+                    message_type
+                        .payment_status
+                        .as_deref()
+                        .unwrap_or("(no status)"),
+                    parent_txn_id
+                );
+                return Ok(outcome_response(state, IpnOutcome::Refund));
+            }
             return Err(tide::Error::from_str(
-                StatusCode::Ok, // Don't want PayPal to retry.
+                state.config().ipn_outcome_status(IpnOutcome::NoTxnType),
                 format!(
                     "IPN: no transaction type. IPN: \"{}\"",
-                    ipn_transaction_message_raw
+                    state.log_body(&ipn_transaction_message_raw)
                 ),
             ));
         }
@@ -137,204 +369,3952 @@ pub async fn ipn_handler(mut req: AppRequest) -> tide::Result<Response> {
                     StatusCode::InternalServerError,
                     format!(
                         "(Full IPN Details) Invalid IPN: unparseable IPN: \"{}\" - error: {}",
-                        ipn_transaction_message_raw, error
+                        state.log_body(&ipn_transaction_message_raw),
+                        error
                     ),
                 ));
             }
         };
 
+    Span::current()
+        .record("txn_id", ipn_transaction_message.txn_id.as_str())
+        .record("email", ipn_transaction_message.payer_email.as_str());
+
+    let test_ipn = ipn_transaction_message.test_ipn.as_deref() == Some("1");
+    if test_ipn {
+        info!(
+            logger,
+            "TEST IPN: from the PayPal IPN simulator or sandbox (`test_ipn=1`)"
+        );
+    }
+
     if let Some(txn_subject) = ipn_transaction_message.transaction_subject {
         info!(logger, "IPN `transaction_subject`: {}", txn_subject);
     } else {
         info!(logger, "No `transaction_subject` in IPN");
     }
 
-    if let Some(payment_date) = ipn_transaction_message.payment_date {
+    if let Some(payment_date) = &ipn_transaction_message.payment_date {
         info!(logger, "Payment Timestamp: {}", payment_date);
     }
 
-    // Check the result of IPN verification.
-    let verify_status = verify_response.body_string().await?;
-    match verify_status.as_str() {
-        "VERIFIED" => {
+    // Anything that isn't a success status, by default just "Completed", we don't care about.
+    //
+    // Usually this means a "Completed" IPN will be sent later from a pending transaction.
+    let config = state.config();
+    let preregister = if !config
+        .paypal_success_statuses
+        .iter()
+        .any(|status| status.eq_ignore_ascii_case(&ipn_transaction_message.payment_status))
+    {
+        info!(
+            logger,
+            "IPN: Payment status was not a success status ({}): {}",
+            config.paypal_success_statuses.join(", "),
+            ipn_transaction_message.payment_status
+        );
+        // A refund takes back the membership time its original payment granted, if we know of it.
+        if let Some(parent_txn_id) = &ipn_transaction_message.parent_txn_id {
+            if is_refund(Some(&ipn_transaction_message.payment_status)) {
+                return reverse_grant(
+                    logger,
+                    state,
+                    &ipn_transaction_message_raw,
+                    parent_txn_id,
+                    &retry_budget,
+                    verified,
+                )
+                .await;
+            }
             info!(
                 logger,
-                "Verified IPN: IPN message for Transaction ID \"{}\" is verified",
-                ipn_transaction_message.txn_id
+                "IPN: {} of original payment \"{}\", membership not adjusted - Email: {}",
+                ipn_transaction_message.payment_status,
+                parent_txn_id,
+                ipn_transaction_message.payer_email
             );
         }
-        "INVALID" => {
-            return Err(tide::Error::from_str(
-                StatusCode::InternalServerError,
-                format!(
-                    "Invalid IPN: IPN message for Transaction ID \"{}\" is invalid. IPN: \"{}\"",
-                    ipn_transaction_message.txn_id, ipn_transaction_message_raw
-                ),
-            ));
-        }
-        unknown => {
-            return Err(tide::Error::from_str(
-                StatusCode::InternalServerError,
-                format!(
-                    "Invalid IPN: Unexpected IPN verify response body: \"{}\" - IPN: {}",
-                    unknown, ipn_transaction_message_raw
-                ),
-            ));
+        // New members may be added to the list right away, to be given an EXPIRES once the payment completes.
+        if !(config.preregister_pending
+            && ipn_transaction_message
+                .payment_status
+                .eq_ignore_ascii_case("Pending"))
+        {
+            let outcome = if ipn_transaction_message.parent_txn_id.is_some() {
+                IpnOutcome::Refund
+            } else {
+                IpnOutcome::NotSuccessStatus
+            };
+            return Ok(outcome_response(state, outcome));
         }
-    }
-
-    // Anything that isn't "Completed" we don't care about.
-    //
-    // Usually this means a "Completed" IPN will be sent later from a pending transaction.
-    if ipn_transaction_message.payment_status != "Completed" {
         info!(
             logger,
-            "IPN: Payment status was not \"Completed\": {}", ipn_transaction_message.payment_status
+            "IPN: pre-registering without an EXPIRES until the payment completes"
         );
-        return Ok(StatusCode::Ok.into());
-    }
+        true
+    } else {
+        false
+    };
 
     // Temporary: figure out why kind of payment values PayPal is actually giving us, as the docs are unclear.
     info!(
         logger,
         "IPN: type: \"{}\" - gross amount: {} - currency: {} - exchange rate: {}",
-        ipn_transaction_message.txn_type,
-        ipn_transaction_message.mc_gross,
-        ipn_transaction_message.mc_currency,
+        ipn_transaction_message
+            .txn_type
+            .as_deref()
+            .unwrap_or("(none)"),
+        ipn_transaction_message
+            .mc_gross
+            .as_deref()
+            .unwrap_or("(none)"),
+        ipn_transaction_message
+            .mc_currency
+            .as_deref()
+            .unwrap_or("(none)"),
         ipn_transaction_message
             .exchange_rate
             .as_deref()
             .unwrap_or("(none)"),
     );
 
-    let payment_amount: f64 = ipn_transaction_message.mc_gross.parse()?;
-    if payment_amount < 10.0 {
-        info!(logger, "Refusing membership, payment amount too low.",);
-        return Ok(StatusCode::Ok.into());
+    let Some(mc_gross) = ipn_transaction_message.mc_gross.as_deref() else {
+        info!(logger, "Refusing membership, no payment amount.");
+        return Ok(outcome_response(state, IpnOutcome::NoAmount));
+    };
+    let zero_decimal = is_zero_decimal(state, ipn_transaction_message.mc_currency.as_deref());
+    let Some(paid_amount) = parse_payment_amount(mc_gross, zero_decimal) else {
+        info!(
+            logger,
+            "Refusing membership, unparseable payment amount: \"{}\"", mc_gross
+        );
+        return Ok(outcome_response(state, IpnOutcome::UnparseableAmount));
+    };
+    let currency = ipn_transaction_message.mc_currency.as_deref();
+    // Foreign payments are converted with PayPal's exchange rate, when it gives one, so that
+    // the base currency's minimum applies.
+    let (payment_amount, min_amount) = match state.to_base_currency(
+        paid_amount,
+        currency,
+        ipn_transaction_message
+            .exchange_rate
+            .as_deref()
+            .and_then(parse_exchange_rate),
+    ) {
+        Some((converted_amount, base_currency)) => {
+            info!(
+                logger,
+                "Converted payment amount: {} {}", converted_amount, base_currency
+            );
+            (converted_amount, config.min_amount_for(Some(base_currency)))
+        }
+        None => {
+            if state.base_currency.as_deref().is_some_and(|base_currency| {
+                currency.is_some_and(|currency| !currency.eq_ignore_ascii_case(base_currency))
+            }) {
+                info!(
+                    logger,
+                    "No usable exchange rate (\"{}\") for a foreign payment, using the per-currency minimum.",
+                    ipn_transaction_message
+                        .exchange_rate
+                        .as_deref()
+                        .unwrap_or("(none)")
+                );
+            }
+            (paid_amount, config.min_amount_for(currency))
+        }
+    };
+    if payment_amount < min_amount {
+        info!(
+            logger,
+            "Refusing membership, payment amount too low. Minimum: {}", min_amount
+        );
+        return Ok(outcome_response(state, IpnOutcome::AmountTooLow));
     }
 
     info!(logger, "Email: {}", ipn_transaction_message.payer_email);
 
-    // The MailChimp api is a bit strange.
-    let hash = md5::compute(ipn_transaction_message.payer_email.to_lowercase());
-
     let mc_query = MailchimpQuery {
-        fields: &["EXPIRES"],
+        fields: &["EXPIRES", LASTPAY_MERGE_FIELD, GRANTS_MERGE_FIELD],
     };
 
-    // Check if the person is already in our MailChimp list.
-    let mc_path = format!("3.0/lists/{}/members/{:x}", state.mc_list_id, hash);
-    let mut mailchimp_res = state.mailchimp.get(&mc_path).query(&mc_query)?.await?;
+    // Multi-list setups may keep separate lists per currency.
+    let mc_list_id = state.mc_list_id_for(ipn_transaction_message.mc_currency.as_deref());
+
+    // Verify the IPN with PayPal (PayPal requires this), and meanwhile check if the person is already in our MailChimp list.
+    //
+    // The MailChimp response is not looked at until verification has succeeded.
+    let mc_path = member_path(mc_list_id, &ipn_transaction_message.payer_email);
+    let mc_get = state
+        .mailchimp
+        .get(&mc_path)
+        .query(&mc_query)?
+        .timed(&logger, state, "MailChimp");
+    let verify = async {
+        if verified {
+            return Ok(IpnVerification::Verified);
+        }
+        verify_ipn(
+            &mut logger,
+            state,
+            &ipn_transaction_message_raw,
+            &retry_budget,
+        )
+        .await
+    };
+    let (verification, mut mailchimp_res) = futures::try_join!(verify, mc_get)?;
+
+    // Check the result of IPN verification.
+    match verification {
+        IpnVerification::Verified => {
+            info!(
+                logger,
+                "Verified IPN: IPN message for Transaction ID \"{}\" is verified",
+                ipn_transaction_message.txn_id
+            );
+        }
+        IpnVerification::Invalid => {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "Invalid IPN: IPN message for Transaction ID \"{}\" is invalid. IPN: \"{}\"",
+                    ipn_transaction_message.txn_id,
+                    state.log_body(&ipn_transaction_message_raw)
+                ),
+            ));
+        }
+        IpnVerification::Unexpected(unknown) => {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "Invalid IPN: Unexpected IPN verify response body: \"{}\" - IPN: {}",
+                    state.log_body(&unknown),
+                    state.log_body(&ipn_transaction_message_raw)
+                ),
+            ));
+        }
+    }
 
     if mailchimp_res.status().is_server_error() {
         let error_body = mailchimp_res.body_string().await?;
 
         return Err(tide::Error::from_str(
             mailchimp_res.status(),
-            format!("Mailchimp GET: error body: \"{}\"", error_body),
+            format!(
+                "Mailchimp GET: error body: \"{}\"",
+                state.log_body(&error_body)
+            ),
         ));
     }
 
-    let utc_now: DateTime<Utc> = Utc::now();
-    let mut utc_expires: DateTime<Utc> = Utc::now() + Duration::days(365);
+    let payment_date = state.clock.now();
+    let today = payment_date.date_naive();
 
     let status;
+    let existing_expires;
+    let mut existing_last_payment = None;
+    let mut existing_grants = Vec::new();
+    let mut existing_member = None; // (status, merge fields), to skip a PUT which wouldn't change anything
+                                    // Members added by a subscription signup or a pending payment have no `EXPIRES` until their first payment.
+    let mut first_payment = false;
+    let new_member = mailchimp_res.status() == StatusCode::NotFound;
+    if preregister && !new_member {
+        info!(
+            logger,
+            "IPN: already on the list, the membership is renewed when the payment completes"
+        );
+        return Ok(StatusCode::Ok.into());
+    }
     if mailchimp_res.status().is_client_error() {
         // If the person is not in our list, set them as pending to give them an opportunity to properly accept if they want an email subscription.
-        status = "pending";
+        status = MembershipStatus::Pending;
+        existing_expires = None;
+        first_payment = true;
     } else {
-        let mc_json: MailchimpResponse = mailchimp_res.body_json().await?;
+        let mc_value: Value = mailchimp_res.body_json().await?;
+        let mc_json: MailchimpMember = serde_json::from_value(mc_value.clone())?;
+        existing_member = Some((mc_json.status, mc_value["merge_fields"].clone()));
         info!(
             logger,
             "Mailchimp existing status: {}",
             mc_json.status.as_str(),
         );
-        status = match mc_json.status.as_str() {
+        status = match mc_json.status {
             // Don't re-subscribe someone who has unsubscribed from our emails. They will still be a list member regardless.
-            "unsubscribed" => "unsubscribed",
-            "subscribed" => "subscribed",
-            _ => "pending",
+            MembershipStatus::Unsubscribed => MembershipStatus::Unsubscribed,
+            MembershipStatus::Subscribed => MembershipStatus::Subscribed,
+            // An existing member renewing, who may be taken to have consented by now.
+            MembershipStatus::Pending
+                if config.subscribe_policy == SubscribePolicy::DirectSubscribe =>
+            {
+                MembershipStatus::Subscribed
+            }
+            // Anyone else gets an opportunity to properly accept, as with new members.
+            MembershipStatus::Pending
+            | MembershipStatus::Cleaned
+            | MembershipStatus::Transactional
+            | MembershipStatus::Archived => MembershipStatus::Pending,
         };
+        if mc_json.status == MembershipStatus::Archived {
+            // A PUT with an explicit status un-archives the member, which a paying member should be.
+            info!(
+                logger,
+                "Mailchimp: member is archived, reactivating as \"{}\": {}",
+                status.as_str(),
+                mc_json.email_address
+            );
+        }
+
+        existing_last_payment = parse_expires(&mc_json.merge_fields.last_payment)
+            .ok()
+            .flatten();
+        existing_grants = ExpiryGrant::parse_all(&mc_json.merge_fields.grants);
 
         // Pick up an existing date if one exists and if we can parse it.
-        if let Ok(existing_expire_day) =
-            NaiveDate::parse_from_str(&mc_json.merge_fields.expires, "%Y-%m-%d")
-        {
-            let existing_expire = existing_expire_day
-                .and_hms_opt(12, 0, 0)
-                .expect("Failed to create a NaiveDateTime with the given date and time.");
-            let existing_expire = DateTime::from_naive_utc_and_offset(existing_expire, Utc);
-            if existing_expire > utc_expires {
+        existing_expires = match parse_expires(&mc_json.merge_fields.expires) {
+            Ok(Some(existing_expires)) => Some(existing_expires),
+            Ok(None) => {
+                // Some old members had blank fields.
+                info!(
+                    logger,
+                    "No existing EXPIRES, using the payment period from today"
+                );
+                first_payment = true;
+                None
+            }
+            Err(error) => {
+                // Weird, we couldn't parse the date.
                 info!(
                     logger,
-                    "existing EXPIRES is beyond one year, using it: {}",
-                    mc_json.merge_fields.expires
+                    "Could not parse MailChimp existing EXPIRES: \"{}\" - error: {}",
+                    mc_json.merge_fields.expires,
+                    error
                 );
-                utc_expires = existing_expire;
+                None
             }
+        };
+    };
+
+    // Recurring subscription payments may be set up differently from one-off payments.
+    let (mut period_days, stack) =
+        if ipn_transaction_message.txn_type.as_deref() == Some("subscr_payment") {
+            (
+                config.subscr_payment_period_days,
+                config.subscr_payment_stacks,
+            )
         } else {
-            // Weird, we couldn't parse the date. Maybe it was blank in mailchimp? (Some old members had blank fields.)
+            (config.web_accept_period_days, false)
+        };
+    // Chapters with several membership products tell them apart by the PayPal button.
+    let membership_type =
+        resolve_membership_type(&config, ipn_transaction_message.item_number.as_deref());
+    if !config.membership_types.is_empty() {
+        match membership_type {
+            Some(membership_type) => info!(
+                logger,
+                "Membership type: {} - item: {}",
+                membership_type.name,
+                ipn_transaction_message
+                    .item_name
+                    .as_deref()
+                    .unwrap_or("(no item name)")
+            ),
+            None => info!(
+                logger,
+                "Unknown membership item number, using the default period: {}",
+                ipn_transaction_message
+                    .item_number
+                    .as_deref()
+                    .unwrap_or("(none)")
+            ),
+        }
+    }
+    if let Some(type_period_days) =
+        membership_type.and_then(|membership_type| membership_type.period_days)
+    {
+        period_days = type_period_days;
+    }
+    let policy = ExpiryPolicy {
+        period_days,
+        stack,
+        renewal_window_days: config.renewal_window_days,
+        max_expiry_years: config.max_expiry_years,
+    };
+    // A resent IPN of a payment which already granted its membership time doesn't extend it again.
+    let already_granted = existing_grants
+        .iter()
+        .any(|grant| grant.txn_id == ipn_transaction_message.txn_id);
+    let expires = match existing_expires {
+        Some(existing_expires) if already_granted => {
             info!(
                 logger,
-                "Could not parse MailChimp existing EXPIRES: \"{}\"", mc_json.merge_fields.expires
-            )
+                "Payment \"{}\" was already granted, keeping EXPIRES",
+                ipn_transaction_message.txn_id
+            );
+            existing_expires
         }
+        _ => compute_expiry(
+            payment_date,
+            existing_expires,
+            existing_last_payment,
+            &policy,
+        ),
     };
+    if let Some(existing_expires) = existing_expires {
+        info!(
+            logger,
+            "EXPIRES: existing: {} - new: {}",
+            to_mailchimp_format(existing_expires),
+            to_mailchimp_format(expires)
+        );
+    }
 
     // Set up the new member's MailChimp information.
-    let mc_req = json!({
+    let mut merge_fields = json!({
+        "EXPIRES": to_mailchimp_format(expires),
+    });
+    // Only when creating the member, so that renewals keep when they joined, and a repeat IPN changes nothing.
+    if new_member {
+        merge_fields["JOINED"] = to_mailchimp_format(today).into();
+        add_new_member_defaults(&mut merge_fields, &config);
+    }
+    // When PayPal says the payment was made, which may be a while ago for a retried IPN.
+    let last_payment = ipn_transaction_message
+        .payment_date
+        .as_deref()
+        .and_then(parse_ipn_payment_date)
+        .unwrap_or(today);
+    merge_fields[LASTPAY_MERGE_FIELD] = to_mailchimp_format(last_payment).into();
+    // Remembered on the member, so that a refund of this payment can take back what it granted.
+    // Only the first grant of a `txn_id` counts, as a retried IPN doesn't grant any more.
+    let days = (expires - existing_expires.unwrap_or(today)).num_days();
+    if days > 0 && !already_granted {
+        let mut grants = vec![ExpiryGrant {
+            txn_id: ipn_transaction_message.txn_id.clone(),
+            days,
+            amount: paid_amount,
+        }];
+        grants.extend(existing_grants);
+        merge_fields[GRANTS_MERGE_FIELD] =
+            ExpiryGrant::format_all(&grants, MERGE_FIELD_MAX_LEN).into();
+    }
+    if let Some(membership_type) = membership_type {
+        merge_fields[MEMBERTYPE_MERGE_FIELD] = membership_type.name.as_str().into();
+    }
+    // Names are not always present, don't blank out existing names if so.
+    if let Some(first_name) = normalize_name(ipn_transaction_message.first_name, &config) {
+        merge_fields["FNAME"] = first_name.into();
+    }
+    if let Some(last_name) = normalize_name(ipn_transaction_message.last_name, &config) {
+        merge_fields["LNAME"] = last_name.into();
+    }
+    if let Some(custom) = ipn_transaction_message
+        .custom
+        .filter(|custom| !custom.is_empty())
+    {
+        merge_fields[state.mc_custom_merge_field.as_str()] = custom.into();
+    }
+    // A blank EXPIRES is filled in from the payment date by the "Completed" IPN.
+    if preregister {
+        if let Value::Object(merge_fields) = &mut merge_fields {
+            merge_fields.remove("EXPIRES");
+            merge_fields.remove(LASTPAY_MERGE_FIELD);
+            merge_fields.remove(GRANTS_MERGE_FIELD);
+        }
+    }
+    // The status is only set for members we found, so that a member added since (e.g. by a concurrent IPN)
+    // isn't set back to pending. MailChimp applies `status_if_new` only when it creates the member.
+    let mut mc_req = json!({
         "email_address": &ipn_transaction_message.payer_email,
-        "merge_fields": {
-            "FNAME": ipn_transaction_message.first_name,
-            "LNAME": ipn_transaction_message.last_name,
-            "JOINED": utc_now.to_rfc3339_opts(Secs, true),
-            "EXPIRES": utc_expires.to_rfc3339_opts(Secs, true),
-        },
-        "status": status,
+        "merge_fields": merge_fields,
+        "status_if_new": MembershipStatus::Pending,
     });
+    if existing_member.is_some() {
+        mc_req["status"] = json!(status);
+    }
 
-    // Add the new member to our MailChimp list.
-    let mc_path = format!("3.0/lists/{}/members/{:x}", state.mc_list_id, hash);
-    let mut mailchimp_res = state
-        .mailchimp
-        .put(&mc_path)
-        .body(Body::from_json(&mc_req)?)
-        .await?;
-
-    if !mailchimp_res.status().is_success() {
+    if test_ipn && state.test_ipn_dry_run {
+        info!(
+            logger,
+            "TEST IPN: dry run, not updating MailChimp: {}", mc_req
+        );
+        return Ok(StatusCode::Ok.into());
+    }
+
+    // Most often a repeat of an IPN which was already processed.
+    if let Some((existing_status, existing_merge_fields)) = &existing_member {
+        if *existing_status == status
+            && merge_fields_unchanged(&mc_req["merge_fields"], existing_merge_fields)
+        {
+            info!(
+                logger,
+                "Mailchimp: member is already up to date, not updating: {}",
+                ipn_transaction_message.payer_email
+            );
+            return Ok(StatusCode::Ok.into());
+        }
+    }
+
+    // Add the new member to our MailChimp list.
+    let mut mailchimp_res = state
+        .mailchimp
+        .put(&mc_path)
+        .body(Body::from_json(&mc_req)?)
+        .timed(&logger, state, "MailChimp")
+        .await?;
+
+    // MailChimp rejects over-long merge field values, e.g. very long names, and unknown merge fields. Fix those and retry once.
+    if mailchimp_res.status() == StatusCode::BadRequest {
+        let error_body = mailchimp_res.body_string().await?;
+        let error: MailchimpError = serde_json::from_str(&error_body).unwrap_or_default();
+
+        // Create (POST) semantics, which a PUT shouldn't hit, but the member is there either way.
+        if error.is_member_exists() {
+            info!(
+                logger,
+                "Mailchimp: member already exists, nothing to do for: {}",
+                ipn_transaction_message.payer_email
+            );
+            return Ok(StatusCode::Ok.into());
+        }
+
+        // Lists without the optional LASTPAY or GRANTS merge fields still get the rest.
+        let mut optional_rejected = false;
+        for optional_field in [LASTPAY_MERGE_FIELD, GRANTS_MERGE_FIELD] {
+            let rejected = error.errors.iter().any(|field_error| {
+                field_error.field.trim_start_matches("merge_fields.") == optional_field
+            });
+            if rejected {
+                if let Some(Value::Object(merge_fields)) = mc_req.get_mut("merge_fields") {
+                    merge_fields.remove(optional_field);
+                }
+                info!(
+                    logger,
+                    "Mailchimp: {} merge field was rejected, retrying without it", optional_field
+                );
+                optional_rejected = true;
+            }
+        }
+
+        let truncated = truncate_merge_fields(&mut mc_req, &error);
+        if truncated.is_empty() && !optional_rejected {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                format!("Mailchimp error: {}", state.log_body(&error_body)),
+            ));
+        }
+
+        if !retry_budget.allows(time::Duration::ZERO) {
+            return Err(tide::Error::from_str(
+                StatusCode::ServiceUnavailable, // PayPal will retry.
+                format!(
+                    "Mailchimp error, not retrying as the retry budget is used up: {}",
+                    state.log_body(&error_body)
+                ),
+            ));
+        }
+
+        if !truncated.is_empty() {
+            info!(
+                logger,
+                "Mailchimp: truncated over-long merge fields to {} characters, retrying: {}",
+                MERGE_FIELD_MAX_LEN,
+                truncated.join(", ")
+            );
+        }
+        mailchimp_res = state
+            .mailchimp
+            .put(&mc_path)
+            .body(Body::from_json(&mc_req)?)
+            .timed(&logger, state, "MailChimp")
+            .await?;
+    }
+
+    if !mailchimp_res.status().is_success() {
         let error_body = mailchimp_res.body_string().await?;
 
         Err(tide::Error::from_str(
             mailchimp_res.status(),
-            format!("Mailchimp error: {}", error_body),
+            format!("Mailchimp error: {}", state.log_body(&error_body)),
         ))
     } else {
-        let mc_json: MailchimpResponse = mailchimp_res.body_json().await?;
-        if mc_json.status == "pending" || mc_json.status == "subscribed" {
+        let mc_json: MailchimpMember = mailchimp_res.body_json().await?;
+        let audit_event = AuditEvent {
+            actor: "paypal_ipn",
+            email: &mc_json.email_address,
+            txn_id: Some(&ipn_transaction_message.txn_id),
+            old_status: existing_member.as_ref().map(|(status, _)| *status),
+            new_status: mc_json.status,
+            old_expires: existing_member
+                .as_ref()
+                .and_then(|(_, merge_fields)| merge_fields["EXPIRES"].as_str())
+                .filter(|expires| !expires.is_empty()),
+            new_expires: mc_req["merge_fields"]["EXPIRES"].as_str(),
+            timestamp: state.clock.now().to_rfc3339(),
+        };
+        if matches!(
+            mc_json.status,
+            MembershipStatus::Pending | MembershipStatus::Subscribed
+        ) {
             info!(
                 logger,
                 "Mailchimp: successfully set subscription status \"{}\" for: {}",
-                mc_json.status,
+                mc_json.status.as_str(),
                 mc_json.email_address
             );
-            Ok(StatusCode::Ok.into())
+            notify_subscription(
+                &mut logger,
+                state,
+                &ipn_transaction_message.txn_id,
+                &mc_json,
+            )
+            .await;
+            if first_payment && !preregister {
+                send_welcome(
+                    &mut logger,
+                    state,
+                    &config,
+                    &mc_json,
+                    expires,
+                    ipn_transaction_message.residence_country.as_deref(),
+                )
+                .await;
+            }
+            let mut res = StatusCode::Ok.into();
+            audit(&mut logger, state, &mut res, &audit_event).await;
+            Ok(res)
         } else if mc_json.status == status {
             // If someone has unsubscribed, we don't subscribe them again but it's also not an error.
             info!(
                 logger,
                 "Mailchimp: successfully renewed existing status \"{}\" for: {}",
-                mc_json.status,
+                mc_json.status.as_str(),
                 mc_json.email_address
             );
-            Ok(StatusCode::Ok.into())
+            notify_subscription(
+                &mut logger,
+                state,
+                &ipn_transaction_message.txn_id,
+                &mc_json,
+            )
+            .await;
+            let mut res = StatusCode::Ok.into();
+            audit(&mut logger, state, &mut res, &audit_event).await;
+            Ok(res)
         } else {
             Err(tide::Error::from_str(
                 StatusCode::InternalServerError,
                 format!(
                     "Mailchimp: unsuccessful result: {}",
-                    serde_json::to_string(&mc_json)?
+                    state.log_body(&serde_json::to_string(&mc_json)?)
                 ),
             ))
         }
     }
 }
+
+/// Whether an IPN `payment_status` takes back an earlier payment.
+fn is_refund(payment_status: Option<&str>) -> bool {
+    payment_status.is_some_and(|status| {
+        status.eq_ignore_ascii_case("Refunded") || status.eq_ignore_ascii_case("Reversed")
+    })
+}
+
+/// Take back the membership time a refunded or reversed payment granted, as kept in the member's `GRANTS` merge field.
+///
+/// Only a full refund takes back the whole grant, partial refunds are logged for a manual review.
+async fn reverse_grant(
+    mut logger: AzureFnLogger,
+    state: &AppState,
+    ipn_transaction_message_raw: &str,
+    parent_txn_id: &str,
+    retry_budget: &RetryBudget,
+    verified: bool,
+) -> tide::Result<Response> {
+    let refund_message: IPNRefundMessage =
+        match serde_qs::Config::new(5, false).deserialize_str(ipn_transaction_message_raw) {
+            Ok(msg) => msg,
+            Err(error) => {
+                return Err(tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!(
+                        "(Refund) Invalid IPN: unparseable IPN: \"{}\" - error: {}",
+                        state.log_body(ipn_transaction_message_raw),
+                        error
+                    ),
+                ));
+            }
+        };
+
+    // Verify the IPN with PayPal, unless it already was before being queued. PayPal requires this.
+    let verification = if verified {
+        IpnVerification::Verified
+    } else {
+        verify_ipn(
+            &mut logger,
+            state,
+            ipn_transaction_message_raw,
+            retry_budget,
+        )
+        .await?
+    };
+    if verification != IpnVerification::Verified {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            format!(
+                "Invalid IPN: refund of \"{}\" is not verified: \"{}\" - IPN: {}",
+                parent_txn_id,
+                state.log_body(&verification.to_string()),
+                state.log_body(ipn_transaction_message_raw)
+            ),
+        ));
+    }
+
+    let mc_query = MailchimpQuery {
+        fields: &["EXPIRES", GRANTS_MERGE_FIELD],
+    };
+    let mc_list_id = state.mc_list_id_for(refund_message.mc_currency.as_deref());
+    let email = &refund_message.payer_email;
+    let member = get_member(
+        &state.mailchimp,
+        mc_list_id,
+        email,
+        &mc_query,
+        state.log_body_max_len,
+    )
+    .await?;
+    let mut grants = member
+        .as_ref()
+        .map(|member| ExpiryGrant::parse_all(&member.merge_fields.grants))
+        .unwrap_or_default();
+    let Some(grant_index) = grants
+        .iter()
+        .position(|grant| grant.txn_id == parent_txn_id)
+    else {
+        info!(
+            logger,
+            "IPN: refund of unknown original payment \"{}\", membership not adjusted - Email: {}",
+            parent_txn_id,
+            email
+        );
+        return Ok(outcome_response(state, IpnOutcome::Refund));
+    };
+    let grant = grants.remove(grant_index);
+
+    let zero_decimal = is_zero_decimal(state, refund_message.mc_currency.as_deref());
+    let refund_amount = refund_message
+        .mc_gross
+        .as_deref()
+        .and_then(|mc_gross| parse_payment_amount(mc_gross, zero_decimal))
+        .map(f64::abs);
+    if !refund_amount.is_some_and(|refund_amount| refund_amount > grant.amount - 0.005) {
+        info!(
+            logger,
+            "IPN: partial refund of \"{}\" ({} of {}), membership not adjusted, please review - Email: {}",
+            parent_txn_id,
+            refund_message.mc_gross.as_deref().unwrap_or("(none)"),
+            grant.amount,
+            email
+        );
+        return Ok(outcome_response(state, IpnOutcome::Refund));
+    }
+
+    let existing_expires = member
+        .as_ref()
+        .and_then(|member| parse_expires(&member.merge_fields.expires).ok().flatten());
+    let (Some(member), Some(existing_expires)) = (member, existing_expires) else {
+        info!(
+            logger,
+            "IPN: refund of \"{}\", but the member has no EXPIRES, membership not adjusted - Email: {}",
+            parent_txn_id,
+            email
+        );
+        return Ok(outcome_response(state, IpnOutcome::Refund));
+    };
+
+    let expires = existing_expires - Duration::days(grant.days);
+    let mut mc_req = json!({
+        "merge_fields": {
+            "EXPIRES": to_mailchimp_format(expires),
+        },
+    });
+    mc_req["merge_fields"][GRANTS_MERGE_FIELD] =
+        ExpiryGrant::format_all(&grants, MERGE_FIELD_MAX_LEN).into();
+    let mut mailchimp_res = state
+        .mailchimp
+        .patch(member_path(mc_list_id, email))
+        .body(Body::from_json(&mc_req)?)
+        .timed(&logger, state, "MailChimp")
+        .await?;
+    if !mailchimp_res.status().is_success() {
+        let error_body = mailchimp_res.body_string().await?;
+        return Err(tide::Error::from_str(
+            mailchimp_res.status(),
+            format!("Mailchimp error: {}", state.log_body(&error_body)),
+        ));
+    }
+
+    info!(
+        logger,
+        "IPN: refund of \"{}\", took back {} days of membership, EXPIRES: {} -> {} - Email: {}",
+        parent_txn_id,
+        grant.days,
+        to_mailchimp_format(existing_expires),
+        to_mailchimp_format(expires),
+        email
+    );
+    let old_expires = to_mailchimp_format(existing_expires);
+    let new_expires = to_mailchimp_format(expires);
+    let audit_event = AuditEvent {
+        actor: "paypal_ipn",
+        email: &member.email_address,
+        txn_id: Some(parent_txn_id),
+        old_status: Some(member.status),
+        new_status: member.status,
+        old_expires: Some(&old_expires),
+        new_expires: Some(&new_expires),
+        timestamp: state.clock.now().to_rfc3339(),
+    };
+    let mut res = outcome_response(state, IpnOutcome::Refund);
+    audit(&mut logger, state, &mut res, &audit_event).await;
+    Ok(res)
+}
+
+/// A membership change, queued for auditing if `AUDIT_QUEUE` is on.
+#[derive(Debug, Serialize)]
+struct AuditEvent<'event> {
+    actor: &'event str, // what made the change, `paypal_ipn` or `admin_resync`
+    email: &'event str,
+    txn_id: Option<&'event str>,
+    old_status: Option<MembershipStatus>, // none for a new member
+    new_status: MembershipStatus,
+    old_expires: Option<&'event str>,
+    new_expires: Option<&'event str>,
+    timestamp: String,
+}
+
+/// Add an audit event to the response for the `audit` queue output, if `AUDIT_QUEUE` is on.
+///
+/// An event which can't be queued is logged, as the change itself has been made.
+async fn audit(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+    res: &mut Response,
+    event: &AuditEvent<'_>,
+) {
+    if !state.config().audit_queue {
+        return;
+    }
+    match serde_json::to_value(event) {
+        Ok(event) => {
+            let mut outputs = res.ext::<AzureFnOutputs>().cloned().unwrap_or_default();
+            outputs.0.insert(AUDIT_BINDING.to_string(), event);
+            res.insert_ext(outputs);
+        }
+        Err(error) => info!(logger, "Audit: event for {:?} not queued: {}", event, error),
+    }
+}
+
+/// The response for an IPN which is acknowledged & ignored, see `IPN_OUTCOME_STATUSES`.
+///
+/// `200` by default, so that PayPal doesn't retry it.
+fn outcome_response(state: &AppState, outcome: IpnOutcome) -> Response {
+    state.config().ipn_outcome_status(outcome).into()
+}
+
+/// Record an IPN with a `txn_type` we don't handle to the `DEAD_LETTER` sink, and acknowledge it.
+async fn dead_letter(
+    mut logger: AzureFnLogger,
+    state: &AppState,
+    txn_type: &str,
+    ipn_transaction_message_raw: &str,
+) -> tide::Result<Response> {
+    let mut res = outcome_response(state, IpnOutcome::UnhandledTxnType);
+    match state.config().dead_letter {
+        DeadLetterSink::Off => {
+            info!(logger, "IPN: txn_type was not acceptable: {}", txn_type);
+        }
+        DeadLetterSink::Log => {
+            info!(
+                logger,
+                "DEAD LETTER: IPN txn_type was not acceptable: {} - IPN: \"{}\"",
+                txn_type,
+                state.log_body(ipn_transaction_message_raw)
+            );
+        }
+        DeadLetterSink::Queue => {
+            info!(
+                logger,
+                "IPN: txn_type was not acceptable, dead-lettered to the queue: {}", txn_type
+            );
+            let mut outputs = Map::new();
+            outputs.insert(
+                DEAD_LETTER_BINDING.to_string(),
+                json!({
+                    "txn_type": txn_type,
+                    "received": state.clock.now().to_rfc3339(),
+                    "ipn": ipn_transaction_message_raw,
+                }),
+            );
+            res.insert_ext(AzureFnOutputs(outputs));
+        }
+    }
+    Ok(res)
+}
+
+/// Whether a member already has all of these merge fields, with the same values.
+///
+/// MailChimp has no ETags or conditional requests for list members, so the fetched member is compared instead.
+fn merge_fields_unchanged(merge_fields: &Value, existing: &Value) -> bool {
+    match merge_fields {
+        Value::Object(merge_fields) => merge_fields
+            .iter()
+            .all(|(name, value)| existing.get(name) == Some(value)),
+        _ => false,
+    }
+}
+
+/// Add the `NEW_MEMBER_MERGE_FIELDS` defaults, without replacing merge fields which are already set.
+///
+/// Only for members who aren't on the list yet, so that renewals don't overwrite them.
+fn add_new_member_defaults(merge_fields: &mut Value, config: &ReloadableConfig) {
+    if let Value::Object(merge_fields) = merge_fields {
+        for (name, value) in &config.new_member_merge_fields {
+            merge_fields
+                .entry(name.as_str())
+                .or_insert_with(|| value.clone());
+        }
+    }
+}
+
+/// The membership type for a PayPal button's `item_number`, or `None` for the default membership.
+fn resolve_membership_type<'config>(
+    config: &'config ReloadableConfig,
+    item_number: Option<&str>,
+) -> Option<&'config MembershipType> {
+    item_number.and_then(|item_number| config.membership_types.get(item_number.trim()))
+}
+
+/// Send the welcome email on a member's first payment, if `TEMPLATE_WELCOME` is set.
+///
+/// In the language for the payer's country, if `TEMPLATE_LOCALES` has one with a welcome template.
+/// The member is already on the list by now, so a failure is only logged.
+async fn send_welcome(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+    config: &ReloadableConfig,
+    member: &MailchimpMember,
+    expires: NaiveDate,
+    country: Option<&str>,
+) {
+    let Some(template_welcome) = &config.template_welcome else {
+        return;
+    };
+    let template_welcome = country
+        .and_then(|country| config.locale_for_country(country))
+        .and_then(|locale| locale.welcome.as_ref())
+        .unwrap_or(template_welcome);
+    if config
+        .suppressed_emails
+        .contains(&member.email_address.to_lowercase())
+    {
+        info!(logger, "Suppressed email, no welcome email");
+        return;
+    }
+
+    let template_data = MembershipTemplateData {
+        member_name: member.merge_fields.first_name.clone(),
+        expires: to_mailchimp_format(expires),
+        status: "active".to_string(),
+        membership_type: member.merge_fields.membership_type.clone(),
+    };
+    let mut mail = SendGridMail::template(
+        &member.email_address,
+        FROM_EMAIL,
+        template_welcome,
+        Some(template_data),
+    );
+    mail.from.name = state.sendgrid_from_name.clone();
+    mail.reply_to = state.sendgrid_reply_to.clone();
+    if let Some(qa_redirect_email) = &config.qa_redirect_email {
+        mail.redirect_to(qa_redirect_email);
+    }
+
+    match state.email.send(logger, state, &mail).await {
+        Ok(()) => info!(logger, "Welcome email sent to: {}", member.email_address),
+        Err(error) => info!(logger, "Welcome email not sent: {}", error),
+    }
+}
+
+/// Verify an IPN with PayPal, by the legacy postback or the REST api, see `IPN_VERIFY_METHOD`.
+///
+/// PayPal occasionally returns `INVALID` transiently due to its own propagation delays,
+/// so that is retried with exponential backoff, up to the configured number of attempts.
+async fn verify_ipn(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+    ipn_transaction_message_raw: &str,
+    retry_budget: &RetryBudget,
+) -> tide::Result<IpnVerification> {
+    // Checked against the sandbox flag here too, so that live IPNs are always verified.
+    if state.skip_paypal_verify && state.paypal_sandbox {
+        info!(
+            logger,
+            "SANDBOX: SKIP_PAYPAL_VERIFY: treating the IPN as VERIFIED without asking PayPal"
+        );
+        return Ok(IpnVerification::Verified);
+    }
+
+    let attempts = state.config().paypal_verify_attempts;
+    let mut delay = VERIFY_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let verification = match state.ipn_verify_method {
+            IpnVerifyMethod::Postback => {
+                verify_postback(logger, state, ipn_transaction_message_raw).await?
+            }
+            IpnVerifyMethod::Rest => {
+                verify_rest(logger, state, ipn_transaction_message_raw).await?
+            }
+        };
+        if verification != IpnVerification::Invalid || attempt >= attempts {
+            return Ok(verification);
+        }
+        if !retry_budget.allows(delay) {
+            // Treated as INVALID, which PayPal retries.
+            info!(
+                logger,
+                "PayPal IPN verification returned INVALID, not retrying as the retry budget is used up (attempt {} of {})",
+                attempt,
+                attempts
+            );
+            return Ok(verification);
+        }
+
+        info!(
+            logger,
+            "PayPal IPN verification returned INVALID, retrying in {:?} (attempt {} of {})",
+            delay,
+            attempt,
+            attempts
+        );
+        task::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Verify an IPN by posting it back to PayPal, which answers `VERIFIED` or `INVALID`.
+async fn verify_postback(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+    ipn_transaction_message_raw: &str,
+) -> tide::Result<IpnVerification> {
+    let verification_body = ["cmd=_notify-validate&", ipn_transaction_message_raw].concat();
+    let verify_request = state
+        .paypal
+        .post("/cgi-bin/webscr")
+        .body(verification_body)
+        .timed(logger, state, "PayPal");
+    // Applied here rather than on the client, as the proxy client doesn't take per-client timeouts.
+    let mut verify_response =
+        match future::timeout(state.paypal_verify_timeout, verify_request).await {
+            Ok(verify_response) => verify_response?,
+            Err(_) => {
+                info!(
+                    logger,
+                    "PayPal IPN verification timed out after {:?}", state.paypal_verify_timeout
+                );
+                return Err(tide::Error::from_str(
+                    StatusCode::InternalServerError, // PayPal will retry.
+                    "PayPal IPN verification timed out",
+                ));
+            }
+        };
+
+    if !verify_response.status().is_success() {
+        let status = verify_response.status();
+        let error_body = verify_response.body_string().await?;
+        info!(
+            logger,
+            "PayPal IPN verification error - status: {} - body: \"{}\"",
+            status,
+            state.log_body(&error_body)
+        );
+
+        // A 4xx is most likely a bad request on our part, which retrying won't fix.
+        let retry_status = if status.is_client_error() {
+            StatusCode::Ok // Don't want PayPal to retry.
+        } else {
+            StatusCode::InternalServerError
+        };
+        return Err(tide::Error::from_str(
+            retry_status,
+            format!(
+                "PayPal IPN verification failed - status: {} - body: \"{}\"",
+                status,
+                state.log_body(&error_body)
+            ),
+        ));
+    }
+
+    Ok(IpnVerification::from_body(
+        &verify_response.body_string().await?,
+    ))
+}
+
+/// Verify an IPN by finding its transaction with the PayPal REST api, and checking it matches the IPN.
+///
+/// The transaction may be pending or a refund, as IPNs are sent for those too, so only the size of the amount is compared.
+/// IPNs without a `txn_id`, i.e. subscription signups, can only be verified by postback.
+/// The transaction search can take a while to include new payments, until then the IPN is `INVALID`, which PayPal retries.
+async fn verify_rest(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+    ipn_transaction_message_raw: &str,
+) -> tide::Result<IpnVerification> {
+    let ipn: IPNRestVerifyMessage =
+        serde_qs::Config::new(5, false).deserialize_str(ipn_transaction_message_raw)?;
+    let Some(txn_id) = ipn.txn_id.as_deref() else {
+        info!(
+            logger,
+            "PayPal REST verification: no txn_id, verifying by postback instead"
+        );
+        return verify_postback(logger, state, ipn_transaction_message_raw).await;
+    };
+    let Some(credentials) = &state.paypal_rest_credentials else {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "PayPal REST verification needs PAYPAL_CLIENT_ID & PAYPAL_CLIENT_SECRET",
+        ));
+    };
+
+    let access_token =
+        paypal::access_token(&state.paypal_rest, credentials, state.log_body_max_len).await?;
+    let now = state.clock.now();
+    let payment = paypal::find_transaction(
+        &state.paypal_rest,
+        &access_token,
+        txn_id,
+        now - Duration::days(REST_VERIFY_LOOKBACK_DAYS),
+        now,
+        state.log_body_max_len,
+    )
+    .await?;
+
+    let Some(payment) = payment else {
+        info!(
+            logger,
+            "PayPal REST verification: no transaction found for: {}", txn_id
+        );
+        return Ok(IpnVerification::Invalid);
+    };
+    let zero_decimal = is_zero_decimal(state, ipn.mc_currency.as_deref());
+    let amount_matches = match (
+        ipn.mc_gross
+            .as_deref()
+            .and_then(|gross| parse_payment_amount(gross, zero_decimal)),
+        parse_payment_amount(&payment.amount, false),
+    ) {
+        (Some(ipn_amount), Some(amount)) => (ipn_amount.abs() - amount.abs()).abs() < 0.005,
+        _ => false,
+    };
+    let matches = amount_matches
+        && ipn
+            .mc_currency
+            .as_deref()
+            .is_some_and(|currency| currency.eq_ignore_ascii_case(&payment.currency))
+        && ipn
+            .payer_email
+            .as_deref()
+            .is_some_and(|email| email.eq_ignore_ascii_case(&payment.email));
+    if !matches {
+        info!(
+            logger,
+            "PayPal REST verification: the payment doesn't match the IPN - payment: {} {} from {}",
+            payment.amount,
+            payment.currency,
+            payment.email
+        );
+        return Ok(IpnVerification::Invalid);
+    }
+
+    Ok(IpnVerification::Verified)
+}
+
+/// Truncate the merge fields in a MailChimp member request which MailChimp rejected for being too long.
+///
+/// Returns the names of the truncated fields.
+fn truncate_merge_fields(mc_req: &mut Value, error: &MailchimpError) -> Vec<String> {
+    let mut truncated = vec![];
+    for field_error in &error.errors {
+        let name = field_error
+            .field
+            .strip_prefix("merge_fields.")
+            .unwrap_or(&field_error.field);
+        if let Some(Value::String(value)) = mc_req.pointer_mut(&format!("/merge_fields/{}", name)) {
+            if value.chars().count() > MERGE_FIELD_MAX_LEN {
+                *value = value.chars().take(MERGE_FIELD_MAX_LEN).collect();
+                truncated.push(name.to_string());
+            }
+        }
+    }
+    truncated
+}
+
+/// The date of an IPN `payment_date`, such as `18:30:30 Feb 28, 2011 PST`.
+///
+/// The time & timezone are ignored, the date is as PayPal reports it.
+fn parse_ipn_payment_date(payment_date: &str) -> Option<NaiveDate> {
+    let (date_time, _timezone) = payment_date.trim().rsplit_once(' ')?;
+    NaiveDateTime::parse_from_str(date_time, "%H:%M:%S %b %d, %Y")
+        .ok()
+        .map(|date_time| date_time.date())
+}
+
+/// Whether a currency has no fractional part, per `ZERO_DECIMAL_CURRENCIES`.
+fn is_zero_decimal(state: &AppState, currency: Option<&str>) -> bool {
+    currency.is_some_and(|currency| {
+        state
+            .zero_decimal_currencies
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(currency))
+    })
+}
+
+/// Parse a PayPal payment amount, which is formatted per the payer's locale.
+///
+/// Accepts both `.` & `,` as the decimal separator (`10.00`, `10,00`), and as thousands grouping (`1,000.00`).
+/// Currencies without a fractional part (`zero_decimal`) must not have a non-zero fraction.
+fn parse_payment_amount(amount: &str, zero_decimal: bool) -> Option<f64> {
+    let amount = amount.trim();
+    let (sign, amount) = match amount.strip_prefix('-') {
+        Some(amount) => ("-", amount),
+        None => ("", amount),
+    };
+
+    let (whole, fraction) = match amount.rfind(['.', ',']) {
+        // Exactly three digits after the last separator is thousands grouping, e.g. "1,000".
+        Some(i) if amount.len() - i - 1 != 3 => (&amount[..i], &amount[i + 1..]),
+        _ => (amount, ""),
+    };
+    let whole: String = whole.chars().filter(|c| !matches!(c, '.' | ',')).collect();
+
+    if whole.is_empty()
+        || !whole.chars().all(|c| c.is_ascii_digit())
+        || !fraction.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    if zero_decimal && fraction.chars().any(|c| c != '0') {
+        return None;
+    }
+
+    format!("{}{}.{}0", sign, whole, fraction).parse().ok()
+}
+
+/// Tidy a PayPal name for MailChimp, per `NAME_NORMALIZATION`, or `None` if there is nothing left.
+fn normalize_name(name: Option<String>, config: &ReloadableConfig) -> Option<String> {
+    let name = name?;
+    let name = match config.name_normalization {
+        NameNormalization::Off => return Some(name),
+        NameNormalization::Whitespace => name.split_whitespace().collect::<Vec<_>>().join(" "),
+        NameNormalization::TitleCase => name
+            .split_whitespace()
+            .map(title_case_word)
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    (!name.is_empty()).then_some(name)
+}
+
+/// Upper case the first letter of a word & of each part after a `-` or `'`, e.g. `Mary-Jane O'Neil`, and lower case the rest.
+///
+/// By `char`, so multibyte letters such as `é` are cased correctly.
+fn title_case_word(word: &str) -> String {
+    let mut title_cased = String::with_capacity(word.len());
+    let mut start_of_part = true;
+    for c in word.chars() {
+        if start_of_part {
+            title_cased.extend(c.to_uppercase());
+        } else {
+            title_cased.extend(c.to_lowercase());
+        }
+        start_of_part = matches!(c, '-' | '\'');
+    }
+    title_cased
+}
+
+/// Parse a PayPal `exchange_rate`, a plain positive decimal such as `0.731410`.
+///
+/// Blank or malformed rates, including exponents & signs which `f64` would otherwise accept, are `None`.
+fn parse_exchange_rate(rate: &str) -> Option<f64> {
+    let rate = rate.trim();
+    let (whole, fraction) = rate.split_once('.').unwrap_or((rate, ""));
+    if whole.is_empty()
+        || !whole.chars().all(|c| c.is_ascii_digit())
+        || !fraction.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    rate.parse()
+        .ok()
+        .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+}
+
+/// Pre-register a PayPal subscription signup as a `pending` MailChimp member, without an `EXPIRES`.
+///
+/// The real expiry is set when the first "subscr_payment" IPN arrives.
+async fn subscr_signup(
+    mut logger: AzureFnLogger,
+    state: &AppState,
+    ipn_transaction_message_raw: &str,
+    retry_budget: &RetryBudget,
+    verified: bool,
+) -> tide::Result<Response> {
+    let serde_qs_loose = serde_qs::Config::new(5, false);
+
+    let signup_message: IPNSubscrSignupMessage =
+        match serde_qs_loose.deserialize_str(ipn_transaction_message_raw) {
+            Ok(msg) => msg,
+            Err(error) => {
+                return Err(tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!(
+                        "(Subscription Signup) Invalid IPN: unparseable IPN: \"{}\" - error: {}",
+                        state.log_body(ipn_transaction_message_raw),
+                        error
+                    ),
+                ));
+            }
+        };
+
+    let subscr_id = signup_message.subscr_id.as_deref().unwrap_or("(none)");
+
+    // Verify the IPN with PayPal, unless it already was before being queued. PayPal requires this.
+    let verification = if verified {
+        IpnVerification::Verified
+    } else {
+        verify_ipn(
+            &mut logger,
+            state,
+            ipn_transaction_message_raw,
+            retry_budget,
+        )
+        .await?
+    };
+    if verification != IpnVerification::Verified {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            format!(
+                "Invalid IPN: Subscription signup \"{}\" is not verified: \"{}\" - IPN: {}",
+                subscr_id,
+                state.log_body(&verification.to_string()),
+                state.log_body(ipn_transaction_message_raw)
+            ),
+        ));
+    }
+
+    info!(
+        logger,
+        "Verified IPN: Subscription signup \"{}\" - Email: {}",
+        subscr_id,
+        signup_message.payer_email
+    );
+
+    let mc_query = MailchimpQuery {
+        fields: &["status"],
+    };
+
+    let mc_list_id = state.mc_list_id_for(signup_message.mc_currency.as_deref());
+
+    // Check if the person is already in our MailChimp list.
+    let mc_path = member_path(mc_list_id, &signup_message.payer_email);
+    let mut mailchimp_res = state
+        .mailchimp
+        .get(&mc_path)
+        .query(&mc_query)?
+        .timed(&logger, state, "MailChimp")
+        .await?;
+
+    if mailchimp_res.status().is_success() {
+        // Existing members are handled by the payment IPN which follows.
+        info!(
+            logger,
+            "Subscription signup: already a MailChimp member, leaving as-is: {}",
+            signup_message.payer_email
+        );
+        return Ok(StatusCode::Ok.into());
+    } else if mailchimp_res.status() != StatusCode::NotFound {
+        let error_body = mailchimp_res.body_string().await?;
+
+        return Err(tide::Error::from_str(
+            mailchimp_res.status(),
+            format!(
+                "Mailchimp GET: error body: \"{}\"",
+                state.log_body(&error_body)
+            ),
+        ));
+    }
+
+    // No EXPIRES, the payment sets it.
+    let mut merge_fields = json!({
+        "JOINED": to_mailchimp_format(state.clock.today()),
+    });
+    let config = state.config();
+    add_new_member_defaults(&mut merge_fields, &config);
+    if let Some(first_name) = normalize_name(signup_message.first_name, &config) {
+        merge_fields["FNAME"] = first_name.into();
+    }
+    if let Some(last_name) = normalize_name(signup_message.last_name, &config) {
+        merge_fields["LNAME"] = last_name.into();
+    }
+    let mc_req = json!({
+        "email_address": &signup_message.payer_email,
+        "merge_fields": merge_fields,
+        // Not `status`, so as not to reset a member the payment IPN added in the meantime.
+        "status_if_new": MembershipStatus::Pending,
+    });
+
+    if signup_message.test_ipn.as_deref() == Some("1") && state.test_ipn_dry_run {
+        info!(
+            logger,
+            "TEST IPN: dry run, not updating MailChimp: {}", mc_req
+        );
+        return Ok(StatusCode::Ok.into());
+    }
+
+    let mut mailchimp_res = state
+        .mailchimp
+        .put(&mc_path)
+        .body(Body::from_json(&mc_req)?)
+        .timed(&logger, state, "MailChimp")
+        .await?;
+
+    if !mailchimp_res.status().is_success() {
+        let error_body = mailchimp_res.body_string().await?;
+
+        return Err(tide::Error::from_str(
+            mailchimp_res.status(),
+            format!("Mailchimp error: {}", state.log_body(&error_body)),
+        ));
+    }
+
+    info!(
+        logger,
+        "Mailchimp: pre-registered pending member for subscription signup: {}",
+        signup_message.payer_email
+    );
+    let mut res = StatusCode::Ok.into();
+    let audit_event = AuditEvent {
+        actor: "paypal_ipn",
+        email: &signup_message.payer_email,
+        txn_id: None,
+        old_status: None,
+        new_status: MembershipStatus::Pending,
+        old_expires: None,
+        new_expires: None,
+        timestamp: state.clock.now().to_rfc3339(),
+    };
+    audit(&mut logger, state, &mut res, &audit_event).await;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_std::channel;
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::azure_function::{detached_logger, take_logs};
+    use crate::clock::FixedClock;
+    use crate::test_support::{
+        azure_server, fake_upstreams, get_member, insert_member, invocation, ipn, json, post,
+        server, test_config, test_state, text, Members, MockEmail, MockHttp, TraceRecorder,
+        LIST_ID,
+    };
+
+    fn refund(txn_id: &str, parent_txn_id: &str, email: &str) -> String {
+        format!(
+            "txn_type=web_accept&payment_status=Refunded&txn_id={}&parent_txn_id={}&payer_email={}&mc_gross=-20.00&mc_currency=CAD",
+            txn_id,
+            parent_txn_id,
+            email.replace('@', "%40")
+        )
+    }
+
+    fn expires_of(members: &Members, email: &str) -> Value {
+        get_member(members, LIST_ID, email).expect("member")["merge_fields"]["EXPIRES"].clone()
+    }
+
+    #[async_std::test]
+    async fn refunds_take_back_what_their_payment_granted() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2024-12-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let logger = detached_logger("test");
+
+        process_ipn(
+            logger.clone(),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+        assert_eq!(expires_of(&members, "ada@example.com"), "2025-06-15");
+
+        // The grant is kept on the member, so a restart in between doesn't lose it.
+        let state = test_state(&http, &MockEmail::default());
+        let res = process_ipn(
+            logger,
+            &state,
+            refund("TXN2", "TXN1", "ada@example.com"),
+            false,
+        )
+        .await
+        .expect("refund");
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(expires_of(&members, "ada@example.com"), "2024-12-01");
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["GRANTS"], "");
+    }
+
+    #[async_std::test]
+    async fn partial_refunds_are_logged_for_review() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2024-12-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let logger = detached_logger("test");
+
+        process_ipn(
+            logger.clone(),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["GRANTS"], "TXN1:196:20");
+
+        let res = process_ipn(
+            logger.clone(),
+            &state,
+            refund("TXN2", "TXN1", "ada@example.com").replace("mc_gross=-20.00", "mc_gross=-5.00"),
+            false,
+        )
+        .await
+        .expect("refund");
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(expires_of(&members, "ada@example.com"), "2025-06-15");
+        assert!(take_logs(&logger)
+            .await
+            .iter()
+            .any(|line| line.contains("partial refund of \"TXN1\" (-5.00 of 20)")));
+    }
+
+    #[async_std::test]
+    async fn membership_dates_are_written_date_only() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        let put = http
+            .sent_to("mailchimp.test")
+            .into_iter()
+            .find(|sent| sent.method == Method::Put)
+            .expect("PUT");
+        let mc_req: Value = serde_json::from_str(&put.body).expect("JSON");
+        assert_eq!(mc_req["merge_fields"]["JOINED"], "2024-06-15");
+        assert_eq!(mc_req["merge_fields"]["EXPIRES"], "2025-06-15");
+    }
+
+    #[test]
+    fn names_are_title_cased() {
+        let mut config = test_config();
+        config.name_normalization = NameNormalization::TitleCase;
+        let normalize = |name: &str| normalize_name(Some(name.to_string()), &config);
+
+        assert_eq!(
+            normalize("mary-jane o'NEIL").as_deref(),
+            Some("Mary-Jane O'Neil")
+        );
+        assert_eq!(
+            normalize("élodie DE LA TOUR").as_deref(),
+            Some("Élodie De La Tour")
+        );
+        assert_eq!(normalize("   "), None);
+        assert_eq!(normalize_name(None, &config), None);
+    }
+
+    #[async_std::test]
+    async fn new_members_get_joined() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["JOINED"], "2024-06-15");
+    }
+
+    #[async_std::test]
+    async fn renewals_which_change_nothing_are_not_written() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        // As left by a payment today, which PayPal is now retrying.
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({
+                "FNAME": "Ada",
+                "LNAME": "Lovelace",
+                "JOINED": "2020-03-01",
+                "EXPIRES": "2025-06-15",
+                "LASTPAY": "2024-06-15",
+            }),
+        );
+        let state = test_state(&http, &MockEmail::default());
+
+        let res = process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(http
+            .sent_to("mailchimp.test")
+            .iter()
+            .all(|sent| sent.method == Method::Get));
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["JOINED"], "2020-03-01");
+    }
+
+    #[async_std::test]
+    async fn refunds_of_unknown_payments_leave_the_membership_alone() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2025-01-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+
+        let res = process_ipn(
+            detached_logger("test"),
+            &state,
+            refund("TXN2", "TXN1", "ada@example.com"),
+            false,
+        )
+        .await
+        .expect("refund");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(http
+            .sent_to("mailchimp.test")
+            .iter()
+            .all(|sent| sent.method == Method::Get));
+        assert_eq!(expires_of(&members, "ada@example.com"), "2025-01-01");
+    }
+
+    #[async_std::test]
+    async fn queued_ipns_are_verified_before_being_acknowledged() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let (sender, queue) = channel::bounded(1);
+        let mut state = test_state(&http, &MockEmail::default());
+        state.ipn_queue = Some(sender);
+
+        let body = ipn("TXN1", "ada@example.com", "20.00");
+        let res: tide::http::Response = server(state)
+            .respond(post(
+                "/Paypal-IPN",
+                "application/x-www-form-urlencoded",
+                &body,
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(http.sent_to("paypal.test").len(), 1);
+        // The MailChimp update is left for the queue.
+        assert!(http.sent_to("mailchimp.test").is_empty());
+        assert!(members.lock().expect("members").is_empty());
+        assert_eq!(queue.try_recv().expect("queued IPN"), body);
+    }
+
+    #[async_std::test]
+    async fn invalid_ipns_are_not_queued() {
+        let (http, _) = fake_upstreams("INVALID");
+        let (sender, queue) = channel::bounded(1);
+        let mut state = test_state(&http, &MockEmail::default());
+        state.ipn_queue = Some(sender);
+        let mut config = test_config();
+        config.paypal_verify_attempts = 1;
+        state.set_config(config);
+
+        let body = ipn("TXN1", "ada@example.com", "20.00");
+        let res: tide::http::Response = server(state)
+            .respond(post(
+                "/Paypal-IPN",
+                "application/x-www-form-urlencoded",
+                &body,
+            ))
+            .await
+            .expect("response");
+
+        // PayPal retries it.
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        assert!(queue.is_empty());
+        assert!(http.sent_to("mailchimp.test").is_empty());
+    }
+
+    #[async_std::test]
+    async fn ipns_without_names_still_grant_a_membership() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+
+        let res = process_ipn(
+            detached_logger("test"),
+            &state,
+            "txn_type=web_accept&payment_status=Completed&txn_id=TXN1&payer_email=ada%40example.com&mc_gross=20.00&mc_currency=CAD".to_string(),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["EXPIRES"], "2025-06-15");
+        assert!(member["merge_fields"].get("FNAME").is_none());
+        assert!(member["merge_fields"].get("LNAME").is_none());
+    }
+
+    #[async_std::test]
+    async fn subscription_signups_are_pending_until_the_first_payment() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let logger = detached_logger("test");
+
+        process_ipn(
+            logger.clone(),
+            &state,
+            "txn_type=subscr_signup&subscr_id=I-1&payer_email=ada%40example.com&first_name=Ada&mc_currency=CAD".to_string(),
+            false,
+        )
+        .await
+        .expect("signup");
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["status"], "pending");
+        assert_eq!(member["merge_fields"]["JOINED"], "2024-06-15");
+        assert!(member["merge_fields"].get("EXPIRES").is_none());
+
+        process_ipn(
+            logger,
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00").replace("web_accept", "subscr_payment"),
+            false,
+        )
+        .await
+        .expect("payment");
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["status"], "pending");
+        assert_eq!(member["merge_fields"]["EXPIRES"], "2025-06-15");
+        assert_eq!(member["merge_fields"]["JOINED"], "2024-06-15");
+    }
+
+    #[test]
+    fn payment_amounts_are_parsed_in_any_locale() {
+        assert_eq!(parse_payment_amount("10.00", false), Some(10.0));
+        assert_eq!(parse_payment_amount("10,00", false), Some(10.0));
+        assert_eq!(parse_payment_amount("1,000.50", false), Some(1000.5));
+        assert_eq!(parse_payment_amount("1.000,50", false), Some(1000.5));
+        assert_eq!(parse_payment_amount("-20.00", false), Some(-20.0));
+        assert_eq!(parse_payment_amount("ten", false), None);
+        assert_eq!(parse_payment_amount("", false), None);
+    }
+
+    #[test]
+    fn zero_decimal_amounts_have_no_fraction() {
+        assert_eq!(parse_payment_amount("1000", true), Some(1000.0));
+        assert_eq!(parse_payment_amount("1,000", true), Some(1000.0));
+        assert_eq!(parse_payment_amount("1000.00", true), Some(1000.0));
+        assert_eq!(parse_payment_amount("1000.50", true), None);
+    }
+
+    async fn logs_of_unhandled_ipn(log_raw_ipn: bool) -> Vec<String> {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.log_raw_ipn = log_raw_ipn;
+        let logger = detached_logger("test");
+
+        process_ipn(
+            logger.clone(),
+            &state,
+            "txn_type=cart&txn_id=TXN1&payer_email=ada%40example.com".to_string(),
+            false,
+        )
+        .await
+        .expect("acknowledged");
+        take_logs(&logger).await
+    }
+
+    #[async_std::test]
+    async fn raw_ipns_are_logged_only_if_enabled() {
+        let logs = logs_of_unhandled_ipn(true).await;
+        assert!(logs
+            .iter()
+            .any(|line| line.contains("Raw IPN: \"txn_type=cart&txn_id=TXN1")));
+
+        let logs = logs_of_unhandled_ipn(false).await;
+        assert!(!logs.iter().any(|line| line.contains("ada%40example.com")));
+    }
+
+    async fn merge_fields_after(ipn: String) -> Value {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        process_ipn(detached_logger("test"), &state, ipn, false)
+            .await
+            .expect("payment");
+        get_member(&members, LIST_ID, "ada@example.com").expect("member")["merge_fields"].clone()
+    }
+
+    #[async_std::test]
+    async fn custom_values_are_stored_if_present() {
+        let merge_fields =
+            merge_fields_after(ipn("TXN1", "ada@example.com", "20.00") + "&custom=SQ24").await;
+        assert_eq!(merge_fields["CUSTOM"], "SQ24");
+
+        let merge_fields =
+            merge_fields_after(ipn("TXN1", "ada@example.com", "20.00") + "&custom=").await;
+        assert!(merge_fields.get("CUSTOM").is_none());
+
+        let merge_fields = merge_fields_after(ipn("TXN1", "ada@example.com", "20.00")).await;
+        assert!(merge_fields.get("CUSTOM").is_none());
+    }
+
+    /// PayPal answering `INVALID` the first `invalid` times, then `VERIFIED`, with MailChimp as [`fake_upstreams`].
+    fn flaky_paypal(invalid: usize) -> (MockHttp, Members) {
+        let (mailchimp, members) = fake_upstreams("VERIFIED");
+        let verifications = AtomicUsize::new(0);
+        let http = MockHttp::new(move |sent| {
+            if !sent.is_to("paypal.test") {
+                return mailchimp.respond(sent);
+            }
+            if verifications.fetch_add(1, Ordering::SeqCst) < invalid {
+                text(StatusCode::Ok, "INVALID")
+            } else {
+                text(StatusCode::Ok, "VERIFIED")
+            }
+        });
+        (http, members)
+    }
+
+    #[async_std::test]
+    async fn transiently_invalid_ipns_are_verified_again() {
+        let (http, members) = flaky_paypal(1);
+        let state = test_state(&http, &MockEmail::default());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        assert_eq!(http.sent_to("paypal.test").len(), 2);
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+    }
+
+    #[async_std::test]
+    async fn verification_gives_up_after_the_configured_attempts() {
+        let (http, members) = flaky_paypal(usize::MAX);
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.paypal_verify_attempts = 2;
+        state.set_config(config);
+
+        let error = process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect_err("invalid IPN");
+
+        assert_eq!(error.status(), StatusCode::InternalServerError);
+        assert_eq!(http.sent_to("paypal.test").len(), 2);
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_none());
+    }
+
+    #[async_std::test]
+    async fn ipn_spans_record_the_transaction() {
+        let recorder = TraceRecorder::default();
+        let _default = recorder.set_default();
+        let (http, _) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        let fields = recorder.span("process_ipn").expect("process_ipn span");
+        assert_eq!(fields.get("txn_id").map(String::as_str), Some("TXN1"));
+        assert_eq!(
+            fields.get("email").map(String::as_str),
+            Some("ada@example.com")
+        );
+    }
+
+    #[async_std::test]
+    async fn members_without_an_expiry_renew_from_today() {
+        for merge_fields in [json!({"EXPIRES": ""}), json!({"FNAME": "Ada"})] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            insert_member(
+                &members,
+                LIST_ID,
+                "ada@example.com",
+                "subscribed",
+                merge_fields,
+            );
+            let state = test_state(&http, &MockEmail::default());
+
+            let res = process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn("TXN1", "ada@example.com", "20.00"),
+                false,
+            )
+            .await
+            .expect("processed");
+
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(expires_of(&members, "ada@example.com"), "2025-06-15");
+        }
+    }
+
+    #[async_std::test]
+    async fn disabled_ipn_handler_asks_paypal_to_retry_later() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.ipn_enabled = false;
+        state.set_config(config);
+
+        let res: tide::http::Response = server(state)
+            .respond(post(
+                "/Paypal-IPN",
+                "application/x-www-form-urlencoded",
+                &ipn("TXN1", "ada@example.com", "20.00"),
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(
+            res.header(RETRY_AFTER).map(|value| value.as_str()),
+            Some(DISABLED_RETRY_AFTER)
+        );
+        assert!(http.sent().is_empty());
+        assert!(members.lock().expect("members").is_empty());
+    }
+
+    #[async_std::test]
+    async fn over_long_names_are_truncated_and_retried() {
+        let (mailchimp, members) = fake_upstreams("VERIFIED");
+        let http = MockHttp::new(move |sent| {
+            let too_long = sent.method == Method::Put
+                && serde_json::from_str::<Value>(&sent.body).is_ok_and(|req| {
+                    req["merge_fields"]["FNAME"]
+                        .as_str()
+                        .is_some_and(|name| name.chars().count() > MERGE_FIELD_MAX_LEN)
+                });
+            if too_long {
+                return json(
+                    StatusCode::BadRequest,
+                    &json!({
+                        "title": "Invalid Resource",
+                        "errors": [{"field": "FNAME", "message": "Value is too long"}],
+                    }),
+                );
+            }
+            mailchimp.respond(sent)
+        });
+        let state = test_state(&http, &MockEmail::default());
+        let logger = detached_logger("test");
+        let body = ipn("TXN1", "ada@example.com", "20.00")
+            .replace("first_name=Ada", &format!("first_name={}", "a".repeat(300)));
+
+        let res = process_ipn(logger.clone(), &state, body, false)
+            .await
+            .expect("processed");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let puts = http
+            .sent_to("mailchimp.test")
+            .into_iter()
+            .filter(|sent| sent.method == Method::Put)
+            .count();
+        assert_eq!(puts, 2);
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        let name = member["merge_fields"]["FNAME"].as_str().expect("FNAME");
+        assert_eq!(name.chars().count(), MERGE_FIELD_MAX_LEN);
+        assert!(take_logs(&logger).await.iter().any(|line| line
+            .contains("truncated over-long merge fields")
+            && line.contains("FNAME")));
+    }
+
+    /// Holds back PayPal's verification until MailChimp has been asked for the member,
+    /// which only happens if both requests are in flight at once.
+    #[derive(Clone, Debug)]
+    struct VerifyAfterLookup {
+        upstreams: MockHttp,
+        looked_up: (channel::Sender<()>, channel::Receiver<()>),
+    }
+
+    #[http_client::async_trait]
+    impl http_client::HttpClient for VerifyAfterLookup {
+        async fn send(
+            &self,
+            req: http_client::Request,
+        ) -> Result<http_client::Response, http_client::Error> {
+            if req.url().host_str() == Some("paypal.test") {
+                let looked_up =
+                    future::timeout(time::Duration::from_secs(1), self.looked_up.1.recv()).await;
+                if looked_up.is_err() {
+                    return Ok(text(StatusCode::Ok, "verified before the MailChimp lookup"));
+                }
+            } else if req.method() == Method::Get {
+                let _ = self.looked_up.0.try_send(());
+            }
+            self.upstreams.send(req).await
+        }
+    }
+
+    /// Send PayPal's & MailChimp's requests to `client` instead.
+    fn use_for_paypal_and_mailchimp(
+        state: &mut AppState,
+        client: impl http_client::HttpClient + Clone,
+    ) {
+        for (upstream, base_url) in [
+            (&mut state.paypal, "https://paypal.test/"),
+            (&mut state.mailchimp, "https://mailchimp.test/"),
+        ] {
+            *upstream = surf::Config::new()
+                .set_base_url(surf::Url::parse(base_url).expect("base url"))
+                .set_http_client(client.clone())
+                .try_into()
+                .expect("client");
+        }
+    }
+
+    fn verify_after_lookup(verification: &'static str) -> (AppState, MockHttp, Members) {
+        let (upstreams, members) = fake_upstreams(verification);
+        let client = VerifyAfterLookup {
+            upstreams: upstreams.clone(),
+            looked_up: channel::bounded(1),
+        };
+        let mut state = test_state(&upstreams, &MockEmail::default());
+        use_for_paypal_and_mailchimp(&mut state, client);
+        let mut config = test_config();
+        config.paypal_verify_attempts = 1;
+        state.set_config(config);
+        (state, upstreams, members)
+    }
+
+    #[async_std::test]
+    async fn verification_and_lookup_are_concurrent() {
+        let (state, http, members) = verify_after_lookup("VERIFIED");
+
+        let res = process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("processed");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let methods: Vec<_> = http
+            .sent_to("mailchimp.test")
+            .iter()
+            .map(|sent| sent.method)
+            .collect();
+        assert_eq!(methods, [Method::Get, Method::Put]);
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+    }
+
+    #[async_std::test]
+    async fn lookups_are_not_acted_on_without_verification() {
+        let (state, http, members) = verify_after_lookup("INVALID");
+
+        let res = process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await;
+
+        assert!(res.is_err());
+        let methods: Vec<_> = http
+            .sent_to("mailchimp.test")
+            .iter()
+            .map(|sent| sent.method)
+            .collect();
+        assert_eq!(methods, [Method::Get]);
+        assert!(members.lock().expect("members").is_empty());
+    }
+
+    /// PayPal failing verification with `status`, with MailChimp as [`fake_upstreams`].
+    fn failing_paypal(status: StatusCode) -> (MockHttp, Members) {
+        let (mailchimp, members) = fake_upstreams("VERIFIED");
+        let http = MockHttp::new(move |sent| {
+            if sent.is_to("paypal.test") {
+                return text(status, "PayPal says no");
+            }
+            mailchimp.respond(sent)
+        });
+        (http, members)
+    }
+
+    #[async_std::test]
+    async fn verify_server_errors_are_retried_by_paypal() {
+        let (http, members) = failing_paypal(StatusCode::InternalServerError);
+        let state = test_state(&http, &MockEmail::default());
+        let logger = detached_logger("test");
+
+        let error = process_ipn(
+            logger.clone(),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect_err("verification failed");
+
+        assert_eq!(error.status(), StatusCode::InternalServerError);
+        assert!(members.lock().expect("members").is_empty());
+        assert!(take_logs(&logger).await.iter().any(|line| line
+            .contains("PayPal IPN verification error - status: 500")
+            && line.contains("PayPal says no")));
+    }
+
+    #[async_std::test]
+    async fn verify_client_errors_are_not_retried() {
+        let (http, members) = failing_paypal(StatusCode::BadRequest);
+        let state = test_state(&http, &MockEmail::default());
+
+        let res: tide::http::Response = server(state)
+            .respond(post(
+                "/Paypal-IPN",
+                "application/x-www-form-urlencoded",
+                &ipn("TXN1", "ada@example.com", "20.00"),
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(http.sent_to("paypal.test").len(), 1);
+        assert!(members.lock().expect("members").is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_ipns_are_dry_runs_if_configured() {
+        for dry_run in [true, false] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let mut state = test_state(&http, &MockEmail::default());
+            state.test_ipn_dry_run = dry_run;
+            let logger = detached_logger("test");
+
+            let res = process_ipn(
+                logger.clone(),
+                &state,
+                ipn("TXN1", "ada@example.com", "20.00") + "&test_ipn=1",
+                false,
+            )
+            .await
+            .expect("processed");
+
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(
+                get_member(&members, LIST_ID, "ada@example.com").is_none(),
+                dry_run
+            );
+            let logs = take_logs(&logger).await;
+            assert!(logs.iter().any(|line| line.contains("TEST IPN")));
+            assert_eq!(
+                logs.iter()
+                    .any(|line| line.contains("TEST IPN: dry run, not updating MailChimp")),
+                dry_run
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn configured_success_statuses_grant_membership() {
+        for (payment_status, granted) in [
+            ("Completed", true),
+            ("processed", true),
+            ("Canceled_Reversal", false),
+            ("Denied", false),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let state = test_state(&http, &MockEmail::default());
+            let mut config = test_config();
+            config.paypal_success_statuses = vec!["Completed".to_string(), "Processed".to_string()];
+            state.set_config(config);
+
+            let res = process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn("TXN1", "ada@example.com", "20.00").replace(
+                    "payment_status=Completed",
+                    &format!("payment_status={}", payment_status),
+                ),
+                false,
+            )
+            .await
+            .expect("processed");
+
+            // Others are acknowledged, so that PayPal doesn't retry them.
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(
+                get_member(&members, LIST_ID, "ada@example.com").is_some(),
+                granted,
+                "{}",
+                payment_status
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn member_exists_errors_are_a_success() {
+        let (mailchimp, members) = fake_upstreams("VERIFIED");
+        let http = MockHttp::new(move |sent| {
+            if sent.method == Method::Put {
+                return json(
+                    StatusCode::BadRequest,
+                    &json!({
+                        "title": "Member Exists",
+                        "status": 400,
+                        "detail": "ada@example.com is already a list member.",
+                    }),
+                );
+            }
+            mailchimp.respond(sent)
+        });
+        let state = test_state(&http, &MockEmail::default());
+
+        let res = process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("processed");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        // Not retried.
+        let puts = http
+            .sent_to("mailchimp.test")
+            .into_iter()
+            .filter(|sent| sent.method == Method::Put)
+            .count();
+        assert_eq!(puts, 1);
+        assert!(members.lock().expect("members").is_empty());
+    }
+
+    #[async_std::test]
+    async fn minimum_amounts_are_per_currency() {
+        for (amount, currency, granted) in [
+            ("15.00", "USD", true),
+            ("12.00", "USD", false),
+            // The default minimum, for currencies without their own.
+            ("10.00", "EUR", true),
+            ("9.00", "EUR", false),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let state = test_state(&http, &MockEmail::default());
+            let mut config = test_config();
+            config.min_amounts = HashMap::from([("USD".to_string(), 15.0)]);
+            state.set_config(config);
+
+            process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn("TXN1", "ada@example.com", amount)
+                    .replace("mc_currency=CAD", &format!("mc_currency={}", currency)),
+                false,
+            )
+            .await
+            .expect("processed");
+
+            assert_eq!(
+                get_member(&members, LIST_ID, "ada@example.com").is_some(),
+                granted,
+                "{} {}",
+                amount,
+                currency
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn read_only_defers_ipns() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.read_only = true;
+        state.set_config(config);
+
+        let res: tide::http::Response = server(state)
+            .respond(post(
+                "/Paypal-IPN",
+                "application/x-www-form-urlencoded",
+                &ipn("TXN1", "ada@example.com", "20.00"),
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert!(res.header(RETRY_AFTER).is_some());
+        assert!(http.sent().is_empty());
+        assert!(members.lock().expect("members").is_empty());
+    }
+
+    #[async_std::test]
+    async fn archived_members_are_reactivated() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "archived",
+            json!({"EXPIRES": "2022-01-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let logger = detached_logger("test");
+
+        let res = process_ipn(
+            logger.clone(),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("processed");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let put = http
+            .sent_to("mailchimp.test")
+            .into_iter()
+            .find(|sent| sent.method == Method::Put)
+            .expect("PUT");
+        let put: Value = serde_json::from_str(&put.body).expect("PUT JSON");
+        // An explicit status, which un-archives.
+        assert_eq!(put["status"], "pending");
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["status"], "pending");
+        assert_eq!(member["merge_fields"]["EXPIRES"], "2025-06-15");
+        assert!(take_logs(&logger)
+            .await
+            .iter()
+            .any(|line| line.contains("member is archived, reactivating as \"pending\"")));
+    }
+
+    #[async_std::test]
+    async fn expiry_policy_depends_on_the_txn_type() {
+        for (txn_type, expires) in [
+            ("web_accept", "2025-06-15"),
+            // Monthly, stacked on the existing expiry.
+            ("subscr_payment", "2024-07-31"),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            insert_member(
+                &members,
+                LIST_ID,
+                "ada@example.com",
+                "subscribed",
+                json!({"EXPIRES": "2024-07-01"}),
+            );
+            let state = test_state(&http, &MockEmail::default());
+            let mut config = test_config();
+            config.subscr_payment_period_days = 30;
+            config.subscr_payment_stacks = true;
+            state.set_config(config);
+
+            process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn("TXN1", "ada@example.com", "20.00").replace("web_accept", txn_type),
+                false,
+            )
+            .await
+            .expect("processed");
+
+            assert_eq!(
+                expires_of(&members, "ada@example.com"),
+                expires,
+                "{}",
+                txn_type
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn resent_stacking_payments_do_not_extend_again() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2024-07-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.subscr_payment_period_days = 30;
+        config.subscr_payment_stacks = true;
+        state.set_config(config);
+        let payment =
+            ipn("TXN1", "ada@example.com", "20.00").replace("web_accept", "subscr_payment");
+
+        for _ in 0..2 {
+            process_ipn(detached_logger("test"), &state, payment.clone(), false)
+                .await
+                .expect("processed");
+            assert_eq!(expires_of(&members, "ada@example.com"), "2024-07-31");
+        }
+    }
+
+    /// Answers as [`fake_upstreams`], after a `delay` for requests to `host`.
+    #[derive(Clone, Debug)]
+    struct SlowUpstream {
+        upstreams: MockHttp,
+        host: &'static str,
+        delay: time::Duration,
+    }
+
+    #[http_client::async_trait]
+    impl http_client::HttpClient for SlowUpstream {
+        async fn send(
+            &self,
+            req: http_client::Request,
+        ) -> Result<http_client::Response, http_client::Error> {
+            if req.url().host_str() == Some(self.host) {
+                task::sleep(self.delay).await;
+            }
+            self.upstreams.send(req).await
+        }
+    }
+
+    fn slow_upstream(host: &'static str, delay: time::Duration) -> (AppState, Members) {
+        let (upstreams, members) = fake_upstreams("VERIFIED");
+        let client = SlowUpstream {
+            upstreams: upstreams.clone(),
+            host,
+            delay,
+        };
+        let mut state = test_state(&upstreams, &MockEmail::default());
+        use_for_paypal_and_mailchimp(&mut state, client);
+        state.paypal_verify_timeout = time::Duration::from_millis(50);
+        let mut config = test_config();
+        config.paypal_verify_attempts = 1;
+        state.set_config(config);
+        (state, members)
+    }
+
+    #[async_std::test]
+    async fn slow_verifications_time_out() {
+        let (state, members) = slow_upstream("paypal.test", time::Duration::from_secs(5));
+        let logger = detached_logger("test");
+
+        let error = process_ipn(
+            logger.clone(),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect_err("timed out");
+
+        // PayPal retries it.
+        assert_eq!(error.status(), StatusCode::InternalServerError);
+        assert!(members.lock().expect("members").is_empty());
+        assert!(take_logs(&logger)
+            .await
+            .iter()
+            .any(|line| line.contains("PayPal IPN verification timed out after 50ms")));
+    }
+
+    #[async_std::test]
+    async fn the_verify_timeout_is_only_for_verification() {
+        let (state, members) = slow_upstream("mailchimp.test", time::Duration::from_millis(100));
+
+        let res = process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("processed");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+    }
+
+    #[async_std::test]
+    async fn each_verification_outcome_is_typed() {
+        for (body, expected) in [
+            ("VERIFIED", IpnVerification::Verified),
+            ("INVALID", IpnVerification::Invalid),
+            (
+                "SERVICE UNAVAILABLE",
+                IpnVerification::Unexpected("SERVICE UNAVAILABLE".to_string()),
+            ),
+        ] {
+            let (http, _) = fake_upstreams(body);
+            let state = test_state(&http, &MockEmail::default());
+            let mut config = test_config();
+            config.paypal_verify_attempts = 1;
+            state.set_config(config);
+
+            let verification = verify_ipn(
+                &mut detached_logger("test"),
+                &state,
+                &ipn("TXN1", "ada@example.com", "20.00"),
+                &RetryBudget::start(state.config().retry_budget_secs),
+            )
+            .await
+            .expect("verification");
+
+            assert_eq!(verification, expected, "{}", body);
+            let sent = http.sent_to("paypal.test");
+            assert_eq!(sent.len(), 1);
+            assert!(sent[0]
+                .body
+                .starts_with("cmd=_notify-validate&txn_type=web_accept&"));
+        }
+    }
+
+    #[async_std::test]
+    async fn pending_members_renewing_follow_the_subscribe_policy() {
+        for (policy, status, expected) in [
+            (SubscribePolicy::Pending, "pending", "pending"),
+            (SubscribePolicy::DirectSubscribe, "pending", "subscribed"),
+            // Unsubscribed members are never re-subscribed.
+            (SubscribePolicy::Pending, "unsubscribed", "unsubscribed"),
+            (
+                SubscribePolicy::DirectSubscribe,
+                "unsubscribed",
+                "unsubscribed",
+            ),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            insert_member(
+                &members,
+                LIST_ID,
+                "ada@example.com",
+                status,
+                json!({"EXPIRES": "2024-07-01"}),
+            );
+            let state = test_state(&http, &MockEmail::default());
+            let mut config = test_config();
+            config.subscribe_policy = policy;
+            state.set_config(config);
+
+            process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn("TXN1", "ada@example.com", "20.00"),
+                false,
+            )
+            .await
+            .expect("payment");
+
+            let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+            assert_eq!(member["status"], expected, "{:?} - {}", policy, status);
+        }
+    }
+
+    #[async_std::test]
+    async fn payments_are_dated_by_the_clock() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.clock = Box::new(FixedClock(
+            Utc.with_ymd_and_hms(2023, 2, 28, 23, 59, 59)
+                .single()
+                .expect("valid date"),
+        ));
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["JOINED"], "2023-02-28");
+        assert_eq!(member["merge_fields"]["EXPIRES"], "2024-02-28");
+    }
+
+    #[async_std::test]
+    async fn locally_rejected_ipns_are_not_verified() {
+        for body in [
+            // No transaction type.
+            "payment_status=Completed&txn_id=TXN1&payer_email=ada%40example.com&mc_gross=20.00",
+            ipn("TXN1", "ada@example.com", "20.00")
+                .replace("payment_status=Completed", "payment_status=Pending")
+                .as_str(),
+            ipn("TXN1", "ada@example.com", "5.00").as_str(),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let state = test_state(&http, &MockEmail::default());
+
+            let _ = process_ipn(detached_logger("test"), &state, body.to_string(), false).await;
+
+            assert!(http.sent_to("paypal.test").is_empty(), "{}", body);
+            assert!(get_member(&members, LIST_ID, "ada@example.com").is_none());
+        }
+    }
+
+    #[test]
+    fn ipn_payment_dates_are_parsed() {
+        assert_eq!(
+            parse_ipn_payment_date("18:30:30 Feb 28, 2011 PST"),
+            NaiveDate::from_ymd_opt(2011, 2, 28)
+        );
+        assert_eq!(
+            parse_ipn_payment_date(" 00:00:01 Jun 01, 2024 PDT "),
+            NaiveDate::from_ymd_opt(2024, 6, 1)
+        );
+        assert_eq!(parse_ipn_payment_date("2024-06-01"), None);
+        assert_eq!(parse_ipn_payment_date(""), None);
+    }
+
+    #[async_std::test]
+    async fn lastpay_is_the_payment_date() {
+        for (payment_date, lastpay) in [
+            (
+                Some("18%3A30%3A30%20Jun%2010%2C%202024%20PDT"),
+                "2024-06-10",
+            ),
+            // Today, without a usable payment date.
+            (Some("yesterday"), "2024-06-15"),
+            (None, "2024-06-15"),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let state = test_state(&http, &MockEmail::default());
+            let mut body = ipn("TXN1", "ada@example.com", "20.00");
+            if let Some(payment_date) = payment_date {
+                body.push_str(&format!("&payment_date={}", payment_date));
+            }
+
+            process_ipn(detached_logger("test"), &state, body, false)
+                .await
+                .expect("payment");
+
+            let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+            assert_eq!(
+                member["merge_fields"]["LASTPAY"], lastpay,
+                "{:?}",
+                payment_date
+            );
+            // Distinct from JOINED, which is always when we processed the first payment.
+            assert_eq!(member["merge_fields"]["JOINED"], "2024-06-15");
+        }
+    }
+
+    #[async_std::test]
+    async fn lists_without_lastpay_are_updated_without_it() {
+        let (mailchimp, members) = fake_upstreams("VERIFIED");
+        let http = MockHttp::new(move |sent| {
+            if sent.method == Method::Put && sent.body.contains(LASTPAY_MERGE_FIELD) {
+                return json(
+                    StatusCode::BadRequest,
+                    &json!({
+                        "title": "Invalid Resource",
+                        "errors": [{"field": "merge_fields.LASTPAY", "message": "Unknown merge field"}],
+                    }),
+                );
+            }
+            mailchimp.respond(sent)
+        });
+        let state = test_state(&http, &MockEmail::default());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        let puts: Vec<_> = http
+            .sent_to("mailchimp.test")
+            .into_iter()
+            .filter(|sent| sent.method == Method::Put)
+            .collect();
+        assert_eq!(puts.len(), 2);
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["merge_fields"]["EXPIRES"], "2025-06-15");
+        assert!(member["merge_fields"].get("LASTPAY").is_none());
+    }
+
+    #[test]
+    fn exchange_rates_are_plain_positive_decimals() {
+        assert_eq!(parse_exchange_rate("0.731410"), Some(0.73141));
+        assert_eq!(parse_exchange_rate(" 1.25 "), Some(1.25));
+        assert_eq!(parse_exchange_rate("2"), Some(2.0));
+        assert_eq!(parse_exchange_rate(""), None);
+        assert_eq!(parse_exchange_rate("0"), None);
+        assert_eq!(parse_exchange_rate("-1.25"), None);
+        assert_eq!(parse_exchange_rate("1e3"), None);
+        assert_eq!(parse_exchange_rate(".5"), None);
+    }
+
+    #[async_std::test]
+    async fn foreign_payments_use_their_exchange_rate_if_given() {
+        for (exchange_rate, granted) in [
+            // 12 USD is 15 CAD, over the CAD minimum of 10.
+            (Some("1.25"), true),
+            // Otherwise the USD minimum of 15 applies.
+            (None, false),
+            (Some("bogus"), false),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let mut state = test_state(&http, &MockEmail::default());
+            state.base_currency = Some("CAD".to_string());
+            let mut config = test_config();
+            config.min_amounts = HashMap::from([("USD".to_string(), 15.0)]);
+            state.set_config(config);
+            let mut body = ipn("TXN1", "ada@example.com", "12.00")
+                .replace("mc_currency=CAD", "mc_currency=USD");
+            if let Some(exchange_rate) = exchange_rate {
+                body.push_str(&format!("&exchange_rate={}", exchange_rate));
+            }
+
+            process_ipn(detached_logger("test"), &state, body, false)
+                .await
+                .expect("response");
+
+            assert_eq!(
+                get_member(&members, LIST_ID, "ada@example.com").is_some(),
+                granted,
+                "{:?}",
+                exchange_rate
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn welcome_emails_are_only_sent_to_new_members() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "bob@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2024-07-01"}),
+        );
+        let email = MockEmail::default();
+        let state = test_state(&http, &email);
+        let mut config = test_config();
+        config.template_welcome = Some("d-welcome".to_string());
+        state.set_config(config);
+
+        for (txn_id, payer) in [("TXN1", "ada@example.com"), ("TXN2", "bob@example.com")] {
+            process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn(txn_id, payer, "20.00"),
+                false,
+            )
+            .await
+            .expect("payment");
+        }
+
+        let sent = email.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["template_id"], "d-welcome");
+        assert_eq!(
+            sent[0]["personalizations"][0]["to"][0]["email"],
+            "ada@example.com"
+        );
+        assert_eq!(
+            sent[0]["personalizations"][0]["dynamic_template_data"],
+            json!({
+                "member_name": "Ada",
+                "expires": "2025-06-15",
+                "status": "active",
+                "membership_type": "",
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn welcome_emails_are_off_by_default() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let email = MockEmail::default();
+        let state = test_state(&http, &email);
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        assert!(email.sent().is_empty());
+    }
+
+    #[async_std::test]
+    async fn pending_payments_preregister_new_members_until_completed() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.preregister_pending = true;
+        state.set_config(config);
+        let pending = ipn("TXN1", "ada@example.com", "20.00")
+            .replace("payment_status=Completed", "payment_status=Pending");
+
+        process_ipn(detached_logger("test"), &state, pending, false)
+            .await
+            .expect("pending payment");
+
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("preregistered");
+        assert_eq!(member["status"], "pending");
+        assert!(member["merge_fields"].get("EXPIRES").is_none());
+        assert!(member["merge_fields"].get("LASTPAY").is_none());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("completed payment");
+
+        assert_eq!(expires_of(&members, "ada@example.com"), "2025-06-15");
+    }
+
+    #[async_std::test]
+    async fn pending_payments_change_nothing_for_existing_members() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2024-07-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.preregister_pending = true;
+        state.set_config(config);
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00")
+                .replace("payment_status=Completed", "payment_status=Pending"),
+            false,
+        )
+        .await
+        .expect("pending payment");
+
+        assert_eq!(expires_of(&members, "ada@example.com"), "2024-07-01");
+        assert!(http
+            .sent_to("mailchimp.test")
+            .iter()
+            .all(|sent| sent.method == Method::Get));
+    }
+
+    #[async_std::test]
+    async fn pending_payments_are_ignored_by_default() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00")
+                .replace("payment_status=Completed", "payment_status=Pending"),
+            false,
+        )
+        .await
+        .expect("pending payment");
+
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_none());
+    }
+
+    #[async_std::test]
+    async fn the_retry_budget_caps_verification_attempts() {
+        let (http, members) = flaky_paypal(usize::MAX);
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.paypal_verify_attempts = 5;
+        // Room for the first retry after 500ms, but not the second after a further 1s.
+        config.retry_budget_secs = 1;
+        state.set_config(config);
+
+        let error = process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect_err("invalid IPN");
+
+        // Retry-safe, so PayPal sends it again.
+        assert_eq!(error.status(), StatusCode::InternalServerError);
+        assert_eq!(http.sent_to("paypal.test").len(), 2);
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_none());
+    }
+
+    #[async_std::test]
+    async fn ipns_of_any_content_type_are_processed_by_default() {
+        for content_type in [
+            "application/x-www-form-urlencoded",
+            "application/x-www-form-urlencoded; charset=windows-1252",
+            "text/plain",
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let state = test_state(&http, &MockEmail::default());
+
+            let res: tide::http::Response = server(state)
+                .respond(post(
+                    "/Paypal-IPN",
+                    content_type,
+                    &ipn("TXN1", "ada@example.com", "20.00"),
+                ))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::Ok, "{}", content_type);
+            assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+        }
+    }
+
+    #[async_std::test]
+    async fn unexpected_content_types_are_rejected_if_strict() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.ipn_strict_content_type = true;
+        state.set_config(config);
+        let server = server(state);
+
+        let rejected: tide::http::Response = server
+            .respond(post(
+                "/Paypal-IPN",
+                "application/json",
+                &ipn("TXN1", "ada@example.com", "20.00"),
+            ))
+            .await
+            .expect("response");
+        assert_eq!(rejected.status(), StatusCode::UnsupportedMediaType);
+        assert!(http.sent().is_empty());
+
+        let accepted: tide::http::Response = server
+            .respond(post(
+                "/Paypal-IPN",
+                "application/x-www-form-urlencoded; charset=UTF-8",
+                &ipn("TXN1", "ada@example.com", "20.00"),
+            ))
+            .await
+            .expect("response");
+        assert_eq!(accepted.status(), StatusCode::Ok);
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+    }
+
+    fn membership_types_config() -> ReloadableConfig {
+        let mut config = test_config();
+        config.membership_types = HashMap::from([
+            (
+                "FAM".to_string(),
+                MembershipType {
+                    name: "family".to_string(),
+                    period_days: Some(400),
+                },
+            ),
+            (
+                "STU".to_string(),
+                MembershipType {
+                    name: "student".to_string(),
+                    period_days: None,
+                },
+            ),
+        ]);
+        config
+    }
+
+    #[test]
+    fn item_numbers_resolve_to_membership_types() {
+        let config = membership_types_config();
+        let name = |item_number| {
+            resolve_membership_type(&config, item_number)
+                .map(|membership_type| membership_type.name.as_str())
+        };
+
+        assert_eq!(name(Some("FAM")), Some("family"));
+        assert_eq!(name(Some(" STU ")), Some("student"));
+        assert_eq!(name(Some("fam")), None);
+        assert_eq!(name(Some("GIFT")), None);
+        assert_eq!(name(None), None);
+    }
+
+    #[async_std::test]
+    async fn membership_types_set_membertype_and_the_period() {
+        for (item_number, membertype, expires) in [
+            ("FAM", Some("family"), "2025-07-20"),
+            ("STU", Some("student"), "2025-06-15"),
+            // Unknown items get the default membership.
+            ("GIFT", None, "2025-06-15"),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let state = test_state(&http, &MockEmail::default());
+            state.set_config(membership_types_config());
+            let body = format!(
+                "{}&item_number={}&item_name=Membership",
+                ipn("TXN1", "ada@example.com", "20.00"),
+                item_number
+            );
+
+            process_ipn(detached_logger("test"), &state, body, false)
+                .await
+                .expect("payment");
+
+            let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+            assert_eq!(
+                member["merge_fields"]
+                    .get("MEMBERTYPE")
+                    .and_then(Value::as_str),
+                membertype,
+                "{}",
+                item_number
+            );
+            assert_eq!(
+                member["merge_fields"]["EXPIRES"], expires,
+                "{}",
+                item_number
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn out_of_order_payments_do_not_shorten_expires() {
+        for existing in ["2025-12-01", "2030-01-01"] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            // As left by a later payment, or set by hand beyond MAX_EXPIRY_YEARS.
+            insert_member(
+                &members,
+                LIST_ID,
+                "ada@example.com",
+                "subscribed",
+                json!({"EXPIRES": existing}),
+            );
+            let state = test_state(&http, &MockEmail::default());
+            // An earlier payment, which PayPal only now delivered.
+            let body = format!(
+                "{}&payment_date=10%3A00%3A00%20Jan%2002%2C%202024%20PST",
+                ipn("TXN0", "ada@example.com", "20.00")
+            );
+
+            process_ipn(detached_logger("test"), &state, body, false)
+                .await
+                .expect("payment");
+
+            assert_eq!(expires_of(&members, "ada@example.com"), existing);
+        }
+    }
+
+    const UNHANDLED_IPN: &str =
+        "txn_type=mp_signup&mp_id=B-1&payer_email=ada%40example.com&mc_currency=CAD";
+
+    #[async_std::test]
+    async fn unhandled_txn_types_are_dead_lettered_to_the_log() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.dead_letter = DeadLetterSink::Log;
+        state.set_config(config);
+        let logger = detached_logger("test");
+
+        let res = process_ipn(logger.clone(), &state, UNHANDLED_IPN.to_string(), false)
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let logs = take_logs(&logger).await;
+        assert!(
+            logs.iter().any(|line| line.ends_with(&format!(
+                "DEAD LETTER: IPN txn_type was not acceptable: mp_signup - IPN: \"{}\"",
+                UNHANDLED_IPN
+            ))),
+            "{:?}",
+            logs
+        );
+        assert!(http.sent().is_empty());
+        assert!(members.lock().expect("members").is_empty());
+    }
+
+    #[async_std::test]
+    async fn unhandled_txn_types_are_dead_lettered_to_the_queue() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.dead_letter = DeadLetterSink::Queue;
+        state.set_config(config);
+
+        let mut res: tide::http::Response = azure_server(state)
+            .respond(invocation(
+                "/Paypal-IPN",
+                "POST",
+                json!({"Content-Type": ["application/x-www-form-urlencoded"]}),
+                UNHANDLED_IPN,
+            ))
+            .await
+            .expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 200);
+        assert_eq!(
+            out["Outputs"][DEAD_LETTER_BINDING],
+            json!({
+                "txn_type": "mp_signup",
+                "received": "2024-06-15T12:00:00+00:00",
+                "ipn": UNHANDLED_IPN,
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn each_outcome_embeds_its_configured_status() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.ipn_outcome_statuses = HashMap::from([
+            (IpnOutcome::Refund, StatusCode::Accepted),
+            (IpnOutcome::NoTxnType, StatusCode::BadRequest),
+            (IpnOutcome::UnhandledTxnType, StatusCode::NotImplemented),
+            (IpnOutcome::NotSuccessStatus, StatusCode::Conflict),
+            (IpnOutcome::NoAmount, StatusCode::LengthRequired),
+            (
+                IpnOutcome::UnparseableAmount,
+                StatusCode::UnprocessableEntity,
+            ),
+            (IpnOutcome::AmountTooLow, StatusCode::PaymentRequired),
+        ]);
+        state.set_config(config);
+        let server = azure_server(state);
+        let completed = ipn("TXN1", "ada@example.com", "20.00");
+
+        for (body, status) in [
+            ("payment_status=Canceled_Reversal&parent_txn_id=TXN0&txn_id=TXN1&payer_email=ada%40example.com".to_string(), 202),
+            ("payment_status=Completed&txn_id=TXN1&payer_email=ada%40example.com".to_string(), 400),
+            (UNHANDLED_IPN.to_string(), 501),
+            (completed.replace("payment_status=Completed", "payment_status=Pending"), 409),
+            (completed.replace("&mc_gross=20.00", ""), 411),
+            (completed.replace("mc_gross=20.00", "mc_gross=twenty"), 422),
+            (ipn("TXN1", "ada@example.com", "5.00"), 402),
+            // Anything which isn't an ignored outcome is unaffected.
+            (completed.clone(), 200),
+        ] {
+            let mut res: tide::http::Response = server
+                .respond(invocation(
+                    "/Paypal-IPN",
+                    "POST",
+                    json!({"Content-Type": ["application/x-www-form-urlencoded"]}),
+                    &body,
+                ))
+                .await
+                .expect("response");
+
+            // Azure only takes a 200, the outcome is in the envelope.
+            assert_eq!(res.status(), StatusCode::Ok);
+            let out: Value = res.body_json().await.expect("envelope");
+            assert_eq!(out["Outputs"]["res"]["statusCode"], status, "{}", body);
+        }
+    }
+
+    #[async_std::test]
+    async fn default_merge_fields_are_only_for_new_members() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "bob@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2024-07-01", "SOURCE": "website"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.new_member_merge_fields = json!({"SOURCE": "paypal", "FNAME": "Member"})
+            .as_object()
+            .cloned()
+            .expect("object");
+        state.set_config(config);
+
+        for (txn_id, payer) in [("TXN1", "ada@example.com"), ("TXN2", "bob@example.com")] {
+            process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn(txn_id, payer, "20.00"),
+                false,
+            )
+            .await
+            .expect("payment");
+        }
+
+        let ada = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(ada["merge_fields"]["SOURCE"], "paypal");
+        // The IPN's own merge fields take precedence.
+        assert_eq!(ada["merge_fields"]["FNAME"], "Ada");
+        let bob = get_member(&members, LIST_ID, "bob@example.com").expect("member");
+        assert_eq!(bob["merge_fields"]["SOURCE"], "website");
+    }
+
+    /// PayPal's REST api, with `transactions` for any search, and MailChimp otherwise.
+    fn paypal_rest(transactions: Value) -> (AppState, MockHttp) {
+        let (upstreams, _) = fake_upstreams("VERIFIED");
+        let http = MockHttp::new(move |sent| match sent.url.path() {
+            "/v1/oauth2/token" => json(StatusCode::Ok, &json!({"access_token": "token"})),
+            "/v1/reporting/transactions" => json(
+                StatusCode::Ok,
+                &json!({"transaction_details": transactions, "total_pages": 1}),
+            ),
+            _ => upstreams.respond(sent),
+        });
+        let mut state = test_state(&http, &MockEmail::default());
+        state.ipn_verify_method = IpnVerifyMethod::Rest;
+        state.paypal_rest_credentials = Some(paypal::PaypalRestCredentials {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+        });
+        let mut config = test_config();
+        config.paypal_verify_attempts = 1;
+        state.set_config(config);
+        (state, http)
+    }
+
+    fn rest_transaction(txn_id: &str, email: &str, amount: &str) -> Value {
+        json!({
+            "transaction_info": {
+                "transaction_id": txn_id,
+                "transaction_status": "S",
+                "transaction_initiation_date": "2024-06-15T11:00:00+0000",
+                "transaction_amount": {"currency_code": "CAD", "value": amount},
+            },
+            "payer_info": {"email_address": email},
+        })
+    }
+
+    async fn verify_with(state: &AppState, body: &str) -> IpnVerification {
+        verify_ipn(
+            &mut detached_logger("test"),
+            state,
+            body,
+            &RetryBudget::start(0),
+        )
+        .await
+        .expect("verification")
+    }
+
+    #[async_std::test]
+    async fn rest_verification_finds_the_matching_payment() {
+        let (state, http) = paypal_rest(json!([rest_transaction(
+            "TXN1",
+            "Ada@example.com",
+            "20.00"
+        )]));
+
+        let verification = verify_with(&state, &ipn("TXN1", "ada@example.com", "20.00")).await;
+
+        assert_eq!(verification, IpnVerification::Verified);
+        let sent = http.sent_to("paypal-rest.test");
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].url.path(), "/v1/oauth2/token");
+        assert_eq!(
+            sent[0]
+                .headers
+                .get("Authorization")
+                .map(|auth| auth.as_str()),
+            Some("Basic Y2xpZW50OnNlY3JldA==")
+        );
+        assert_eq!(
+            sent[1]
+                .headers
+                .get("Authorization")
+                .map(|auth| auth.as_str()),
+            Some("Bearer token")
+        );
+        assert!(sent[1]
+            .url
+            .query_pairs()
+            .any(|(name, value)| name == "transaction_id" && value == "TXN1"));
+        assert!(http.sent_to("paypal.test").is_empty());
+    }
+
+    #[async_std::test]
+    async fn rest_verification_is_invalid_unless_the_payment_matches() {
+        for (transactions, body) in [
+            (json!([]), ipn("TXN1", "ada@example.com", "20.00")),
+            (
+                json!([rest_transaction("TXN1", "ada@example.com", "20.00")]),
+                ipn("TXN1", "ada@example.com", "200.00"),
+            ),
+            (
+                json!([rest_transaction("TXN1", "bob@example.com", "20.00")]),
+                ipn("TXN1", "ada@example.com", "20.00"),
+            ),
+            (
+                json!([rest_transaction("TXN1", "ada@example.com", "20.00")]),
+                ipn("TXN1", "ada@example.com", "20.00")
+                    .replace("mc_currency=CAD", "mc_currency=USD"),
+            ),
+        ] {
+            let (state, _) = paypal_rest(transactions);
+
+            assert_eq!(
+                verify_with(&state, &body).await,
+                IpnVerification::Invalid,
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn rest_verification_falls_back_to_postback_without_a_txn_id() {
+        let (state, http) = paypal_rest(json!([]));
+
+        let verification = verify_with(
+            &state,
+            "txn_type=subscr_signup&subscr_id=I-1&payer_email=ada%40example.com",
+        )
+        .await;
+
+        assert_eq!(verification, IpnVerification::Verified);
+        assert!(http.sent_to("paypal-rest.test").is_empty());
+        assert_eq!(http.sent_to("paypal.test").len(), 1);
+    }
+
+    #[async_std::test]
+    async fn malformed_exchange_rates_are_logged_and_not_converted() {
+        for (exchange_rate, logged) in [
+            ("", "(none)"),
+            ("1e3", "1e3"),
+            ("-1.25", "-1.25"),
+            ("1,25", "1,25"),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let mut state = test_state(&http, &MockEmail::default());
+            state.base_currency = Some("CAD".to_string());
+            let logger = detached_logger("test");
+            let body = format!(
+                "{}&exchange_rate={}",
+                ipn("TXN1", "ada@example.com", "12.00")
+                    .replace("mc_currency=CAD", "mc_currency=USD"),
+                exchange_rate.replace(',', "%2C")
+            );
+
+            process_ipn(logger.clone(), &state, body, false)
+                .await
+                .expect("payment");
+
+            let logs = take_logs(&logger).await;
+            assert!(
+                logs.iter().any(|line| line.contains(&format!(
+                    "No usable exchange rate (\"{}\") for a foreign payment",
+                    logged
+                ))),
+                "{:?}",
+                logs
+            );
+            assert!(!logs
+                .iter()
+                .any(|line| line.contains("Converted payment amount")));
+            // Held to the default minimum in USD instead, which 12 meets.
+            assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+        }
+    }
+
+    /// Real IPN samples, with the personal details replaced.
+    const IPN_CORPUS: &[&str] = &[
+        "mc_gross=20.00&protection_eligibility=Eligible&address_status=confirmed&payer_id=LPLWNMTBWMFAY&payment_date=20%3A12%3A59+Jan+13%2C+2009+PST&payment_status=Completed&charset=windows-1252&first_name=Ada&mc_fee=0.88&notify_version=2.6&payer_status=verified&business=treasurer%40example.com&quantity=1&verify_sign=AtkOfCXbDm2hu0ZELryHFjY-Vb7PAUvS6nMXgysbElEn9v-1XcmSoGtf&payer_email=ada%40example.com&txn_id=61E67681CH3238416&payment_type=instant&last_name=Lovelace&receiver_email=treasurer%40example.com&payment_fee=0.88&receiver_id=S8XGHLYDW9T3S&txn_type=web_accept&item_name=Membership&mc_currency=CAD&item_number=&residence_country=CA&handling_amount=0.00&transaction_subject=&payment_gross=&shipping=0.00",
+        "txn_type=subscr_payment&subscr_id=I-BRKWTS1VH7L4&payment_status=Completed&payment_date=03%3A14%3A07+Feb+02%2C+2024+PST&txn_id=4MV71226EU843722L&payer_email=grace%40example.com&mc_gross=15.00&mc_currency=USD&exchange_rate=1.32145&first_name=Grace&last_name=Hopper&charset=UTF-8",
+        "txn_type=web_accept&payment_status=Pending&pending_reason=echeck&txn_id=9XJ47128WM558245P&payer_email=alan%40example.com&mc_gross=20.00&mc_currency=CAD&first_name=Alan&last_name=Turing&test_ipn=1",
+        "txn_type=subscr_cancel&subscr_id=I-BRKWTS1VH7L4&payer_email=grace%40example.com&first_name=Grace&last_name=Hopper&subscr_date=09%3A00%3A00+Mar+01%2C+2024+PST",
+    ];
+
+    /// Values that PayPal, or something pretending to be it, might send in any field.
+    const MALFORMED_VALUES: &[&str] = &[
+        "",
+        "%",
+        "%ZZ",
+        "%00",
+        "%C3%28",
+        "caf\u{e9}",
+        "+++",
+        "=",
+        "a=b=c",
+        "-0.00",
+        "1e308",
+        "NaN",
+        "99999999999999999999999999",
+        "..%2F..%2F",
+    ];
+
+    /// Every prefix of each sample, and each sample with each field dropped or replaced by a
+    /// malformed value: the handler must come back with a response or an error, never panic.
+    #[async_std::test]
+    async fn malformed_ipns_never_panic() {
+        let mut bodies = Vec::new();
+        for sample in IPN_CORPUS {
+            bodies.push((*sample).to_string());
+            bodies.extend((0..sample.len()).map(|len| sample[..len].to_string()));
+
+            let fields: Vec<&str> = sample.split('&').collect();
+            for (i, field) in fields.iter().enumerate() {
+                let name = field.split('=').next().unwrap_or_default();
+                let mut dropped = fields.clone();
+                dropped.remove(i);
+                bodies.push(dropped.join("&"));
+                for value in MALFORMED_VALUES {
+                    let mut replaced = fields.clone();
+                    let field = format!("{}={}", name, value);
+                    replaced[i] = &field;
+                    bodies.push(replaced.join("&"));
+                }
+            }
+        }
+
+        let (http, _members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        for body in bodies {
+            match process_ipn(detached_logger("test"), &state, body.clone(), false).await {
+                Ok(res) => assert!(res.status().is_success(), "{}: {}", res.status(), body),
+                // Some errors are 200s, so that PayPal doesn't retry them.
+                Err(error) => assert!(!error.to_string().is_empty(), "{}", body),
+            }
+        }
+    }
+
+    #[test]
+    fn names_are_normalized_as_configured() {
+        let mut config = test_config();
+        let name = || Some("  joHN   dOE ".to_string());
+
+        assert_eq!(
+            normalize_name(name(), &config).as_deref(),
+            Some("  joHN   dOE ")
+        );
+        config.name_normalization = NameNormalization::Whitespace;
+        assert_eq!(normalize_name(name(), &config).as_deref(), Some("joHN dOE"));
+        config.name_normalization = NameNormalization::TitleCase;
+        assert_eq!(normalize_name(name(), &config).as_deref(), Some("John Doe"));
+        for (name, normalized) in [
+            ("mary-jane o'neil", "Mary-Jane O'Neil"),
+            ("éLODIE\u{a0}ÅSTRÖM", "Élodie Åström"),
+            ("ß", "SS"),
+        ] {
+            assert_eq!(
+                normalize_name(Some(name.to_string()), &config).as_deref(),
+                Some(normalized)
+            );
+        }
+        assert_eq!(normalize_name(Some(" \t ".to_string()), &config), None);
+        assert_eq!(normalize_name(None, &config), None);
+    }
+
+    #[async_std::test]
+    async fn normalized_names_are_written_to_mailchimp() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "bob@example.com",
+            "subscribed",
+            json!({"FNAME": "Bob", "LNAME": "Smith"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.name_normalization = NameNormalization::TitleCase;
+        state.set_config(config);
+
+        for (txn_id, payer, first_name, last_name) in [
+            ("TXN1", "ada@example.com", "++aDA++", "LOVELACE"),
+            ("TXN2", "bob@example.com", "+", "+"),
+        ] {
+            let body = ipn(txn_id, payer, "20.00")
+                .replace("first_name=Ada", &format!("first_name={}", first_name))
+                .replace("last_name=Lovelace", &format!("last_name={}", last_name));
+            process_ipn(detached_logger("test"), &state, body, false)
+                .await
+                .expect("payment");
+        }
+
+        let ada = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(ada["merge_fields"]["FNAME"], "Ada");
+        assert_eq!(ada["merge_fields"]["LNAME"], "Lovelace");
+        // Names left empty don't blank out the existing ones.
+        let bob = get_member(&members, LIST_ID, "bob@example.com").expect("member");
+        assert_eq!(bob["merge_fields"]["FNAME"], "Bob");
+        assert_eq!(bob["merge_fields"]["LNAME"], "Smith");
+    }
+
+    #[async_std::test]
+    async fn json_wrapped_ipns_are_unwrapped_if_configured() {
+        let form = ipn("TXN1", "ada@example.com", "20.00");
+        let wrapped = json!({"body": form, "headers": {}}).to_string();
+        let (http, members) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.ipn_strict_content_type = true;
+        config.ipn_json_field = Some("body".to_string());
+        state.set_config(config);
+        let server = server(state);
+
+        let res: tide::http::Response = server
+            .respond(post("/Paypal-IPN", "application/json", &wrapped))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+        // PayPal is asked to verify the form body, not the wrapper.
+        assert_eq!(
+            http.sent_to("paypal.test")[0].body,
+            format!("cmd=_notify-validate&{}", form)
+        );
+
+        // Form bodies still pass through unchanged.
+        let res: tide::http::Response = server
+            .respond(post(
+                "/Paypal-IPN",
+                "application/x-www-form-urlencoded",
+                &ipn("TXN2", "bob@example.com", "20.00"),
+            ))
+            .await
+            .expect("response");
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(get_member(&members, LIST_ID, "bob@example.com").is_some());
+    }
+
+    #[async_std::test]
+    async fn json_is_only_unwrapped_from_the_configured_string_field() {
+        let mut logger = detached_logger("test");
+        let form = ipn("TXN1", "ada@example.com", "20.00");
+
+        for body in [
+            json!({"payload": form}).to_string(),
+            json!({"body": {"form": form}}).to_string(),
+            json!([form]).to_string(),
+            form.clone(),
+        ] {
+            assert_eq!(
+                unwrap_json_ipn(&mut logger, body.clone(), "body").await,
+                body
+            );
+        }
+        assert!(take_logs(&logger).await.is_empty());
+        let wrapped = json!({"body": form}).to_string();
+        assert_eq!(unwrap_json_ipn(&mut logger, wrapped, "body").await, form);
+        assert_eq!(
+            take_logs(&logger).await,
+            ["test IPN: unwrapped from the JSON \"body\" field"]
+        );
+    }
+
+    #[async_std::test]
+    async fn paypal_verification_is_only_skipped_in_the_sandbox() {
+        for paypal_sandbox in [false, true] {
+            let (http, members) = fake_upstreams("INVALID");
+            let mut state = test_state(&http, &MockEmail::default());
+            state.paypal_sandbox = paypal_sandbox;
+            state.skip_paypal_verify = true;
+            let logger = detached_logger("test");
+
+            let res = process_ipn(
+                logger.clone(),
+                &state,
+                ipn("TXN1", "ada@example.com", "20.00"),
+                false,
+            )
+            .await;
+
+            assert_eq!(res.is_ok(), paypal_sandbox);
+            let skipped = take_logs(&logger)
+                .await
+                .iter()
+                .any(|line| line.contains("SKIP_PAYPAL_VERIFY: treating the IPN as VERIFIED"));
+            let member = get_member(&members, LIST_ID, "ada@example.com");
+            if paypal_sandbox {
+                assert!(skipped);
+                assert!(http.sent_to("paypal.test").is_empty());
+                assert!(member.is_some());
+            } else {
+                assert!(!skipped);
+                assert!(!http.sent_to("paypal.test").is_empty());
+                assert!(member.is_none());
+            }
+        }
+    }
+
+    /// Each IPN's audit event, as queued through the Azure output binding.
+    async fn audit_events(state: AppState, bodies: &[String]) -> Vec<Value> {
+        let server = azure_server(state);
+        let mut events = Vec::new();
+        for body in bodies {
+            let mut res: tide::http::Response = server
+                .respond(invocation(
+                    "/Paypal-IPN",
+                    "POST",
+                    json!({"Content-Type": ["application/x-www-form-urlencoded"]}),
+                    body,
+                ))
+                .await
+                .expect("response");
+            let out: Value = res.body_json().await.expect("envelope");
+            assert_eq!(out["Outputs"]["res"]["statusCode"], 200);
+            events.push(out["Outputs"][AUDIT_BINDING].clone());
+        }
+        events
+    }
+
+    #[async_std::test]
+    async fn membership_changes_are_audited() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "bob@example.com",
+            "subscribed",
+            json!({"FNAME": "Bob", "EXPIRES": "2024-07-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.audit_queue = true;
+        state.set_config(config);
+
+        let events = audit_events(
+            state,
+            &[
+                ipn("TXN1", "ada@example.com", "20.00"),
+                ipn("TXN2", "bob@example.com", "20.00"),
+            ],
+        )
+        .await;
+
+        let expires = |email| {
+            get_member(&members, LIST_ID, email).expect("member")["merge_fields"]["EXPIRES"].clone()
+        };
+        assert_eq!(
+            events,
+            [
+                json!({
+                    "actor": "paypal_ipn",
+                    "email": "ada@example.com",
+                    "txn_id": "TXN1",
+                    "old_status": null,
+                    "new_status": "pending",
+                    "old_expires": null,
+                    "new_expires": expires("ada@example.com"),
+                    "timestamp": "2024-06-15T12:00:00+00:00",
+                }),
+                json!({
+                    "actor": "paypal_ipn",
+                    "email": "bob@example.com",
+                    "txn_id": "TXN2",
+                    "old_status": "subscribed",
+                    "new_status": "subscribed",
+                    "old_expires": "2024-07-01",
+                    "new_expires": expires("bob@example.com"),
+                    "timestamp": "2024-06-15T12:00:00+00:00",
+                }),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn changes_are_not_audited_by_default() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let state = test_state(&http, &MockEmail::default());
+
+        let events = audit_events(state, &[ipn("TXN1", "ada@example.com", "20.00")]).await;
+
+        assert_eq!(events, [Value::Null]);
+    }
+
+    /// The body of each MailChimp member PUT.
+    fn member_puts(http: &MockHttp) -> Vec<Value> {
+        http.sent_to("mailchimp.test")
+            .iter()
+            .filter(|sent| sent.method == Method::Put)
+            .map(|sent| serde_json::from_str(&sent.body).expect("PUT JSON"))
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn status_is_only_set_for_existing_members() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "bob@example.com",
+            "subscribed",
+            json!({"FNAME": "Bob", "EXPIRES": "2024-07-01"}),
+        );
+        let state = test_state(&http, &MockEmail::default());
+
+        for (txn_id, payer) in [("TXN1", "ada@example.com"), ("TXN2", "bob@example.com")] {
+            process_ipn(
+                detached_logger("test"),
+                &state,
+                ipn(txn_id, payer, "20.00"),
+                false,
+            )
+            .await
+            .expect("payment");
+        }
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            "txn_type=subscr_signup&subscr_id=I-1&payer_email=cy%40example.com".to_string(),
+            false,
+        )
+        .await
+        .expect("signup");
+
+        let puts = member_puts(&http);
+        assert_eq!(puts.len(), 3);
+        assert_eq!(puts[0]["status_if_new"], "pending");
+        assert!(puts[0].get("status").is_none());
+        assert_eq!(puts[1]["status_if_new"], "pending");
+        assert_eq!(puts[1]["status"], "subscribed");
+        assert_eq!(puts[2]["status_if_new"], "pending");
+        assert!(puts[2].get("status").is_none());
+    }
+
+    #[async_std::test]
+    async fn members_added_since_the_lookup_keep_their_status() {
+        let (upstreams, members) = fake_upstreams("VERIFIED");
+        let added = members.clone();
+        // Another IPN adds the member just after our lookup finds nothing.
+        let http = MockHttp::new(move |sent| {
+            let res = upstreams.respond(sent);
+            if sent.method == Method::Get
+                && sent.url.host_str() == Some("mailchimp.test")
+                && get_member(&added, LIST_ID, "ada@example.com").is_none()
+            {
+                insert_member(&added, LIST_ID, "ada@example.com", "subscribed", json!({}));
+            }
+            res
+        });
+        let state = test_state(&http, &MockEmail::default());
+
+        process_ipn(
+            detached_logger("test"),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("payment");
+
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("member");
+        assert_eq!(member["status"], "subscribed");
+        assert_eq!(member["merge_fields"]["FNAME"], "Ada");
+    }
+
+    #[async_std::test]
+    async fn welcome_emails_are_in_the_payers_language() {
+        for (residence_country, template) in [
+            (None, "d-welcome"),
+            (Some("CA"), "d-welcome"),
+            (Some("FR"), "d-fr-welcome"),
+        ] {
+            let (http, _) = fake_upstreams("VERIFIED");
+            let email = MockEmail::default();
+            let state = test_state(&http, &email);
+            let mut config = test_config();
+            config.template_welcome = Some("d-welcome".to_string());
+            config.template_locales = serde_json::from_value(json!({
+                "fr": {"welcome": "d-fr-welcome", "countries": ["FR"]},
+            }))
+            .expect("TEMPLATE_LOCALES");
+            state.set_config(config);
+            let mut body = ipn("TXN1", "ada@example.com", "20.00");
+            if let Some(residence_country) = residence_country {
+                body = format!("{}&residence_country={}", body, residence_country);
+            }
+
+            process_ipn(detached_logger("test"), &state, body, false)
+                .await
+                .expect("payment");
+
+            assert_eq!(
+                email.sent()[0]["template_id"],
+                template,
+                "{:?}",
+                residence_country
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn welcome_emails_include_the_membership_type() {
+        for (item_number, membership_type) in [("FAM", "family"), ("GIFT", "")] {
+            let (http, _) = fake_upstreams("VERIFIED");
+            let email = MockEmail::default();
+            let state = test_state(&http, &email);
+            let mut config = membership_types_config();
+            config.template_welcome = Some("d-welcome".to_string());
+            state.set_config(config);
+            let body = format!(
+                "{}&item_number={}",
+                ipn("TXN1", "ada@example.com", "20.00"),
+                item_number
+            );
+
+            process_ipn(detached_logger("test"), &state, body, false)
+                .await
+                .expect("payment");
+
+            assert_eq!(
+                email.sent()[0]["personalizations"][0]["dynamic_template_data"]["membership_type"],
+                membership_type,
+                "{}",
+                item_number
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn rest_verification_finds_refunds_pending_payments_and_locale_amounts() {
+        let with_status = |status: &str, amount: &str| {
+            let mut transaction = rest_transaction("TXN1", "ada@example.com", amount);
+            transaction["transaction_info"]["transaction_status"] = json!(status);
+            json!([transaction])
+        };
+        for (transactions, body) in [
+            (
+                with_status("S", "-20.00"),
+                ipn("TXN1", "ada@example.com", "-20.00")
+                    .replace("payment_status=Completed", "payment_status=Refunded")
+                    + "&parent_txn_id=TXN0",
+            ),
+            (
+                with_status("P", "20.00"),
+                ipn("TXN1", "ada@example.com", "20.00")
+                    .replace("payment_status=Completed", "payment_status=Pending"),
+            ),
+            (
+                with_status("S", "20.00"),
+                ipn("TXN1", "ada@example.com", "20,00"),
+            ),
+        ] {
+            let (state, _) = paypal_rest(transactions);
+
+            assert_eq!(
+                verify_with(&state, &body).await,
+                IpnVerification::Verified,
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn welcome_emails_are_sent_on_the_first_payment_of_added_members() {
+        let signup = "txn_type=subscr_signup&subscr_id=I-1&payer_email=ada%40example.com&first_name=Ada&mc_currency=CAD".to_string();
+        let pending = ipn("TXN1", "ada@example.com", "20.00")
+            .replace("payment_status=Completed", "payment_status=Pending");
+        for (added_by, completed) in [
+            (
+                signup,
+                ipn("TXN1", "ada@example.com", "20.00").replace("web_accept", "subscr_payment"),
+            ),
+            (pending, ipn("TXN1", "ada@example.com", "20.00")),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            let email = MockEmail::default();
+            let state = test_state(&http, &email);
+            let mut config = test_config();
+            config.template_welcome = Some("d-welcome".to_string());
+            config.preregister_pending = true;
+            state.set_config(config);
+
+            process_ipn(detached_logger("test"), &state, added_by.clone(), false)
+                .await
+                .expect("added");
+            assert!(get_member(&members, LIST_ID, "ada@example.com").is_some());
+            assert!(email.sent().is_empty(), "{}", added_by);
+            process_ipn(detached_logger("test"), &state, completed, false)
+                .await
+                .expect("payment");
+
+            let sent = email.sent();
+            assert_eq!(sent.len(), 1, "{}", added_by);
+            assert_eq!(sent[0]["template_id"], "d-welcome");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::channel::Receiver;
+use async_std::task;
+
+// The info! logging macro comes from crate::azure_function::logger
+use crate::azure_function::{detached_logger, take_logs, AzureFnLoggerExt, AzureFnOutputs};
+use crate::ipn_handler::process_ipn;
+use crate::AppState;
+
+/// Attempts at processing a queued IPN, as PayPal no longer retries it for us once acknowledged.
+const ATTEMPTS: u32 = 3;
+
+/// The delay before the first retry of a queued IPN, doubled after each attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Process IPNs which were verified & acknowledged to PayPal before being processed, see `IPN_QUEUE_SIZE`.
+///
+/// Runs until the queue is closed & drained, e.g. on shutdown.
+/// Server errors, such as MailChimp being unavailable, are retried a few times.
+/// Logs go to the application log, as there is no Azure invocation left to attach them to.
+pub async fn worker(state: Arc<AppState>, queue: Receiver<String>) {
+    let mut queued = 0_u64;
+    while let Ok(ipn) = queue.recv().await {
+        queued += 1;
+        let mut logger = detached_logger(&format!("(queued IPN {})", queued));
+
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=ATTEMPTS {
+            let status = match process_ipn(logger.clone(), &state, ipn.clone(), true).await {
+                Ok(res) => {
+                    // Output bindings are only for Azure invocations.
+                    if let Some(AzureFnOutputs(outputs)) = res.ext::<AzureFnOutputs>() {
+                        for (binding, value) in outputs {
+                            info!(
+                                logger,
+                                "Queued IPN: no \"{}\" output binding outside of an invocation: {}",
+                                binding,
+                                value
+                            );
+                        }
+                    }
+                    res.status()
+                }
+                Err(error) => {
+                    info!(logger, "Queued IPN: error: {}", error);
+                    error.status()
+                }
+            };
+            if !status.is_server_error() {
+                info!(logger, "Queued IPN: finished - status: {}", status);
+                break;
+            }
+            if attempt == ATTEMPTS {
+                info!(
+                    logger,
+                    "Queued IPN: giving up after {} attempts - status: {} - IPN: \"{}\"",
+                    attempt,
+                    status,
+                    state.log_body(&ipn)
+                );
+                break;
+            }
+            info!(
+                logger,
+                "Queued IPN: retrying in {:?} (attempt {} of {}) - status: {}",
+                delay,
+                attempt,
+                ATTEMPTS,
+                status
+            );
+            task::sleep(delay).await;
+            delay *= 2;
+        }
+
+        for line in take_logs(&logger).await {
+            log::info!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::channel;
+
+    use super::*;
+    use crate::test_support::{fake_upstreams, get_member, ipn, test_state, MockEmail, LIST_ID};
+
+    #[async_std::test]
+    async fn worker_adds_queued_ipns_to_mailchimp_without_verifying_again() {
+        let (http, members) = fake_upstreams("INVALID");
+        let state = Arc::new(test_state(&http, &MockEmail::default()));
+        let (sender, queue) = channel::bounded(1);
+        sender
+            .try_send(ipn("TXN1", "ada@example.com", "20.00"))
+            .expect("queue space");
+        drop(sender);
+
+        worker(state, queue).await;
+
+        assert!(http.sent_to("paypal.test").is_empty());
+        let member = get_member(&members, LIST_ID, "ada@example.com").expect("new member");
+        assert_eq!(member["status"], "pending");
+        assert_eq!(member["merge_fields"]["EXPIRES"], "2025-06-15");
+    }
+}
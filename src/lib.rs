@@ -39,7 +39,6 @@
     clippy::print_stdout,
     clippy::same_name_method,
     clippy::single_char_lifetime_names,
-    clippy::string_to_string,
     clippy::trait_duplication_in_bounds,
     clippy::type_repetition_in_bounds,
     clippy::unimplemented,
@@ -48,41 +47,286 @@
     clippy::used_underscore_binding
 )]
 
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration as StdDuration, Instant};
 
+use async_std::channel::Sender;
+use chrono::{DateTime, Duration, Months, NaiveDate, Utc};
 use log::warn;
-use serde::{Deserialize, Serialize};
 use surf::Client;
+
+use callback::SubscriptionCallback;
+use clock::Clock;
+use config::ReloadableConfig;
+use email::EmailSender;
+use paypal::{IpnVerifyMethod, PaypalRestCredentials};
+use sendgrid::EmailAddress;
+use tide::security::{CorsMiddleware, Origin};
 use tide::{Request, Response, Server, StatusCode};
 
 #[macro_use]
 pub mod azure_function;
+pub mod callback;
+pub mod clock;
+pub mod config;
+pub mod email;
+pub mod ipn_queue;
+pub mod mailchimp;
+pub mod mandrill;
+pub mod paypal;
+pub mod proxy;
+pub mod retry;
+pub mod sendgrid;
+pub mod timing;
+pub mod tls;
 
 // Our functions
+mod admin;
 mod ipn_handler;
 mod membership_check;
+mod reconciliation;
+mod stats;
+#[cfg(test)]
+mod test_support;
 
+use admin::{admin_reload, admin_resync};
 use ipn_handler::ipn_handler;
-use membership_check::membership_check;
+use membership_check::{membership_check, IdempotentCheck};
+use reconciliation::{admin_reconcile, reconciliation};
+use stats::{admin_stats, MembershipStats};
 
 #[derive(Debug)]
 pub struct AppState {
+    pub admin_secret: Option<String>, // admin endpoints are disabled without it
+    pub base_currency: Option<String>, // convert foreign payments to this with their exchange_rate, if set
+    pub callback: Client,              // no base url, for the subscription callback
+    pub clock: Box<dyn Clock>,         // Utc::now(), or fixed in tests
+    pub config: RwLock<Arc<ReloadableConfig>>, // reloadable without a restart
+    pub cors_allowed_origins: Vec<String>, // membership check CORS, disabled when empty
+    pub idempotency_keys: Mutex<HashMap<(String, String), (Instant, IdempotentCheck)>>, // membership check (Idempotency-Key, lowercased email) -> (reserved at, response)
+    pub ipn_queue: Option<Sender<String>>, // raw IPNs to process after acknowledging PayPal, if enabled
+    pub ipn_verify_method: IpnVerifyMethod, // legacy postback, or the REST api
+    pub log_body_max_len: usize, // characters of a request or response body which are logged
+    pub log_raw_ipn: bool,       // log every IPN body, for debugging
+    pub log_upstream_timing: bool, // log the time taken by each upstream api request
     pub mailchimp: Client,
-    pub mc_list_id: String,
+    pub mc_custom_merge_field: String, // merge field for the PayPal `custom` value
+    pub mc_list_id: String,            // the default list
+    pub mc_currency_list_ids: HashMap<String, String>, // currency -> list id, for multi-list setups
+    pub membership_check_html: bool,   // inline html page rather than a redirect
+    pub membership_token_secret: Option<String>, // signs the result into the redirect, if set
     pub paypal: Client,
+    pub paypal_rest: Client, // PayPal REST api, distinct from IPN verification
+    pub paypal_rest_credentials: Option<PaypalRestCredentials>,
     pub paypal_sandbox: bool,
-    pub template_membership_check: String, // twilio email template id
-    pub template_membership_notfound: String, // twilio email template id
-    pub twilio: Client,                    // Email sending
+    pub paypal_verify_timeout: StdDuration, // per IPN verification attempt
+    pub ping_body: bool,                    // a JSON body for GET /, for uptime monitors
+    pub sendgrid_from_name: Option<String>, // display name for membership check emails
+    pub sendgrid_reply_to: Option<EmailAddress>, // where replies to membership check emails go
+    pub skip_paypal_verify: bool,           // treat IPNs as verified, only ever with paypal_sandbox
+    pub stats_cache: Mutex<Option<(Instant, MembershipStats)>>, // Admin-Stats result, with when it was computed
+    pub stats_cache_ttl: StdDuration,
+    pub subscription_callback: Option<SubscriptionCallback>,
+    pub test_ipn_dry_run: bool, // don't update MailChimp for `test_ipn=1` IPNs
+    pub zero_decimal_currencies: Vec<String>, // currencies without a fractional part, e.g. JPY
+    pub email: Box<dyn EmailSender>, // Email sending, via SendGrid or Mandrill
+}
+
+impl AppState {
+    /// The current reloadable configuration.
+    pub fn config(&self) -> Arc<ReloadableConfig> {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replace the reloadable configuration, e.g. after re-reading the environment.
+    pub fn set_config(&self, config: ReloadableConfig) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+    }
+
+    /// The MailChimp list id for a payment currency, falling back to the default list.
+    pub fn mc_list_id_for(&self, currency: Option<&str>) -> &str {
+        currency
+            .and_then(|currency| self.mc_currency_list_ids.get(&currency.to_uppercase()))
+            .unwrap_or(&self.mc_list_id)
+    }
+
+    /// Every MailChimp list id, the default list first, then the per-currency lists by currency.
+    pub fn mc_list_ids(&self) -> Vec<&str> {
+        let mut currency_list_ids: Vec<_> = self.mc_currency_list_ids.iter().collect();
+        currency_list_ids.sort();
+
+        let mut list_ids = vec![self.mc_list_id.as_str()];
+        for (_, list_id) in currency_list_ids {
+            if !list_ids.contains(&list_id.as_str()) {
+                list_ids.push(list_id);
+            }
+        }
+        list_ids
+    }
+
+    /// A foreign payment amount converted to `BASE_CURRENCY` with PayPal's exchange rate, and the base currency.
+    ///
+    /// `None` for payments in the base currency, or without an exchange rate, which are taken in their own currency.
+    pub fn to_base_currency(
+        &self,
+        amount: f64,
+        currency: Option<&str>,
+        exchange_rate: Option<f64>,
+    ) -> Option<(f64, &str)> {
+        let base_currency = self.base_currency.as_deref()?;
+        if currency.is_none_or(|currency| currency.eq_ignore_ascii_case(base_currency)) {
+            return None;
+        }
+        Some((amount * exchange_rate?, base_currency))
+    }
+
+    /// Whether a payment is enough for a membership, in `BASE_CURRENCY` if it can be converted, see [`Self::to_base_currency`].
+    pub fn meets_min_amount(
+        &self,
+        amount: f64,
+        currency: Option<&str>,
+        exchange_rate: Option<f64>,
+    ) -> bool {
+        let config = self.config();
+        match self.to_base_currency(amount, currency, exchange_rate) {
+            Some((amount, base_currency)) => amount >= config.min_amount_for(Some(base_currency)),
+            None => amount >= config.min_amount_for(currency),
+        }
+    }
+
+    /// A request or response body for logging, truncated to `log_body_max_len`.
+    pub fn log_body<'body>(&self, body: &'body str) -> Cow<'body, str> {
+        truncate_body(body, self.log_body_max_len)
+    }
+}
+
+/// Truncate a body for logging to `max_len` characters, noting its original length.
+///
+/// Large bodies would otherwise flood the Azure function logs, and leak more data than needed.
+pub fn truncate_body(body: &str, max_len: usize) -> Cow<'_, str> {
+    match body.char_indices().nth(max_len) {
+        Some((end, _)) => format!("{}… ({} bytes in total)", &body[..end], body.len()).into(),
+        None => body.into(),
+    }
+}
+
+/// How a payment sets a membership's `EXPIRES`.
+#[derive(Clone, Copy, Debug)]
+pub struct ExpiryPolicy {
+    pub period_days: i64,         // how long a payment is good for
+    pub stack: bool, // extend an unexpired membership from its expiry, not the payment date
+    pub renewal_window_days: i64, // a repeat payment within this doesn't extend EXPIRES
+    pub max_expiry_years: u32, // cap on how far out a payment extends EXPIRES
+}
+
+/// The membership time a payment granted, so that a refund or reversal of it can take back exactly that.
+///
+/// Kept on the member in MailChimp, as `txn_id:days:amount` in the space-separated `GRANTS` merge field, newest first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpiryGrant {
+    pub txn_id: String,
+    pub days: i64,   // how far the payment moved EXPIRES out
+    pub amount: f64, // what was paid, to tell a full refund from a partial one
+}
+
+impl ExpiryGrant {
+    /// Parse a `GRANTS` merge field, skipping anything malformed.
+    pub fn parse_all(grants: &str) -> Vec<Self> {
+        grants
+            .split_whitespace()
+            .filter_map(|grant| {
+                let mut parts = grant.split(':');
+                let grant = Self {
+                    txn_id: parts.next()?.to_string(),
+                    days: parts.next()?.parse().ok()?,
+                    amount: parts.next()?.parse().ok()?,
+                };
+                parts.next().is_none().then_some(grant)
+            })
+            .collect()
+    }
+
+    /// Format grants for the `GRANTS` merge field, dropping the oldest as needed to fit in `max_len` characters.
+    pub fn format_all(grants: &[Self], max_len: usize) -> String {
+        let mut formatted = String::new();
+        for grant in grants {
+            let grant = format!("{}:{}:{}", grant.txn_id, grant.days, grant.amount);
+            let separator = usize::from(!formatted.is_empty());
+            if formatted.len() + separator + grant.len() > max_len {
+                break;
+            }
+            if separator == 1 {
+                formatted.push(' ');
+            }
+            formatted.push_str(&grant);
+        }
+        formatted
+    }
+}
+
+/// The membership expiry after a payment.
+///
+/// A payment is good for `period_days`, unless the existing expiry is later, in which case it is kept.
+/// With `stack`, an unexpired membership is instead extended by `period_days` from its existing expiry.
+/// The existing expiry is also kept for a repeat payment within the renewal window of the `last_payment`, e.g. a double click on pay.
+/// Lists without a last payment date have it taken as a period before the existing expiry.
+/// Either way the payment extends the expiry to at most `max_expiry_years` from the payment date, e.g. against stacking up many payments.
+/// An existing expiry already beyond that is kept, as a payment never takes membership time away.
+pub fn compute_expiry(
+    payment_date: DateTime<Utc>,
+    existing: Option<NaiveDate>,
+    last_payment: Option<NaiveDate>,
+    policy: &ExpiryPolicy,
+) -> NaiveDate {
+    let today = payment_date.date_naive();
+    let period = Duration::days(policy.period_days);
+    let mut expires = today + period;
+
+    if let Some(existing) = existing {
+        // Without stacking, EXPIRES is set a period out from the last payment.
+        let last_payment = last_payment.unwrap_or(existing - period);
+        let within_window = policy.renewal_window_days > 0
+            && last_payment <= today
+            && last_payment + Duration::days(policy.renewal_window_days) >= today;
+        if within_window {
+            expires = existing;
+        } else if policy.stack && existing > today {
+            expires = existing + period;
+        }
+    }
+
+    // Only the extension is capped, never the existing expiry.
+    let max_expires = today
+        .checked_add_months(Months::new(12 * policy.max_expiry_years))
+        .unwrap_or(NaiveDate::MAX);
+    expires = expires.min(max_expires);
+
+    existing.map_or(expires, |existing| expires.max(existing))
 }
 
 pub type AppRequest = Request<Arc<AppState>>;
 
-async fn get_ping(_req: AppRequest) -> tide::Result<Response> {
-    Ok(StatusCode::Ok.into())
+/// Empty by default, which is all Azure needs. Uptime monitors may want a body to check, if enabled.
+async fn get_ping(req: AppRequest) -> tide::Result<Response> {
+    if !req.state().ping_body {
+        return Ok(StatusCode::Ok.into());
+    }
+    Ok(Response::builder(StatusCode::Ok)
+        .body(serde_json::json!({
+            "status": "ok",
+            "version": env!("CARGO_PKG_VERSION"),
+        }))
+        .into())
 }
 
 pub fn setup_routes(server: &mut Server<Arc<AppState>>) {
+    let state = server.state().clone();
+
     // Required so that Azure known when our custom handler is listening, _I think_.
     server.at("/").get(get_ping);
 
@@ -90,25 +334,368 @@ pub fn setup_routes(server: &mut Server<Arc<AppState>>) {
     server.at("/Paypal-IPN").post(ipn_handler);
 
     // The Membership Check handler, set the path where it's `function.json` sits in the project.
-    server.at("/Membership-Check").post(membership_check);
-}
+    let mut membership_check_route = server.at("/Membership-Check");
+    // Cross-origin requests, e.g. via `fetch` from the website. Preflights are answered by the middleware.
+    if !state.cors_allowed_origins.is_empty() {
+        membership_check_route.with(
+            CorsMiddleware::new().allow_origin(Origin::from(state.cors_allowed_origins.clone())),
+        );
+    }
+    membership_check_route.post(membership_check);
 
-#[derive(Debug, Serialize)]
-struct MailchimpQuery {
-    fields: &'static [&'static str],
-}
+    // Admin: re-process an IPN, set the path where it's `function.json` sits in the project.
+    server.at("/Admin-Resync").post(admin_resync);
+
+    // Admin: reload configuration, set the path where it's `function.json` sits in the project.
+    server.at("/Admin-Reload").post(admin_reload);
+
+    // Admin: membership statistics, set the path where it's `function.json` sits in the project.
+    server.at("/Admin-Stats").post(admin_stats);
+
+    // Admin: on-demand reconciliation, set the path where it's `function.json` sits in the project.
+    server.at("/Admin-Reconcile").post(admin_reconcile);
 
-#[derive(Debug, Deserialize, Serialize)]
-struct McMergeFields {
-    #[serde(rename = "FNAME")]
-    first_name: String,
-    #[serde(rename = "EXPIRES")]
-    expires: String,
+    // Timer: PayPal / MailChimp reconciliation, set the path where it's `function.json` sits in the project.
+    server.at("/Reconciliation").post(reconciliation);
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct MailchimpResponse {
-    status: String,
-    email_address: String,
-    merge_fields: McMergeFields,
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{
+        azure_server, fake_upstreams, invocation, server, test_config, test_state, MockEmail,
+        LIST_ID,
+    };
+
+    fn multi_list_state() -> AppState {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.mc_currency_list_ids = HashMap::from([
+            ("USD".to_string(), "list_usd".to_string()),
+            ("EUR".to_string(), "list_eur".to_string()),
+            ("GBP".to_string(), LIST_ID.to_string()),
+        ]);
+        state
+    }
+
+    #[test]
+    fn currencies_resolve_to_their_list() {
+        let state = multi_list_state();
+        assert_eq!(state.mc_list_id_for(Some("USD")), "list_usd");
+        assert_eq!(state.mc_list_id_for(Some("usd")), "list_usd");
+        assert_eq!(state.mc_list_id_for(Some("CAD")), LIST_ID);
+        assert_eq!(state.mc_list_id_for(None), LIST_ID);
+    }
+
+    #[test]
+    fn foreign_payments_are_converted_to_the_base_currency() {
+        let mut state = multi_list_state();
+        state.base_currency = Some("CAD".to_string());
+        let mut config = test_config();
+        config.min_amounts = HashMap::from([("USD".to_string(), 15.0)]);
+        state.set_config(config);
+
+        assert_eq!(
+            state.to_base_currency(10.0, Some("USD"), Some(1.25)),
+            Some((12.5, "CAD"))
+        );
+        assert_eq!(state.to_base_currency(10.0, Some("cad"), Some(1.25)), None);
+        assert_eq!(state.to_base_currency(10.0, Some("USD"), None), None);
+
+        // The base currency's minimum, once converted.
+        assert!(state.meets_min_amount(10.0, Some("USD"), Some(1.25)));
+        assert!(!state.meets_min_amount(7.0, Some("USD"), Some(1.25)));
+        // Otherwise its own.
+        assert!(!state.meets_min_amount(10.0, Some("USD"), None));
+    }
+
+    #[test]
+    fn long_bodies_are_truncated_by_character() {
+        assert_eq!(truncate_body("short", 10), "short");
+        assert_eq!(truncate_body("exactly10!", 10), "exactly10!");
+        assert_eq!(
+            truncate_body("héllo wörld", 5),
+            "héllo… (13 bytes in total)"
+        );
+    }
+
+    #[test]
+    fn every_list_is_listed_once_default_first() {
+        let state = multi_list_state();
+        assert_eq!(state.mc_list_ids(), [LIST_ID, "list_eur", "list_usd"]);
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).expect("valid date")
+    }
+
+    fn paid_on(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        date(year, month, day)
+            .and_hms_opt(12, 0, 0)
+            .expect("valid time")
+            .and_utc()
+    }
+
+    const POLICY: ExpiryPolicy = ExpiryPolicy {
+        period_days: 365,
+        stack: false,
+        renewal_window_days: 0,
+        max_expiry_years: 2,
+    };
+
+    #[test]
+    fn payments_are_good_for_a_period_from_the_payment_date() {
+        assert_eq!(
+            compute_expiry(paid_on(2024, 1, 1), None, None, &POLICY),
+            date(2024, 12, 31)
+        );
+        // An expired membership restarts from the payment date.
+        assert_eq!(
+            compute_expiry(paid_on(2024, 1, 1), Some(date(2023, 6, 1)), None, &POLICY),
+            date(2024, 12, 31)
+        );
+        // An unexpired one is renewed from the payment date too, unless stacking.
+        assert_eq!(
+            compute_expiry(paid_on(2024, 1, 1), Some(date(2024, 3, 1)), None, &POLICY),
+            date(2024, 12, 31)
+        );
+        let stack = ExpiryPolicy {
+            stack: true,
+            ..POLICY
+        };
+        assert_eq!(
+            compute_expiry(paid_on(2024, 1, 1), Some(date(2024, 3, 1)), None, &stack),
+            date(2025, 3, 1)
+        );
+    }
+
+    #[test]
+    fn stacked_payments_are_clamped_at_the_cap() {
+        let policy = ExpiryPolicy {
+            stack: true,
+            ..POLICY
+        };
+        // 18 months left, another year would be 30 months out.
+        let expires = compute_expiry(paid_on(2024, 1, 1), Some(date(2025, 7, 1)), None, &policy);
+        assert_eq!(expires, date(2026, 1, 1));
+    }
+
+    #[test]
+    fn existing_expiry_beyond_the_cap_is_kept() {
+        let policy = ExpiryPolicy {
+            stack: true,
+            ..POLICY
+        };
+        let existing = Some(date(2030, 1, 1));
+        assert_eq!(
+            compute_expiry(paid_on(2024, 1, 1), existing, None, &policy),
+            date(2030, 1, 1)
+        );
+        assert_eq!(
+            compute_expiry(paid_on(2024, 1, 1), existing, None, &POLICY),
+            date(2030, 1, 1)
+        );
+    }
+
+    /// A membership check from the website, through the Azure envelope, returning the external response.
+    async fn cross_origin_check(method: &str, headers: Value) -> Value {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.cors_allowed_origins = vec!["https://squamishaccess.ca".to_string()];
+
+        let mut res: tide::http::Response = azure_server(state)
+            .respond(invocation(
+                "/Membership-Check",
+                method,
+                headers,
+                "email=ada%40example.com",
+            ))
+            .await
+            .expect("response");
+        let out: Value = res.body_json().await.expect("envelope");
+        out["Outputs"]["res"].clone()
+    }
+
+    #[async_std::test]
+    async fn cors_preflights_are_answered() {
+        let res = cross_origin_check(
+            "OPTIONS",
+            json!({
+                "Origin": ["https://squamishaccess.ca"],
+                "Access-Control-Request-Method": ["POST"],
+            }),
+        )
+        .await;
+
+        assert_eq!(res["statusCode"], 200);
+        assert_eq!(
+            res["headers"]["access-control-allow-origin"],
+            "https://squamishaccess.ca"
+        );
+        assert!(res["headers"]["access-control-allow-methods"]
+            .as_str()
+            .is_some_and(|methods| methods.contains("POST")));
+    }
+
+    #[async_std::test]
+    async fn cross_origin_checks_carry_cors_headers() {
+        let res = cross_origin_check(
+            "POST",
+            json!({
+                "Origin": ["https://squamishaccess.ca"],
+                "Content-Type": ["application/x-www-form-urlencoded"],
+            }),
+        )
+        .await;
+
+        assert_eq!(res["statusCode"], 303);
+        assert_eq!(
+            res["headers"]["access-control-allow-origin"],
+            "https://squamishaccess.ca"
+        );
+    }
+
+    #[test]
+    fn repeat_payments_within_the_renewal_window_do_not_extend() {
+        for stack in [false, true] {
+            let policy = ExpiryPolicy {
+                stack,
+                renewal_window_days: 30,
+                ..POLICY
+            };
+            let first = compute_expiry(paid_on(2024, 1, 1), None, None, &policy);
+            assert_eq!(first, date(2024, 12, 31));
+
+            // Within the window, e.g. paying twice by accident.
+            assert_eq!(
+                compute_expiry(paid_on(2024, 1, 10), Some(first), None, &policy),
+                first
+            );
+            // Outside it, a renewal as usual.
+            let renewed = if stack {
+                date(2025, 12, 31)
+            } else {
+                date(2025, 3, 1)
+            };
+            assert_eq!(
+                compute_expiry(paid_on(2024, 3, 1), Some(first), None, &policy),
+                renewed
+            );
+        }
+    }
+
+    #[test]
+    fn the_renewal_window_is_from_the_last_payment() {
+        let policy = ExpiryPolicy {
+            stack: true,
+            renewal_window_days: 30,
+            max_expiry_years: 5,
+            ..POLICY
+        };
+        // Stacked well beyond a period from the last payment.
+        let stacked = date(2026, 12, 31);
+        let last_payment = Some(date(2024, 1, 1));
+
+        assert_eq!(
+            compute_expiry(paid_on(2024, 1, 10), Some(stacked), last_payment, &policy),
+            stacked
+        );
+        assert_eq!(
+            compute_expiry(paid_on(2024, 3, 1), Some(stacked), last_payment, &policy),
+            date(2027, 12, 31)
+        );
+    }
+
+    #[test]
+    fn expiry_policy_matrix() {
+        let stack = ExpiryPolicy {
+            stack: true,
+            ..POLICY
+        };
+        let capped = ExpiryPolicy {
+            stack: true,
+            max_expiry_years: 1,
+            ..POLICY
+        };
+        // (payment date, existing expiry, policy, expected expiry)
+        let cases = [
+            // Leap days count as days.
+            (paid_on(2024, 2, 29), None, POLICY, date(2025, 2, 28)),
+            (paid_on(2023, 3, 1), None, POLICY, date(2024, 2, 29)),
+            // A later existing expiry is kept, without stacking.
+            (
+                paid_on(2024, 1, 1),
+                Some(date(2025, 6, 1)),
+                POLICY,
+                date(2025, 6, 1),
+            ),
+            // Stacking only extends unexpired memberships.
+            (
+                paid_on(2024, 1, 1),
+                Some(date(2023, 12, 31)),
+                stack,
+                date(2024, 12, 31),
+            ),
+            (
+                paid_on(2024, 1, 1),
+                Some(date(2024, 1, 2)),
+                stack,
+                date(2025, 1, 1),
+            ),
+            // Expiring on the payment date is expired.
+            (
+                paid_on(2024, 1, 1),
+                Some(date(2024, 1, 1)),
+                stack,
+                date(2024, 12, 31),
+            ),
+            // The cap is in months from the payment date, so from a leap day it ends on the 28th.
+            (
+                paid_on(2024, 2, 29),
+                Some(date(2024, 12, 1)),
+                capped,
+                date(2025, 2, 28),
+            ),
+        ];
+        for (payment_date, existing, policy, expected) in cases {
+            assert_eq!(
+                compute_expiry(payment_date, existing, None, &policy),
+                expected,
+                "paid {} - existing {:?} - {:?}",
+                payment_date,
+                existing,
+                policy
+            );
+        }
+    }
+
+    async fn ping(ping_body: bool) -> http_types::Response {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.ping_body = ping_body;
+        let req = http_types::Request::get("http://localhost/");
+        server(state).respond(req).await.expect("response")
+    }
+
+    #[async_std::test]
+    async fn the_ping_is_empty_by_default() {
+        let mut res = ping(false).await;
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.expect("body"), "");
+    }
+
+    #[async_std::test]
+    async fn the_ping_body_has_the_status_and_version() {
+        let mut res = ping(true).await;
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: Value = res.body_json().await.expect("JSON");
+        assert_eq!(
+            body,
+            json!({"status": "ok", "version": env!("CARGO_PKG_VERSION")})
+        );
+    }
 }
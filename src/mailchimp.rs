@@ -0,0 +1,508 @@
+use chrono::{NaiveDate, ParseResult};
+use http_types::headers::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+use surf::{Client, Url};
+use tide::StatusCode;
+
+use crate::truncate_body;
+
+/// The longest value MailChimp accepts for a text merge field.
+pub const MERGE_FIELD_MAX_LEN: usize = 255;
+
+/// Describes the api endpoint for a MailChimp api key or OAuth token.
+const METADATA_URL: &str = "https://login.mailchimp.com/oauth2/metadata";
+
+/// The format of MailChimp date merge fields, such as `JOINED` & `EXPIRES`.
+///
+/// All writers of membership date merge fields must use this format, so that
+/// existing values can be read back & compared.
+const MAILCHIMP_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Format a date for a MailChimp date merge field.
+pub fn to_mailchimp_format(date: NaiveDate) -> String {
+    date.format(MAILCHIMP_DATE_FORMAT).to_string()
+}
+
+/// Parse a MailChimp date merge field.
+pub fn parse_mailchimp_date(date: &str) -> ParseResult<NaiveDate> {
+    NaiveDate::parse_from_str(date, MAILCHIMP_DATE_FORMAT)
+}
+
+/// Parse a MailChimp `EXPIRES` merge field, where blank means there is no expiry on record.
+///
+/// Some legacy members have a blank `EXPIRES`, which is not an error.
+pub fn parse_expires(expires: &str) -> ParseResult<Option<NaiveDate>> {
+    let expires = expires.trim();
+    if expires.is_empty() {
+        Ok(None)
+    } else {
+        parse_mailchimp_date(expires).map(Some)
+    }
+}
+
+/// The api path of a list's members, relative to the MailChimp base url.
+///
+/// Paths have no leading slash, as the base url ends in a slash & a leading slash would replace its path.
+pub fn list_members_path(list_id: &str) -> String {
+    format!("3.0/lists/{}/members", list_id)
+}
+
+/// The api path of a list member, which MailChimp identifies by the MD5 hash of their lowercase email.
+pub fn member_path(list_id: &str, email: &str) -> String {
+    format!(
+        "{}/{:x}",
+        list_members_path(list_id),
+        md5::compute(email.to_lowercase())
+    )
+}
+
+/// The api path of a list member's tags.
+pub fn tags_path(list_id: &str, email: &str) -> String {
+    format!("{}/tags", member_path(list_id, email))
+}
+
+/// Query parameters to limit which fields MailChimp responds with.
+#[derive(Debug, Serialize)]
+pub struct MailchimpQuery {
+    pub fields: &'static [&'static str],
+}
+
+/// A MailChimp list member, as returned by the members API.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MailchimpMember {
+    pub email_address: String,
+    pub status: MembershipStatus,
+    #[serde(default)]
+    pub merge_fields: MergeFields,
+}
+
+/// The merge fields we use on MailChimp list members.
+///
+/// MailChimp sends blank merge fields as empty strings.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MergeFields {
+    #[serde(rename = "FNAME", default)]
+    pub first_name: String,
+    #[serde(rename = "LNAME", default)]
+    pub last_name: String,
+    #[serde(rename = "JOINED", default)]
+    pub joined: String,
+    #[serde(rename = "EXPIRES", default)]
+    pub expires: String,
+    #[serde(rename = "MEMBERTYPE", default)]
+    pub membership_type: String, // see `MEMBERSHIP_TYPES`, blank for most lists
+    #[serde(rename = "LASTPAY", default, skip_serializing_if = "String::is_empty")]
+    pub last_payment: String, // blank for lists without it
+    #[serde(rename = "GRANTS", default, skip_serializing_if = "String::is_empty")]
+    pub grants: String, // see `ExpiryGrant`, blank for lists without it
+}
+
+/// An error response from the MailChimp api.
+#[derive(Debug, Default, Deserialize)]
+pub struct MailchimpError {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub detail: String,
+    /// Per-field validation errors, if any.
+    #[serde(default)]
+    pub errors: Vec<MailchimpFieldError>,
+}
+
+impl MailchimpError {
+    /// Whether this is MailChimp's error for creating a member who is already in the list.
+    ///
+    /// Checked via the `title`, as the `detail` is free text.
+    pub fn is_member_exists(&self) -> bool {
+        self.title == "Member Exists"
+    }
+}
+
+/// A validation error for a single field, in a MailChimp error response.
+#[derive(Debug, Deserialize)]
+pub struct MailchimpFieldError {
+    #[serde(default)]
+    pub field: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+/// The subscription status of a MailChimp list member.
+///
+/// This covers every status MailChimp may respond with, so that decisions on it are exhaustive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MembershipStatus {
+    Subscribed,
+    Unsubscribed,
+    /// The address bounced.
+    Cleaned,
+    /// Awaiting opt-in confirmation.
+    Pending,
+    /// Added via the transactional API only, not a list subscriber.
+    Transactional,
+    Archived,
+}
+
+impl MembershipStatus {
+    /// The MailChimp name of this status.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Subscribed => "subscribed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::Cleaned => "cleaned",
+            Self::Pending => "pending",
+            Self::Transactional => "transactional",
+            Self::Archived => "archived",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    api_endpoint: String,
+}
+
+/// The MailChimp datacenter from an api key's suffix, e.g. `us6` from `...-us6`, if it has a usable one.
+pub fn datacenter_from_key(api_key: &str) -> Option<&str> {
+    let (_, datacenter) = api_key.rsplit_once('-')?;
+    (!datacenter.is_empty() && datacenter.chars().all(|c| c.is_ascii_alphanumeric()))
+        .then_some(datacenter)
+}
+
+/// Ask MailChimp for the api endpoint of an api key or OAuth token, for keys without a datacenter suffix.
+pub async fn discover_api_endpoint(client: &Client, api_key: &str) -> tide::Result<Url> {
+    let mut res = client
+        .get(METADATA_URL)
+        .header(AUTHORIZATION, format!("OAuth {}", api_key))
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(tide::Error::from_str(
+            res.status(),
+            format!(
+                "Mailchimp metadata: error body: \"{}\"",
+                res.body_string().await?
+            ),
+        ));
+    }
+
+    let Metadata { api_endpoint } = res.body_json().await?;
+    Ok(Url::parse(&api_endpoint)?)
+}
+
+/// Fetch a list member by email, or `None` if they are not in the list.
+pub async fn get_member(
+    client: &Client,
+    list_id: &str,
+    email: &str,
+    query: &MailchimpQuery,
+    log_body_max_len: usize,
+) -> tide::Result<Option<MailchimpMember>> {
+    let mc_path = member_path(list_id, email);
+    let mut mailchimp_res = client.get(&mc_path).query(query)?.await?;
+
+    match mailchimp_res.status() {
+        StatusCode::NotFound => Ok(None),
+        status if status.is_success() => Ok(Some(mailchimp_res.body_json().await?)),
+        status => Err(tide::Error::from_str(
+            status,
+            format!(
+                "Mailchimp GET: error body: \"{}\"",
+                truncate_body(&mailchimp_res.body_string().await?, log_body_max_len)
+            ),
+        )),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ListsQuery {
+    fields: &'static str,
+    count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Lists {
+    #[serde(default)]
+    lists: Vec<List>,
+}
+
+#[derive(Debug, Deserialize)]
+struct List {
+    id: String,
+    name: String,
+}
+
+/// Whether a configured list looks like a MailChimp list id, e.g. `a1b2c3d4e5`, rather than an audience name.
+pub fn looks_like_list_id(list: &str) -> bool {
+    list.len() == 10
+        && list
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_lowercase())
+}
+
+/// Find the id of the list (audience) with a name, ignoring case, erroring unless exactly one matches.
+pub async fn resolve_list_id(
+    client: &Client,
+    name: &str,
+    log_body_max_len: usize,
+) -> tide::Result<String> {
+    let query = ListsQuery {
+        fields: "lists.id,lists.name",
+        count: 1000,
+    };
+    let mut res = client.get("3.0/lists").query(&query)?.await?;
+
+    if !res.status().is_success() {
+        return Err(tide::Error::from_str(
+            res.status(),
+            format!(
+                "Mailchimp lists: error body: \"{}\"",
+                truncate_body(&res.body_string().await?, log_body_max_len)
+            ),
+        ));
+    }
+
+    let Lists { lists } = res.body_json().await?;
+    let mut matches = lists
+        .into_iter()
+        .filter(|list| list.name.trim().eq_ignore_ascii_case(name.trim()));
+    match (matches.next(), matches.next()) {
+        (Some(list), None) => Ok(list.id),
+        (None, _) => Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("Mailchimp lists: no list named \"{}\"", name),
+        )),
+        (Some(_), Some(_)) => Err(tide::Error::from_str(
+            StatusCode::Conflict,
+            format!("Mailchimp lists: more than one list named \"{}\"", name),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{json, text, MockHttp};
+
+    #[test]
+    fn dates_are_written_date_only() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 9).expect("valid date");
+        assert_eq!(to_mailchimp_format(date), "2024-03-09");
+        assert_eq!(parse_mailchimp_date("2024-03-09"), Ok(date));
+    }
+
+    #[test]
+    fn blank_expires_is_no_expiry() {
+        assert_eq!(parse_expires(""), Ok(None));
+        assert_eq!(parse_expires("   "), Ok(None));
+        assert_eq!(
+            parse_expires(" 2025-01-31 "),
+            Ok(NaiveDate::from_ymd_opt(2025, 1, 31))
+        );
+        assert!(parse_expires("2025-01-31T00:00:00Z").is_err());
+        assert!(parse_expires("31/01/2025").is_err());
+    }
+
+    #[test]
+    fn datacenters_come_from_the_key_suffix() {
+        assert_eq!(datacenter_from_key("0123456789abcdef-us6"), Some("us6"));
+        assert_eq!(datacenter_from_key("a-b-us21"), Some("us21"));
+        assert_eq!(datacenter_from_key("0123456789abcdef"), None);
+        assert_eq!(datacenter_from_key("0123456789abcdef-"), None);
+        assert_eq!(datacenter_from_key("key-us6.evil.com/"), None);
+    }
+
+    #[test]
+    fn list_ids_are_told_apart_from_audience_names() {
+        assert!(looks_like_list_id("a1b2c3d4e5"));
+        assert!(!looks_like_list_id("A1B2C3D4E5"));
+        assert!(!looks_like_list_id("a1b2c3d4e"));
+        assert!(!looks_like_list_id("Members 24"));
+    }
+
+    #[test]
+    fn members_round_trip_through_json() {
+        let json = serde_json::json!({
+            "email_address": "ada@example.com",
+            "status": "subscribed",
+            "merge_fields": {
+                "FNAME": "Ada",
+                "LNAME": "Lovelace",
+                "JOINED": "2020-03-01",
+                "EXPIRES": "2025-03-01",
+                "MEMBERTYPE": "Family",
+            },
+        });
+        let member: MailchimpMember = serde_json::from_value(json.clone()).expect("member");
+        assert_eq!(member.status, MembershipStatus::Subscribed);
+        assert_eq!(member.merge_fields.first_name, "Ada");
+        assert_eq!(member.merge_fields.expires, "2025-03-01");
+        assert_eq!(serde_json::to_value(&member).expect("JSON"), json);
+    }
+
+    #[test]
+    fn missing_merge_fields_are_blank() {
+        let member: MailchimpMember = serde_json::from_value(serde_json::json!({
+            "email_address": "ada@example.com",
+            "status": "pending",
+            "merge_fields": {"FNAME": "Ada", "ADDRESS": ""},
+        }))
+        .expect("member");
+        assert_eq!(member.merge_fields.expires, "");
+        assert_eq!(member.merge_fields.membership_type, "");
+    }
+
+    #[test]
+    fn every_mailchimp_status_is_a_variant() {
+        for status in [
+            "subscribed",
+            "unsubscribed",
+            "cleaned",
+            "pending",
+            "transactional",
+            "archived",
+        ] {
+            let parsed: MembershipStatus =
+                serde_json::from_value(serde_json::json!(status)).expect("known status");
+            assert_eq!(parsed.as_str(), status);
+            assert_eq!(serde_json::to_value(parsed).expect("JSON"), status);
+        }
+        assert!(serde_json::from_value::<MembershipStatus>(serde_json::json!("deleted")).is_err());
+    }
+
+    #[async_std::test]
+    async fn api_endpoints_are_discovered_from_the_metadata_endpoint() {
+        let http = MockHttp::new(|_| {
+            json(
+                StatusCode::Ok,
+                &serde_json::json!({"dc": "us6", "api_endpoint": "https://us6.api.mailchimp.com"}),
+            )
+        });
+
+        let endpoint = discover_api_endpoint(&http.client("https://mailchimp.test/"), "token")
+            .await
+            .expect("endpoint");
+
+        assert_eq!(endpoint.as_str(), "https://us6.api.mailchimp.com/");
+        let sent = http.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].url.as_str(), METADATA_URL);
+        assert_eq!(
+            sent[0].headers.get(AUTHORIZATION).map(|auth| auth.as_str()),
+            Some("OAuth token")
+        );
+    }
+
+    #[async_std::test]
+    async fn rejected_metadata_requests_are_errors() {
+        let http = MockHttp::new(|_| text(StatusCode::Unauthorized, "invalid_token"));
+
+        let error = discover_api_endpoint(&http.client("https://mailchimp.test/"), "token")
+            .await
+            .expect_err("rejected");
+
+        assert_eq!(error.status(), StatusCode::Unauthorized);
+        assert!(error.to_string().contains("invalid_token"));
+    }
+
+    #[test]
+    fn member_exists_errors_are_told_by_their_title() {
+        let error: MailchimpError = serde_json::from_str(
+            r#"{"type": "https://mailchimp.com/developer/marketing/docs/errors/", "title": "Member Exists", "status": 400, "detail": "ada@example.com is already a list member. Use PUT to insert or update list members."}"#,
+        )
+        .expect("error");
+        assert!(error.is_member_exists());
+
+        // The detail naming the email is not enough.
+        let error: MailchimpError = serde_json::from_str(
+            r#"{"title": "Invalid Resource", "status": 400, "detail": "ada@example.com looks fake or invalid, please enter a real email address."}"#,
+        )
+        .expect("error");
+        assert!(!error.is_member_exists());
+    }
+
+    #[test]
+    fn member_paths_are_the_same_whatever_the_email_casing() {
+        // So a lookup can only ever match one member, without searching by email.
+        let path = member_path("list0000aa", "ada@example.com");
+        assert_eq!(
+            path,
+            "3.0/lists/list0000aa/members/3e3417d7ef77d5932a6734b916515ed5"
+        );
+        assert_eq!(member_path("list0000aa", "Ada@Example.COM"), path);
+    }
+
+    #[test]
+    fn api_paths_are_exact() {
+        assert_eq!(
+            list_members_path("list0000aa"),
+            "3.0/lists/list0000aa/members"
+        );
+        assert_eq!(
+            tags_path("list0000aa", "ada@example.com"),
+            "3.0/lists/list0000aa/members/3e3417d7ef77d5932a6734b916515ed5/tags"
+        );
+
+        // Relative, so they join onto the base url rather than replacing its path.
+        let base_url = Url::parse("https://us1.api.mailchimp.com/").expect("url");
+        assert_eq!(
+            base_url
+                .join(&member_path("list0000aa", "ada@example.com"))
+                .expect("url")
+                .as_str(),
+            "https://us1.api.mailchimp.com/3.0/lists/list0000aa/members/3e3417d7ef77d5932a6734b916515ed5"
+        );
+    }
+
+    fn lists_api() -> MockHttp {
+        MockHttp::new(|_| {
+            json(
+                StatusCode::Ok,
+                &serde_json::json!({"lists": [
+                    {"id": "abc1234567", "name": "Squamish Access Society"},
+                    {"id": "def1234567", "name": "Newsletter"},
+                    {"id": "ghi1234567", "name": "Newsletter"},
+                ]}),
+            )
+        })
+    }
+
+    #[async_std::test]
+    async fn list_names_are_resolved_to_their_id() {
+        let http = lists_api();
+
+        let list_id = resolve_list_id(
+            &http.client("https://mailchimp.test/"),
+            " squamish access society ",
+            1000,
+        )
+        .await
+        .expect("list id");
+
+        assert_eq!(list_id, "abc1234567");
+        let sent = http.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].url.path(), "/3.0/lists");
+        assert_eq!(
+            sent[0].url.query(),
+            Some("fields=lists.id%2Clists.name&count=1000")
+        );
+    }
+
+    #[async_std::test]
+    async fn unknown_or_ambiguous_list_names_are_errors() {
+        let http = lists_api();
+        let client = http.client("https://mailchimp.test/");
+
+        let unknown = resolve_list_id(&client, "Members", 1000)
+            .await
+            .expect_err("no such list");
+        let ambiguous = resolve_list_id(&client, "Newsletter", 1000)
+            .await
+            .expect_err("two lists");
+
+        assert_eq!(unknown.status(), StatusCode::NotFound);
+        assert_eq!(ambiguous.status(), StatusCode::Conflict);
+    }
+}
@@ -39,7 +39,6 @@
     clippy::print_stdout,
     clippy::same_name_method,
     clippy::single_char_lifetime_names,
-    clippy::string_to_string,
     clippy::trait_duplication_in_bounds,
     clippy::type_repetition_in_bounds,
     clippy::unimplemented,
@@ -48,16 +47,36 @@
     clippy::used_underscore_binding
 )]
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::env;
-use std::sync::Arc;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use color_eyre::eyre::Result;
+use async_signal::{Signal, Signals};
+use async_std::channel;
+use async_std::prelude::*;
+use async_std::task;
+use color_eyre::eyre::{ensure, eyre, Result};
 use http_types::auth::{AuthenticationScheme, Authorization, BasicAuth};
 use log::{info, warn};
 use surf::{Client, Config, Url};
 
-use lib::azure_function::{AzureFnLogMiddleware, AzureFnMiddleware};
+use lib::azure_function::{
+    AzureFnLogMiddleware, AzureFnMiddleware, AzureFnRequireHttpsMiddleware, LocalLog,
+};
+use lib::callback::SubscriptionCallback;
+use lib::clock::SystemClock;
+use lib::config::ReloadableConfig;
+use lib::email::{EmailSender, SendGridSender};
+use lib::ipn_queue;
+use lib::mailchimp;
+use lib::mandrill::MandrillSender;
+use lib::paypal::{self, IpnVerifyMethod, PaypalRestCredentials};
+use lib::proxy::ProxyClient;
+use lib::sendgrid::EmailAddress;
+use lib::tls::TlsListener;
 use lib::AppState;
 use squamishaccess_functions as lib;
 
@@ -79,24 +98,74 @@ async fn main() -> Result<()> {
     // MailChimp
     let mc_api_key = env::var("MAILCHIMP_API_KEY").expect("MAILCHIMP_API_KEY is required.");
     let mc_list_id = env::var("MAILCHIMP_LIST_ID").expect("MAILCHIMP_LIST_ID is required.");
-    let mc_base_url = Url::parse(&format!(
-        "https://{}.api.mailchimp.com",
-        mc_api_key
-            .split('-')
-            .nth(1)
-            .expect("Requires a valid, full mailchimp api key")
-    ))?;
-    let mc_auth = BasicAuth::new("any", mc_api_key);
-
-    // Twilio (email sends)
-    let twilio_api_key = env::var("TWILIO_API_KEY").expect("TWILIO_API_KEY is required.");
-    let twilio_auth = Authorization::new(AuthenticationScheme::Bearer, twilio_api_key);
-
-    // Twilio email templates
-    let template_membership_check =
-        env::var("TEMPLATE_MEMBERSHIP_CHECK").expect("TEMPLATE_MEMBERSHIP_CHECK is required.");
-    let template_membership_notfound = env::var("TEMPLATE_MEMBERSHIP_NOTFOUND")
-        .expect("TEMPLATE_MEMBERSHIP_NOTFOUND is required.");
+    let mc_currency_list_ids: HashMap<String, String> = env::var("MAILCHIMP_CURRENCY_LIST_IDS")
+        .map(|v| {
+            serde_json::from_str::<HashMap<String, String>>(&v)
+                .expect("MAILCHIMP_CURRENCY_LIST_IDS must be a JSON object of currency to list id.")
+                .into_iter()
+                .map(|(currency, list_id)| (currency.to_uppercase(), list_id))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mc_custom_merge_field =
+        env::var("MAILCHIMP_CUSTOM_MERGE_FIELD").unwrap_or_else(|_| "CAMPAIGN".to_string());
+    // The datacenter is usually the api key's suffix, otherwise it is discovered once the clients are set up.
+    let mc_base_url = match env::var("MAILCHIMP_BASE_URL") {
+        Ok(url) => Some(Url::parse(&url).expect("MAILCHIMP_BASE_URL must be a valid url.")),
+        Err(_) => mailchimp::datacenter_from_key(&mc_api_key)
+            .map(|datacenter| Url::parse(&format!("https://{}.api.mailchimp.com", datacenter)))
+            .transpose()?,
+    };
+    let mc_auth = BasicAuth::new("any", &mc_api_key);
+
+    // Configuration which can be reloaded without a restart.
+    let config = ReloadableConfig::from_env().expect("Invalid configuration");
+    if !config.ipn_enabled {
+        warn!("IPN_ENABLED: The PayPal IPN handler is disabled");
+    }
+    if config.read_only {
+        warn!("READ_ONLY: PayPal IPNs are deferred, membership checks still work");
+    }
+    if !config.membership_check_enabled {
+        warn!("MEMBERSHIP_CHECK_ENABLED: The membership check is disabled");
+    }
+
+    // Admin endpoints are disabled without a secret.
+    let admin_secret = env::var("ADMIN_SECRET").ok();
+
+    // Email sends, via SendGrid (Twilio) by default, or Mandrill.
+    let email_provider = env::var("EMAIL_PROVIDER").unwrap_or_else(|_| "sendgrid".to_string());
+    assert!(
+        matches!(email_provider.as_str(), "sendgrid" | "mandrill"),
+        "EMAIL_PROVIDER must be sendgrid or mandrill."
+    );
+    let mandrill_api_key = (email_provider == "mandrill").then(|| {
+        env::var("MANDRILL_API_KEY")
+            .expect("MANDRILL_API_KEY is required with EMAIL_PROVIDER=mandrill.")
+    });
+
+    // Optional sender display name & reply-to for emails.
+    let sendgrid_from_name = env::var("SENDGRID_FROM_NAME").ok();
+    let sendgrid_reply_to = env::var("SENDGRID_REPLY_TO")
+        .ok()
+        .map(|email| EmailAddress {
+            email,
+            name: env::var("SENDGRID_REPLY_TO_NAME").ok(),
+        });
+
+    // Membership check response mode
+    let membership_check_html = env::var("MEMBERSHIP_CHECK_HTML").is_ok();
+
+    // Signs the membership check result into its redirect, for the website to display.
+    let membership_token_secret = env::var("MEMBERSHIP_TOKEN_SECRET").ok();
+
+    // Origins allowed to make cross-origin membership checks, e.g. `https://squamishaccess.ca`.
+    let cors_allowed_origins = env::var("CORS_ALLOWED_ORIGIN")
+        .unwrap_or_default()
+        .split(',')
+        .map(|origin| origin.trim().to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
 
     // PayPal
     let paypal_sandbox = env::var("PAYPAL_SANDBOX").is_ok();
@@ -107,37 +176,303 @@ async fn main() -> Result<()> {
         Url::parse("https://ipnpb.paypal.com/")?
     };
 
+    // For local integration testing without PayPal. Never honoured against live PayPal, as anyone could then forge a payment.
+    let skip_paypal_verify = env::var("SKIP_PAYPAL_VERIFY").is_ok();
+    if skip_paypal_verify {
+        if paypal_sandbox {
+            warn!("SANDBOX: SKIP_PAYPAL_VERIFY: IPNs are NOT verified with PayPal, and are all treated as genuine");
+        } else {
+            warn!("SKIP_PAYPAL_VERIFY is ignored without PAYPAL_SANDBOX, IPNs are verified with PayPal");
+        }
+    }
+    let skip_paypal_verify = skip_paypal_verify && paypal_sandbox;
+
+    // PayPal's IPN verification is the most latency-sensitive call, so it has its own timeout.
+    let paypal_verify_timeout = env::var("PAYPAL_VERIFY_TIMEOUT_SECS").map_or(60, |v| {
+        v.parse()
+            .expect("PAYPAL_VERIFY_TIMEOUT_SECS must be a number.")
+    });
+    let paypal_verify_timeout = Duration::from_secs(paypal_verify_timeout);
+
+    // Verbose IPN logging, which includes personal information.
+    let log_raw_ipn = env::var("LOG_RAW_IPN").is_ok();
+    if log_raw_ipn {
+        warn!("LOG_RAW_IPN: Logging raw IPN bodies");
+    }
+
+    // Request & response bodies in logs are truncated to this many characters.
+    let log_body_max_len = env::var("LOG_BODY_MAX_LEN").map_or(2048, |v| {
+        v.parse().expect("LOG_BODY_MAX_LEN must be a number.")
+    });
+
+    // Simulator & sandbox IPNs, with `test_ipn=1`, can be kept from touching MailChimp.
+    let test_ipn_dry_run = env::var("TEST_IPN_DRY_RUN").is_ok();
+    if test_ipn_dry_run {
+        warn!("TEST_IPN_DRY_RUN: Not updating MailChimp for test IPNs");
+    }
+
+    // Admin-Stats pages the whole MailChimp list, so its result is reused for a while.
+    let stats_cache_ttl = env::var("STATS_CACHE_SECS").map_or(300, |v| {
+        v.parse().expect("STATS_CACHE_SECS must be a number.")
+    });
+    let stats_cache_ttl = Duration::from_secs(stats_cache_ttl);
+
+    // Invocation payloads are buffered whole, so very large ones are refused.
+    let max_envelope_len = env::var("MAX_ENVELOPE_BYTES").map_or(10 * 1024 * 1024, |v| {
+        v.parse().expect("MAX_ENVELOPE_BYTES must be a number.")
+    });
+
+    // Local development: invocation logs otherwise only go back to the Azure host.
+    let local_log = env::var("LOCAL_LOG")
+        .ok()
+        .map(|destination| LocalLog::from(destination.as_str()));
+
+    // Reject requests forwarded over plain http, off by default for local development.
+    let require_https = env::var("REQUIRE_HTTPS").is_ok();
+
+    // Uptime monitors may want a body from the ping.
+    let ping_body = env::var("PING_BODY").is_ok();
+
+    // IPNs can be acknowledged to PayPal before processing, for when MailChimp is slow. Off by default.
+    let ipn_queue_size: usize = env::var("IPN_QUEUE_SIZE")
+        .map_or(0, |v| v.parse().expect("IPN_QUEUE_SIZE must be a number."));
+    // Queued IPNs have no output bindings, so their audit events would be lost.
+    assert!(
+        !(config.audit_queue && ipn_queue_size > 0),
+        "AUDIT_QUEUE can't be used with IPN_QUEUE_SIZE, as queued IPNs have no audit output binding."
+    );
+    let (ipn_queue, ipn_queue_receiver) = if ipn_queue_size > 0 {
+        let (sender, receiver) = channel::bounded(ipn_queue_size);
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
+    // Timing of each request to MailChimp, PayPal, SendGrid & the subscription callback.
+    let log_upstream_timing = env::var("LOG_UPSTREAM_TIMING").is_ok();
+
+    // Foreign payments are converted to this currency for the minimum, when PayPal gives an exchange rate.
+    let base_currency = env::var("BASE_CURRENCY")
+        .ok()
+        .map(|currency| currency.trim().to_uppercase())
+        .filter(|currency| !currency.is_empty());
+
+    // Currencies which PayPal does not support decimals for.
+    let zero_decimal_currencies = env::var("ZERO_DECIMAL_CURRENCIES")
+        .unwrap_or_else(|_| "HUF,JPY,TWD".to_string())
+        .split(',')
+        .map(|currency| currency.trim().to_uppercase())
+        .filter(|currency| !currency.is_empty())
+        .collect();
+
+    let paypal_rest_base_url = if paypal_sandbox {
+        Url::parse("https://api-m.sandbox.paypal.com/")?
+    } else {
+        Url::parse("https://api-m.paypal.com/")?
+    };
+
+    // PayPal REST, optional, only needed for reconciliation.
+    let paypal_rest_credentials = match (
+        env::var("PAYPAL_CLIENT_ID"),
+        env::var("PAYPAL_CLIENT_SECRET"),
+    ) {
+        (Ok(client_id), Ok(client_secret)) => Some(PaypalRestCredentials {
+            client_id,
+            client_secret,
+        }),
+        _ => None,
+    };
+
+    // IPNs are verified by the legacy postback, unless the REST api is chosen.
+    let ipn_verify_method: IpnVerifyMethod =
+        env::var("IPN_VERIFY_METHOD").map_or(IpnVerifyMethod::Postback, |v| {
+            v.parse()
+                .expect("IPN_VERIFY_METHOD must be postback or rest.")
+        });
+    assert!(
+        ipn_verify_method == IpnVerifyMethod::Postback || paypal_rest_credentials.is_some(),
+        "IPN_VERIFY_METHOD=rest requires PAYPAL_CLIENT_ID & PAYPAL_CLIENT_SECRET."
+    );
+
+    // Optional notification of an external system, e.g. a CRM, of processed memberships.
+    let subscription_callback =
+        env::var("SUBSCRIPTION_CALLBACK_URL")
+            .ok()
+            .map(|url| SubscriptionCallback {
+                url: Url::parse(&url).expect("SUBSCRIPTION_CALLBACK_URL must be a valid url."),
+                secret: env::var("SUBSCRIPTION_CALLBACK_SECRET").expect(
+                    "SUBSCRIPTION_CALLBACK_SECRET is required with SUBSCRIPTION_CALLBACK_URL.",
+                ),
+            });
+
     // Set up re-useable api clients for efficiency & ergonomics.
     let client_config = Config::new().set_http_keep_alive(false);
+    // Tunnel through an outbound proxy, if the network requires one.
+    let client_config = match ProxyClient::from_env(client_config.as_ref().clone()) {
+        Some(proxy) => {
+            info!("Using outbound proxy: {}", proxy.proxy_addr());
+            client_config.set_http_client(proxy)
+        }
+        None => client_config,
+    };
+    let mc_base_url = match mc_base_url {
+        Some(mc_base_url) => mc_base_url,
+        None => {
+            let client: Client = client_config.clone().try_into()?;
+            let mc_base_url = mailchimp::discover_api_endpoint(&client, &mc_api_key)
+                .await
+                .expect("Could not discover the MailChimp api endpoint, set MAILCHIMP_BASE_URL.");
+            info!("Discovered MailChimp api endpoint: {}", mc_base_url);
+            mc_base_url
+        }
+    };
     let mailchimp: Client = client_config
         .clone()
         .set_base_url(mc_base_url)
         .add_header(mc_auth.name(), mc_auth.value())
         .expect("Provided MailChimp auth must be valid")
         .try_into()?;
-    let twilio: Client = client_config
+    // Operators may give the audience name instead of its id, which is looked up once here.
+    let mc_list_id = if mailchimp::looks_like_list_id(&mc_list_id) {
+        mc_list_id
+    } else {
+        let list_id = mailchimp::resolve_list_id(&mailchimp, &mc_list_id, log_body_max_len)
+            .await
+            .map_err(|error| {
+                eyre!(
+                    "MAILCHIMP_LIST_ID could not be resolved as a list name: {}",
+                    error
+                )
+            })?;
+        info!(
+            "Resolved MailChimp list \"{}\" to id: {}",
+            mc_list_id, list_id
+        );
+        list_id
+    };
+    let email_base_url = Url::parse(if mandrill_api_key.is_some() {
+        "https://mandrillapp.com/"
+    } else {
+        "https://api.sendgrid.com/"
+    })?;
+    let email: Box<dyn EmailSender> = match mandrill_api_key {
+        Some(api_key) => Box::new(MandrillSender {
+            client: client_config
+                .clone()
+                .set_base_url(email_base_url.clone())
+                .try_into()?,
+            api_key,
+        }),
+        None => {
+            let twilio_api_key = env::var("TWILIO_API_KEY").expect("TWILIO_API_KEY is required.");
+            let twilio_auth = Authorization::new(AuthenticationScheme::Bearer, twilio_api_key);
+            Box::new(SendGridSender {
+                client: client_config
+                    .clone()
+                    .set_base_url(email_base_url.clone())
+                    .add_header(twilio_auth.name(), twilio_auth.value())
+                    .expect("Provided Twilio auth must be valid")
+                    .try_into()?,
+            })
+        }
+    };
+    let callback: Client = client_config.clone().try_into()?;
+    let paypal: Client = client_config
         .clone()
-        .set_base_url(Url::parse("https://api.sendgrid.com/")?)
-        .add_header(twilio_auth.name(), twilio_auth.value())
-        .expect("Provided Twilio auth must be valid")
+        .set_base_url(paypal_base_url)
+        .try_into()?;
+    let paypal_rest: Client = client_config
+        .set_base_url(paypal_rest_base_url)
         .try_into()?;
-    let paypal: Client = client_config.set_base_url(paypal_base_url).try_into()?;
+
+    // The effective upstreams, for debugging a first deploy.
+    let base_url = |client: &Client| {
+        client
+            .config()
+            .base_url
+            .as_ref()
+            .map_or_else(|| "(none)".to_string(), redact_url)
+    };
+    info!(
+        "Upstreams - MailChimp: {} - PayPal IPN verification: {} ({:?}) - PayPal REST: {} - email: {} - subscription callback: {}",
+        base_url(&mailchimp),
+        base_url(&paypal),
+        ipn_verify_method,
+        base_url(&paypal_rest),
+        redact_url(&email_base_url),
+        subscription_callback
+            .as_ref()
+            .map_or_else(|| "(none)".to_string(), |callback| redact_url(&callback.url))
+    );
 
     // Application shared state.
     // This is set behind an atomic reference counted pointer.
     let state = AppState {
+        admin_secret,
+        base_currency,
+        callback,
+        clock: Box::new(SystemClock),
+        config: RwLock::new(Arc::new(config)),
+        cors_allowed_origins,
+        idempotency_keys: Mutex::default(),
+        ipn_queue,
+        ipn_verify_method,
+        log_body_max_len,
+        log_raw_ipn,
+        log_upstream_timing,
         mailchimp,
+        mc_custom_merge_field,
         mc_list_id,
+        mc_currency_list_ids,
+        membership_check_html,
+        membership_token_secret,
         paypal,
+        paypal_rest,
+        paypal_rest_credentials,
         paypal_sandbox,
-        template_membership_check,
-        template_membership_notfound,
-        twilio,
+        paypal_verify_timeout,
+        test_ipn_dry_run,
+        zero_decimal_currencies,
+        ping_body,
+        sendgrid_from_name,
+        sendgrid_reply_to,
+        skip_paypal_verify,
+        stats_cache: Mutex::default(),
+        stats_cache_ttl,
+        subscription_callback,
+        email,
     };
 
-    let mut server = tide::with_state(Arc::new(state));
-    server.with(AzureFnMiddleware::new());
+    // Pre-flight for deployments: everything above has been validated & set up, without serving.
+    if env::args().any(|arg| arg == "--check-config") {
+        info!(
+            "Configuration OK - MailChimp list: {} ({} per-currency) - PayPal sandbox: {} - reconciliation: {} - subscription callback: {}",
+            state.mc_list_id,
+            state.mc_currency_list_ids.len(),
+            state.paypal_sandbox,
+            state.paypal_rest_credentials.is_some(),
+            state.subscription_callback.is_some()
+        );
+        if env::args().any(|arg| arg == "--ping") {
+            ping_upstreams(&state).await?;
+        }
+        return Ok(());
+    }
+
+    let state = Arc::new(state);
+    let ipn_queue_worker =
+        ipn_queue_receiver.map(|queue| task::spawn(ipn_queue::worker(state.clone(), queue)));
+
+    let mut server = tide::with_state(state.clone());
+    let mut azure_fn_middleware = AzureFnMiddleware::new().max_envelope_len(max_envelope_len);
+    if let Some(local_log) = local_log {
+        azure_fn_middleware = azure_fn_middleware.local_log(local_log);
+    }
+    server.with(azure_fn_middleware);
     server.with(AzureFnLogMiddleware::new());
+    if require_https {
+        server.with(AzureFnRequireHttpsMiddleware::new());
+    }
 
     lib::setup_routes(&mut server);
 
@@ -147,8 +482,101 @@ async fn main() -> Result<()> {
     });
     let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
 
-    server
-        .listen((host.as_str(), port))
+    // Serve https directly, when not behind the Azure Functions host.
+    let tls_cert = env::var("TLS_CERT").ok();
+    let tls_key = env::var("TLS_KEY").ok();
+    assert!(
+        tls_cert.is_some() == tls_key.is_some(),
+        "TLS_CERT & TLS_KEY must be set together."
+    );
+    let listen = async {
+        if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+            let listener = TlsListener::from_pem_files(format!("{}:{}", host, port), &cert, &key)
+                .expect("TLS_CERT & TLS_KEY must be readable PEM files.");
+            server.listen(listener).await
+        } else {
+            server.listen((host.as_str(), port)).await
+        }
+    };
+
+    match ipn_queue_worker {
+        None => listen.await?,
+        Some(worker) => {
+            // PayPal has already been told that queued IPNs are done, so finish them before exiting.
+            listen.race(shutdown_signal()).await?;
+            if let Some(queue) = &state.ipn_queue {
+                info!("Shutting down, draining {} queued IPNs", queue.len());
+                queue.close();
+            }
+            worker.await;
+        }
+    }
+    Ok(())
+}
+
+/// A url for logging, without any credentials, query or fragment, which may carry secrets.
+fn redact_url(url: &Url) -> String {
+    let mut url = url.clone();
+    // Only fails for urls which can't have credentials anyway.
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.set_query(None);
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// Resolves on SIGTERM, or Ctrl-C.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    let signals = [Signal::Term, Signal::Int];
+    #[cfg(not(unix))]
+    let signals = [Signal::Int];
+
+    Signals::new(signals)?.next().await;
+    Ok(())
+}
+
+/// Check that each upstream api is reachable & accepts our credentials, for `--check-config --ping`.
+async fn ping_upstreams(state: &AppState) -> Result<()> {
+    let res = state
+        .mailchimp
+        .get("3.0/ping")
+        .await
+        .map_err(|error| eyre!("MailChimp ping failed: {}", error))?;
+    ensure!(
+        res.status().is_success(),
+        "MailChimp ping - status: {}",
+        res.status()
+    );
+    info!("MailChimp OK");
+
+    state
+        .email
+        .ping()
         .await
-        .map_err(Into::into)
+        .map_err(|error| eyre!("Email provider ping failed: {}", error))?;
+    info!("Email provider OK");
+
+    // An empty verification is answered with INVALID, which is enough to know PayPal is reachable.
+    let res = state
+        .paypal
+        .post("/cgi-bin/webscr")
+        .body_string("cmd=_notify-validate".to_string())
+        .await
+        .map_err(|error| eyre!("PayPal IPN verification ping failed: {}", error))?;
+    ensure!(
+        res.status().is_success(),
+        "PayPal IPN verification ping - status: {}",
+        res.status()
+    );
+    info!("PayPal IPN verification OK");
+
+    if let Some(credentials) = &state.paypal_rest_credentials {
+        paypal::access_token(&state.paypal_rest, credentials, state.log_body_max_len)
+            .await
+            .map_err(|error| eyre!("PayPal REST ping failed: {}", error))?;
+        info!("PayPal REST OK");
+    }
+
+    Ok(())
 }
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surf::Client;
+use tide::{Body, StatusCode};
+
+use crate::azure_function::AzureFnLogger;
+use crate::email::EmailSender;
+use crate::sendgrid::SendGridMail;
+use crate::timing::TimedExt;
+use crate::AppState;
+
+/// A Mandrill (MailChimp Transactional) `messages/send-template` request.
+///
+/// See <https://mailchimp.com/developer/transactional/api/messages/send-using-message-template/>.
+#[derive(Debug, Serialize)]
+struct MandrillTemplateMail<'mail> {
+    key: &'mail str,
+    template_name: &'mail str,
+    template_content: Vec<MergeVar>, // required, but unused with handlebars templates
+    message: MandrillMessage<'mail>,
+}
+
+#[derive(Debug, Serialize)]
+struct MandrillMessage<'mail> {
+    to: Vec<Recipient<'mail>>,
+    from_email: &'mail str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from_name: Option<&'mail str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<Value>,
+    global_merge_vars: Vec<MergeVar>,
+    merge_language: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<&'mail str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Recipient<'mail> {
+    email: &'mail str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'mail str>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct MergeVar {
+    name: String,
+    content: Value,
+}
+
+/// Mandrill's result per recipient.
+#[derive(Debug, Deserialize)]
+struct SendResult {
+    email: String,
+    status: String,
+    reject_reason: Option<String>,
+}
+
+/// Sends via Mandrill, for chapters which use it rather than SendGrid.
+///
+/// Templates are referred to by name, and should use handlebars merge tags, like SendGrid dynamic templates.
+#[derive(Debug)]
+pub struct MandrillSender {
+    pub client: Client, // with the base url set
+    pub api_key: String,
+}
+
+impl MandrillSender {
+    /// Translate a SendGrid mail, with its template data as global merge vars.
+    fn template_mail<'mail>(
+        &'mail self,
+        mail: &'mail SendGridMail,
+    ) -> tide::Result<MandrillTemplateMail<'mail>> {
+        let mut to = vec![];
+        let mut global_merge_vars = vec![];
+        for personalization in &mail.personalizations {
+            let recipients = [
+                (&personalization.to, "to"),
+                (&personalization.cc, "cc"),
+                (&personalization.bcc, "bcc"),
+            ];
+            for (addresses, kind) in recipients {
+                to.extend(addresses.iter().map(|address| Recipient {
+                    email: &address.email,
+                    name: address.name.as_deref(),
+                    kind,
+                }));
+            }
+            if let Some(Value::Object(data)) = personalization
+                .dynamic_template_data
+                .as_ref()
+                .map(serde_json::to_value)
+                .transpose()?
+            {
+                global_merge_vars.extend(
+                    data.into_iter()
+                        .map(|(name, content)| MergeVar { name, content }),
+                );
+            }
+        }
+
+        Ok(MandrillTemplateMail {
+            key: &self.api_key,
+            template_name: &mail.template_id,
+            template_content: vec![],
+            message: MandrillMessage {
+                to,
+                from_email: &mail.from.email,
+                from_name: mail.from.name.as_deref(),
+                headers: mail
+                    .reply_to
+                    .as_ref()
+                    .map(|reply_to| serde_json::json!({ "Reply-To": reply_to.email })),
+                global_merge_vars,
+                merge_language: "handlebars",
+                tags: mail.categories.iter().map(String::as_str).collect(),
+            },
+        })
+    }
+}
+
+#[surf::utils::async_trait]
+impl EmailSender for MandrillSender {
+    async fn send(
+        &self,
+        logger: &AzureFnLogger,
+        state: &AppState,
+        mail: &SendGridMail,
+    ) -> tide::Result<()> {
+        let mut res = self
+            .client
+            .post("api/1.0/messages/send-template")
+            .body(Body::from_json(&self.template_mail(mail)?)?)
+            .timed(logger, state, "Mandrill")
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "Mandrill error: {}",
+                    state.log_body(&res.body_string().await?)
+                ),
+            ));
+        }
+
+        // Mandrill responds 200 even when it won't deliver, with a status per recipient.
+        let results: Vec<SendResult> = res.body_json().await?;
+        match results
+            .iter()
+            .find(|result| result.status == "rejected" || result.status == "invalid")
+        {
+            Some(result) => Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "Mandrill did not send to {} - status: {} - reason: {}",
+                    result.email,
+                    result.status,
+                    result.reject_reason.as_deref().unwrap_or("none")
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    async fn ping(&self) -> tide::Result<()> {
+        let res = self
+            .client
+            .post("api/1.0/users/ping2")
+            .body(Body::from_json(
+                &serde_json::json!({ "key": self.api_key }),
+            )?)
+            .await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("Mandrill ping - status: {}", res.status()),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::azure_function::detached_logger;
+    use crate::sendgrid::{EmailAddress, MembershipTemplateData};
+    use crate::test_support::{json, test_state, MockEmail, MockHttp};
+
+    fn sender(http: &MockHttp) -> MandrillSender {
+        MandrillSender {
+            client: http.client("https://mandrill.test/"),
+            api_key: "mandrill-key".to_string(),
+        }
+    }
+
+    fn mail() -> SendGridMail {
+        let mut mail = SendGridMail::template(
+            "ada@example.com",
+            "info@squamishaccess.ca",
+            "membership-check",
+            Some(MembershipTemplateData {
+                member_name: "Ada".to_string(),
+                expires: "2025-06-15".to_string(),
+                status: "active".to_string(),
+                membership_type: String::new(),
+            }),
+        );
+        mail.personalizations[0].bcc = vec![EmailAddress::new("records@squamishaccess.ca")];
+        mail.from.name = Some("Squamish Access Society".to_string());
+        mail.reply_to = Some(EmailAddress::new("board@squamishaccess.ca"));
+        mail.categories = vec!["membership-check".to_string()];
+        mail
+    }
+
+    #[test]
+    fn sendgrid_mails_are_translated() {
+        let http = MockHttp::new(|_| json(StatusCode::Ok, &json!([])));
+        let sender = sender(&http);
+        let mail = mail();
+
+        assert_eq!(
+            serde_json::to_value(sender.template_mail(&mail).expect("translated")).expect("JSON"),
+            json!({
+                "key": "mandrill-key",
+                "template_name": "membership-check",
+                "template_content": [],
+                "message": {
+                    "to": [
+                        {"email": "ada@example.com", "type": "to"},
+                        {"email": "records@squamishaccess.ca", "type": "bcc"},
+                    ],
+                    "from_email": "info@squamishaccess.ca",
+                    "from_name": "Squamish Access Society",
+                    "headers": {"Reply-To": "board@squamishaccess.ca"},
+                    "global_merge_vars": [
+                        {"name": "expires", "content": "2025-06-15"},
+                        {"name": "member_name", "content": "Ada"},
+                        {"name": "membership_type", "content": ""},
+                        {"name": "status", "content": "active"},
+                    ],
+                    "merge_language": "handlebars",
+                    "tags": ["membership-check"],
+                },
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn sends_are_posted_to_send_template() {
+        let http = MockHttp::new(|_| {
+            json(
+                StatusCode::Ok,
+                &json!([{"email": "ada@example.com", "status": "sent", "reject_reason": null}]),
+            )
+        });
+        let state = test_state(&http, &MockEmail::default());
+
+        sender(&http)
+            .send(&detached_logger("test"), &state, &mail())
+            .await
+            .expect("sent");
+
+        let sent = http.sent_to("mandrill.test");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].url.path(), "/api/1.0/messages/send-template");
+    }
+
+    #[async_std::test]
+    async fn rejected_recipients_are_an_error() {
+        let http = MockHttp::new(|_| {
+            json(
+                StatusCode::Ok,
+                &json!([{"email": "ada@example.com", "status": "rejected", "reject_reason": "hard-bounce"}]),
+            )
+        });
+        let state = test_state(&http, &MockEmail::default());
+
+        let error = sender(&http)
+            .send(&detached_logger("test"), &state, &mail())
+            .await
+            .expect_err("rejected");
+
+        assert_eq!(
+            error.to_string(),
+            "Mandrill did not send to ada@example.com - status: rejected - reason: hard-bounce"
+        );
+    }
+}
@@ -1,25 +1,70 @@
-use http_types::headers::LOCATION;
-use serde::Deserialize;
-use serde_json::json;
+use std::sync::PoisonError;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{Duration, NaiveDate};
+use encoding_rs::{Encoding, UTF_8};
+use http_types::headers::{ACCEPT, ACCEPT_LANGUAGE, LOCATION, RETRY_AFTER};
+use percent_encoding::percent_decode;
+use tide::http::mime;
 use tide::{Response, StatusCode};
+use tracing::Span;
 
 // The info! logging macro comes from crate::azure_function::logger
-use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt};
-use crate::{AppRequest, MailchimpQuery, MailchimpResponse};
+use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt, AzureFnRequestExt};
+use crate::callback::sign;
+use crate::config::{LocaleTemplates, ReloadableConfig};
+use crate::mailchimp::{
+    member_path, parse_expires, to_mailchimp_format, MailchimpMember, MailchimpQuery,
+    MembershipStatus,
+};
+use crate::sendgrid::{MembershipTemplateData, SendGridMail};
+use crate::timing::TimedExt;
+use crate::{AppRequest, AppState};
+
+/// Lets clients safely retry a membership check, see [`IDEMPOTENCY_TTL`].
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// The sender of membership check emails.
+pub(crate) const FROM_EMAIL: &str = "noreply@squamishaccess.ca";
+
+/// How long an `Idempotency-Key` is remembered for, so that a retried request doesn't send a second email.
+const IDEMPOTENCY_TTL: StdDuration = StdDuration::from_secs(10 * 60);
+
+/// How long a signed membership check result is valid for, see [`result_token`].
+const RESULT_TOKEN_TTL: i64 = 10 * 60;
+
+/// Seconds for clients to wait when MailChimp rate limits us without saying for how long.
+const MAILCHIMP_RETRY_AFTER: &str = "10";
 
 /// Check if an email is in MailChimp & when it's expiry date is, if available.
+#[tracing::instrument(name = "membership_check", skip_all, fields(email))]
 pub async fn membership_check(mut req: AppRequest) -> tide::Result<Response> {
-    let mut logger = req
-        .ext_mut::<AzureFnLogger>()
-        .expect("Must install AzureFnMiddleware")
-        .clone();
+    let mut logger = req.logger();
 
-    #[derive(Debug, Deserialize)]
-    struct Incoming {
-        email: String,
+    if !req.state().config().membership_check_enabled {
+        info!(logger, "Membership check is disabled");
+        return Ok(StatusCode::ServiceUnavailable.into());
     }
 
-    let Incoming { email } = req.body_form().await?;
+    // Only browsers (or clients which ask for it) get an inline page, if enabled.
+    let respond_html = req.state().membership_check_html
+        && req
+            .header(ACCEPT)
+            .is_some_and(|accept| accept.as_str().contains("text/html"));
+
+    // For the email's language, see `TEMPLATE_LOCALES`.
+    let accept_language = req
+        .header(ACCEPT_LANGUAGE)
+        .map(|accept_language| accept_language.as_str().to_string());
+
+    let idempotency_key = req
+        .header(IDEMPOTENCY_KEY)
+        .map(|key| key.last().as_str().to_string());
+
+    let charset = req
+        .content_type()
+        .and_then(|mime| mime.param("charset").map(|charset| charset.to_string()));
+    let email = form_field(&req.body_bytes().await?, "email", charset.as_deref());
 
     if email.is_empty() {
         let mut res: Response = StatusCode::SeeOther.into();
@@ -27,110 +72,178 @@ pub async fn membership_check(mut req: AppRequest) -> tide::Result<Response> {
         return Ok(res);
     }
 
+    Span::current().record("email", email.as_str());
     info!(logger, "Membership check - Email: {}", email);
 
     // Must be done after we take the main request body.
     //
     // An atomic reference-counted pointer to our application state, with shared http clients.
     let state = req.state();
+    let config = state.config();
+    let locale = accept_language
+        .as_deref()
+        .and_then(|accept_language| config.locale_for_accept_language(accept_language));
 
-    // The MailChimp api is a bit strange.
-    let hash = md5::compute(email.to_lowercase());
-
-    let mc_query = MailchimpQuery {
-        fields: &["FNAME", "EXPIRES"],
+    // A retried request gets the original response, without sending another email.
+    //
+    // The key is reserved before sending, so that a retry racing the original doesn't send too.
+    let reservation = match idempotency_key {
+        Some(key) => match Reservation::reserve(state, &key, &email) {
+            Ok(reservation) => Some(reservation),
+            Err(IdempotentCheck::Sent { email, token }) => {
+                info!(
+                    logger,
+                    "Idempotency-Key already used, not sending again: {}", key
+                );
+                return Ok(email_sent_response(
+                    state,
+                    &email,
+                    respond_html,
+                    token.as_deref(),
+                ));
+            }
+            Err(IdempotentCheck::InFlight) => {
+                info!(
+                    logger,
+                    "Idempotency-Key is in use by a request in progress: {}", key
+                );
+                return Ok(StatusCode::Conflict.into());
+            }
+            Err(IdempotentCheck::OtherEmail) => {
+                info!(
+                    logger,
+                    "Idempotency-Key was used for another email, refusing: {}", key
+                );
+                return Ok(StatusCode::UnprocessableEntity.into());
+            }
+        },
+        None => None,
     };
 
-    // Attempt to fetch the member to our MailChimp list.
-    let mc_path = format!("3.0/lists/{}/members/{:x}", state.mc_list_id, hash);
-    let mut mailchimp_res = state.mailchimp.get(&mc_path).query(&mc_query)?.await?;
+    // Suppressed addresses get the same response as anyone else, just without an email.
+    if config.suppressed_emails.contains(&email.to_lowercase()) {
+        info!(logger, "Email is suppressed, not sending: {}", email);
+        if let Some(reservation) = reservation {
+            reservation.complete(&email, None);
+        }
+        return Ok(email_sent_response(state, &email, respond_html, None));
+    }
+
+    // Attempt to fetch the member from our MailChimp lists, the default list first.
+    //
+    // Multi-list setups keep members who paid in another currency on that currency's list.
+    let mut mailchimp_res = get_list_member(&logger, state, &state.mc_list_id, &email).await?;
+    for mc_list_id in state.mc_list_ids().into_iter().skip(1) {
+        if mailchimp_res.status() != StatusCode::NotFound {
+            break;
+        }
+        mailchimp_res = get_list_member(&logger, state, mc_list_id, &email).await?;
+    }
+
+    // The result for the website, if `MEMBERSHIP_TOKEN_SECRET` is set.
+    let token;
 
-    match mailchimp_res.status() {
+    let mut mail = match mailchimp_res.status() {
         StatusCode::Ok => {
-            let mc_json: MailchimpResponse = mailchimp_res.body_json().await?;
+            let mc_json: MailchimpMember = mailchimp_res.body_json().await?;
 
-            let membership = if mc_json.status == "pending" || mc_json.status == "subscribed" {
-                "active"
-            } else {
-                "expired"
+            // A blank or unparseable EXPIRES is treated as expired, as we can't say otherwise.
+            let expires = match parse_expires(&mc_json.merge_fields.expires) {
+                Ok(expires) => expires,
+                Err(error) => {
+                    info!(
+                        logger,
+                        "Could not parse MailChimp EXPIRES: \"{}\" - error: {}",
+                        mc_json.merge_fields.expires,
+                        error
+                    );
+                    None
+                }
             };
+            if expires.is_none() {
+                info!(logger, "No usable EXPIRES for: {}", mc_json.email_address);
+            }
+            let active = is_membership_active(
+                mc_json.status,
+                expires,
+                state.clock.today(),
+                config.membership_grace_days,
+            );
+            let membership = if active { "active" } else { "expired" };
+            token = result_token(state, membership, expires);
 
-            let body = json!({
-                "personalizations": [{
-                    "to": [{
-                        "email": mc_json.email_address
-                    }],
-                    "dynamic_template_data": {
-                        "member_name": mc_json.merge_fields.first_name,
-                        "expires": mc_json.merge_fields.expires,
-                        "status": membership
-                    }
-                }],
-                "from": {
-                    "email": "noreply@squamishaccess.ca"
-                },
-                "to": [{
-                    "email": mc_json.email_address
-                }],
-                "template_id": state.template_membership_check
-            });
+            let mut template_data = MembershipTemplateData {
+                member_name: mc_json.merge_fields.first_name,
+                expires: expires
+                    .map_or_else(|| config.expires_unknown_text.clone(), to_mailchimp_format),
+                status: membership.to_string(),
+                membership_type: mc_json.merge_fields.membership_type,
+            };
 
-            let mut twilio_res = state.twilio.post("v3/mail/send").body(body).await?;
+            let empty_fields = template_data.empty_fields();
+            if !empty_fields.is_empty() {
+                info!(
+                    logger,
+                    "Warning: membership check email template data is empty, the email will have blanks: {}",
+                    empty_fields.join(", ")
+                );
+            }
 
-            if twilio_res.status() == StatusCode::Accepted {
-                let mut res: Response = StatusCode::SeeOther.into();
-                res.insert_header(
-                    LOCATION,
-                    "https://squamishaccess.ca/membership-check-response",
+            let truncated_fields = template_data.truncate_long_fields();
+            if !truncated_fields.is_empty() {
+                info!(
+                    logger,
+                    "Warning: membership check email template data is too long for SendGrid, truncated: {}",
+                    truncated_fields.join(", ")
                 );
-                Ok(res)
-            } else {
-                info!(logger, "Twilio error: {}", twilio_res.body_string().await?);
-                Ok(StatusCode::InternalServerError.into())
             }
+
+            SendGridMail::template(
+                &mc_json.email_address,
+                FROM_EMAIL,
+                select_template(&config, locale, active),
+                Some(template_data),
+            )
         }
         StatusCode::NotFound => {
             info!(logger, "No such member: {}", email);
+            token = result_token(state, "notfound", None);
 
-            let body = json!({
-                "personalizations": [{
-                    "to": [{
-                        "email": email
-                    }]
-                }],
-                "from": {
-                    "email": "noreply@squamishaccess.ca"
-                },
-                "to": [{
-                    "email": email
-                }],
-                "template_id": state.template_membership_notfound
-            });
-
-            let mut twilio_res = state.twilio.post("v3/mail/send").body(body).await?;
-
-            if twilio_res.status() == StatusCode::Accepted {
-                let mut res: Response = StatusCode::SeeOther.into();
-                res.insert_header(
-                    LOCATION,
-                    "https://squamishaccess.ca/membership-check-response",
-                );
-                Ok(res)
-            } else {
-                info!(logger, "Twilio error: {}", twilio_res.body_string().await?);
-                Ok(StatusCode::InternalServerError.into())
-            }
+            SendGridMail::template(
+                &email,
+                FROM_EMAIL,
+                locale
+                    .and_then(|locale| locale.membership_notfound.as_deref())
+                    .unwrap_or(&config.template_membership_notfound),
+                None,
+            )
+        }
+        StatusCode::TooManyRequests => {
+            // Rate limited, most likely from a burst of checks. Ask the client to back off rather than retrying here.
+            let retry_after = mailchimp_res
+                .header(RETRY_AFTER)
+                .map_or(MAILCHIMP_RETRY_AFTER, |retry_after| {
+                    retry_after.last().as_str()
+                })
+                .to_string();
+            info!(
+                logger,
+                "Mailchimp rate limited the membership check - Retry-After: {}", retry_after
+            );
+            let mut res: Response = StatusCode::ServiceUnavailable.into();
+            res.insert_header(RETRY_AFTER, retry_after);
+            return Ok(res);
         }
         s if s.is_client_error() => {
             info!(
                 logger,
                 "Mailchimp client error: {} - {}",
                 s,
-                mailchimp_res.body_string().await?
+                state.log_body(&mailchimp_res.body_string().await?)
             );
-            Ok(Response::builder(StatusCode::InternalServerError)
+            return Ok(Response::builder(StatusCode::InternalServerError)
                 .body("Internal Server Error: mailchimp client error")
-                .into())
+                .into());
         }
         s => {
             // Something else?
@@ -138,11 +251,1194 @@ pub async fn membership_check(mut req: AppRequest) -> tide::Result<Response> {
                 logger,
                 "Mailchimp unknown status: {} - {}",
                 s,
-                mailchimp_res.body_string().await?
+                state.log_body(&mailchimp_res.body_string().await?)
             );
-            Ok(Response::builder(StatusCode::InternalServerError)
+            return Ok(Response::builder(StatusCode::InternalServerError)
                 .body("Internal Server Error: unknown mailchimp status code")
-                .into())
+                .into());
+        }
+    };
+
+    mail.from.name = state.sendgrid_from_name.clone();
+    mail.reply_to = state.sendgrid_reply_to.clone();
+
+    if let Some(qa_redirect_email) = &config.qa_redirect_email {
+        info!(
+            logger,
+            "QA_REDIRECT_EMAIL: sending to {} instead of {}", qa_redirect_email, email
+        );
+        mail.redirect_to(qa_redirect_email);
+    }
+
+    if let Err(error) = state.email.send(&logger, state, &mail).await {
+        info!(logger, "{}", error);
+        return Ok(StatusCode::InternalServerError.into());
+    }
+
+    if let Some(reservation) = reservation {
+        reservation.complete(&email, token.clone());
+    }
+    Ok(email_sent_response(
+        state,
+        &email,
+        respond_html,
+        token.as_deref(),
+    ))
+}
+
+/// A membership check by its `Idempotency-Key`, see [`IDEMPOTENCY_TTL`].
+#[derive(Clone, Debug)]
+pub enum IdempotentCheck {
+    InFlight,
+    Sent {
+        email: String,
+        token: Option<String>, // see `result_token`
+    },
+    OtherEmail, // only from `Reservation::reserve`, for a key already used with another email
+}
+
+/// An `Idempotency-Key` reserved by a membership check in progress.
+///
+/// Released if the check fails without sending, so that the client can retry with the same key.
+#[derive(Debug)]
+struct Reservation<'state> {
+    state: &'state AppState,
+    key: (String, String), // (Idempotency-Key, lowercased email)
+}
+
+impl<'state> Reservation<'state> {
+    /// Reserve an unused `Idempotency-Key` for an email, or how far the request which used it got.
+    ///
+    /// A key is only ever good for one email, so that reusing it can't get someone else's response.
+    fn reserve(state: &'state AppState, key: &str, email: &str) -> Result<Self, IdempotentCheck> {
+        let mut keys = state
+            .idempotency_keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        keys.retain(|_, (reserved_at, _)| reserved_at.elapsed() < IDEMPOTENCY_TTL);
+        let key = (key.to_string(), email.to_lowercase());
+        if let Some((_, check)) = keys.get(&key) {
+            return Err(check.clone());
+        }
+        if keys.keys().any(|(used_key, _)| *used_key == key.0) {
+            return Err(IdempotentCheck::OtherEmail);
+        }
+        keys.insert(key.clone(), (Instant::now(), IdempotentCheck::InFlight));
+        Ok(Self { state, key })
+    }
+
+    /// Remember the response for retries.
+    fn complete(self, email: &str, token: Option<String>) {
+        self.state
+            .idempotency_keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                self.key.clone(),
+                (
+                    Instant::now(),
+                    IdempotentCheck::Sent {
+                        email: email.to_string(),
+                        token,
+                    },
+                ),
+            );
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut keys = self
+            .state
+            .idempotency_keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if matches!(keys.get(&self.key), Some((_, IdempotentCheck::InFlight))) {
+            keys.remove(&self.key);
+        }
+    }
+}
+
+/// Fetch a member from a MailChimp list, with the merge fields for the membership check email.
+async fn get_list_member(
+    logger: &AzureFnLogger,
+    state: &AppState,
+    mc_list_id: &str,
+    email: &str,
+) -> tide::Result<surf::Response> {
+    let mc_query = MailchimpQuery {
+        fields: &["FNAME", "EXPIRES", "MEMBERTYPE"],
+    };
+    let mc_path = member_path(mc_list_id, email);
+    state
+        .mailchimp
+        .get(&mc_path)
+        .query(&mc_query)?
+        .timed(logger, state, "MailChimp")
+        .await
+}
+
+/// The membership check result, signed for the website to display, if `MEMBERSHIP_TOKEN_SECRET` is set.
+///
+/// Formatted as `<status>.<expires>.<valid until>.<signature>`, where the status is `active`, `expired` or `notfound`,
+/// the expiry is `YYYY-MM-DD` or empty, the token is valid until a unix timestamp [`RESULT_TOKEN_TTL`] from now,
+/// and the signature is the lowercase hex HMAC-SHA256 of everything before it, including the trailing `.`.
+fn result_token(state: &AppState, status: &str, expires: Option<NaiveDate>) -> Option<String> {
+    let secret = state.membership_token_secret.as_deref()?;
+    let payload = format!(
+        "{}.{}.{}.",
+        status,
+        expires
+            .map(|expires| expires.to_string())
+            .unwrap_or_default(),
+        state.clock.now().timestamp() + RESULT_TOKEN_TTL
+    );
+    let signature = sign(secret, payload.as_bytes());
+    Some(payload + &signature)
+}
+
+/// A field from a urlencoded form body, or empty if it is missing.
+///
+/// Surrounding whitespace is trimmed, so a whitespace-only field counts as empty,
+/// and an email is looked up (and hashed) the same with or without it.
+///
+/// Some clients send forms in a legacy charset, such as Windows-1252, rather than UTF-8.
+/// The charset is taken from the `Content-Type`, defaulting to UTF-8, and undecodable bytes become U+FFFD rather than an error.
+fn form_field(body: &[u8], name: &str, charset: Option<&str>) -> String {
+    let encoding = charset
+        .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+        .unwrap_or(UTF_8);
+
+    body.split(|&b| b == b'&')
+        .filter_map(|pair| {
+            let (key, value) = match pair.iter().position(|&b| b == b'=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, &[][..]),
+            };
+            (key == name.as_bytes()).then_some(value)
+        })
+        .next()
+        .map(|value| {
+            let value: Vec<u8> = value
+                .iter()
+                .map(|&b| if b == b'+' { b' ' } else { b })
+                .collect();
+            let value: Vec<u8> = percent_decode(&value).collect();
+            encoding
+                .decode_without_bom_handling(&value)
+                .0
+                .trim()
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// The email template for a found member, which may differ for active & expired memberships, and by language.
+///
+/// A language's templates are preferred over the defaults, and either's active or expired template over its general one.
+fn select_template<'config>(
+    config: &'config ReloadableConfig,
+    locale: Option<&'config LocaleTemplates>,
+    active: bool,
+) -> &'config str {
+    let (locale_template, template) = if active {
+        (
+            locale.and_then(|locale| locale.membership_active.as_deref()),
+            config.template_membership_active.as_deref(),
+        )
+    } else {
+        (
+            locale.and_then(|locale| locale.membership_expired.as_deref()),
+            config.template_membership_expired.as_deref(),
+        )
+    };
+    locale_template
+        .or_else(|| locale.and_then(|locale| locale.membership_check.as_deref()))
+        .or(template)
+        .unwrap_or(&config.template_membership_check)
+}
+
+/// Whether a membership is active, which requires both an acceptable MailChimp status and an unexpired `EXPIRES`.
+///
+/// The email subscription status alone says nothing about whether the membership was paid for.
+/// Memberships stay active for `grace_days` after `EXPIRES`.
+fn is_membership_active(
+    status: MembershipStatus,
+    expires: Option<NaiveDate>,
+    today: NaiveDate,
+    grace_days: i64,
+) -> bool {
+    let status_ok = match status {
+        MembershipStatus::Subscribed
+        | MembershipStatus::Pending
+        | MembershipStatus::Transactional
+        | MembershipStatus::Archived => true,
+        MembershipStatus::Unsubscribed | MembershipStatus::Cleaned => false,
+    };
+    let expiry_ok = expires.is_some_and(|expires| expires + Duration::days(grace_days) >= today);
+
+    status_ok && expiry_ok
+}
+
+/// The response once a membership check email has been sent.
+///
+/// Either a redirect to the website (the default), or a small inline HTML confirmation page.
+/// The redirect carries the signed result as a `token` query parameter, if there is one.
+fn email_sent_response(
+    state: &AppState,
+    email: &str,
+    respond_html: bool,
+    token: Option<&str>,
+) -> Response {
+    if respond_html {
+        let body = format!(
+            include_str!("membership_check_sent.html"),
+            email = escape_html(email)
+        );
+        Response::builder(StatusCode::Ok)
+            .body(body)
+            .content_type(mime::HTML)
+            .build()
+    } else {
+        let mut res: Response = StatusCode::SeeOther.into();
+        let mut location = state.config().membership_check_redirect.clone();
+        if let Some(token) = token {
+            location.query_pairs_mut().append_pair("token", token);
+        }
+        res.insert_header(LOCATION, location.as_str());
+        res
+    }
+}
+
+/// Minimal HTML escaping for user-provided text.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::{json, Value};
+    use surf::Url;
+
+    use super::*;
+    use crate::sendgrid::{EmailAddress, MAX_TEMPLATE_VALUE_CHARS};
+    use crate::test_support::{
+        azure_server, fake_upstreams, insert_member, invocation, post, server, test_config,
+        test_state, text, MockEmail, MockHttp, LIST_ID,
+    };
+
+    fn check(email: &str, idempotency_key: &str) -> tide::http::Request {
+        let mut req = post(
+            "/Membership-Check",
+            "application/x-www-form-urlencoded",
+            &format!("email={}", email.replace('@', "%40")),
+        );
+        req.insert_header(IDEMPOTENCY_KEY, idempotency_key);
+        req
+    }
+
+    #[async_std::test]
+    async fn repeated_idempotency_keys_get_the_original_response() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+        let mut state = test_state(&http, &email);
+        state.membership_token_secret = Some("token-secret".to_string());
+        let server = server(state);
+
+        let first: tide::http::Response = server
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+        let retry: tide::http::Response = server
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        assert_eq!(email.sent().len(), 1);
+        assert_eq!(retry.status(), first.status());
+        let location = first.header(LOCATION).expect("redirect").as_str();
+        assert!(location.contains("token=active."));
+        assert_eq!(retry.header(LOCATION).expect("redirect").as_str(), location);
+    }
+
+    #[async_std::test]
+    async fn idempotency_keys_in_use_are_not_sent_twice() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let email = MockEmail::default();
+        let state = test_state(&http, &email);
+        state.idempotency_keys.lock().expect("keys").insert(
+            ("key-1".to_string(), "ada@example.com".to_string()),
+            (Instant::now(), IdempotentCheck::InFlight),
+        );
+
+        let res: tide::http::Response = server(state)
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::Conflict);
+        assert!(email.sent().is_empty());
+    }
+
+    #[async_std::test]
+    async fn idempotency_keys_are_only_good_for_one_email() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+        let server = server(test_state(&http, &email));
+
+        let first: tide::http::Response = server
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+        // The same email in another case is a retry.
+        let retry: tide::http::Response = server
+            .respond(check("Ada@Example.com", "key-1"))
+            .await
+            .expect("response");
+        let reused: tide::http::Response = server
+            .respond(check("grace@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        assert_eq!(retry.status(), first.status());
+        assert_eq!(reused.status(), StatusCode::UnprocessableEntity);
+        assert_eq!(email.sent().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn failed_checks_release_their_idempotency_key() {
+        let http = MockHttp::new(|_| text(StatusCode::InternalServerError, "down"));
+        let email = MockEmail::default();
+        let server = server(test_state(&http, &email));
+
+        let res: tide::http::Response = server
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        assert!(server
+            .state()
+            .idempotency_keys
+            .lock()
+            .expect("keys")
+            .is_empty());
+    }
+
+    #[async_std::test]
+    async fn members_are_found_on_a_currency_list() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            "list_usd",
+            "ada@example.com",
+            "subscribed",
+            json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+        let mut state = test_state(&http, &email);
+        state.mc_currency_list_ids = HashMap::from([("USD".to_string(), "list_usd".to_string())]);
+
+        let res: tide::http::Response = server(state)
+            .respond(post(
+                "/Membership-Check",
+                "application/x-www-form-urlencoded",
+                "email=ada%40example.com",
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::SeeOther);
+        let looked_in: Vec<_> = http
+            .sent_to("mailchimp.test")
+            .iter()
+            .map(|sent| sent.url.path().split('/').nth(3).map(str::to_string))
+            .collect();
+        assert_eq!(
+            looked_in,
+            [Some(LIST_ID.to_string()), Some("list_usd".to_string())]
+        );
+        let sent = email.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["template_id"], "d-check");
+    }
+
+    #[async_std::test]
+    async fn browsers_get_an_html_page_if_enabled() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.membership_check_html = true;
+        let server = server(state);
+
+        let mut req = post(
+            "/Membership-Check",
+            "application/x-www-form-urlencoded",
+            "email=%3Cada%3E%40example.com",
+        );
+        req.insert_header(ACCEPT, "text/html,application/xhtml+xml");
+        let mut res: tide::http::Response = server.respond(req).await.expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.content_type(), Some(mime::HTML));
+        let body = res.body_string().await.expect("body");
+        assert!(body.contains("&lt;ada&gt;@example.com"));
+        assert!(!body.contains("<ada>"));
+
+        // Others are still redirected.
+        let res: tide::http::Response = server
+            .respond(post(
+                "/Membership-Check",
+                "application/x-www-form-urlencoded",
+                "email=ada%40example.com",
+            ))
+            .await
+            .expect("response");
+        assert_eq!(res.status(), StatusCode::SeeOther);
+    }
+
+    #[test]
+    fn active_needs_both_a_status_and_an_unexpired_membership() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 15).expect("date");
+        let future = NaiveDate::from_ymd_opt(2024, 12, 1);
+        let past = NaiveDate::from_ymd_opt(2024, 1, 1);
+
+        let cases = [
+            (MembershipStatus::Subscribed, future, true),
+            (MembershipStatus::Subscribed, past, false),
+            (MembershipStatus::Unsubscribed, future, false),
+            (MembershipStatus::Unsubscribed, past, false),
+        ];
+        for (status, expires, active) in cases {
+            assert_eq!(
+                is_membership_active(status, expires, today, 0),
+                active,
+                "{:?} expiring {:?}",
+                status,
+                expires
+            );
+        }
+    }
+
+    #[test]
+    fn pending_members_are_active_only_until_they_expire() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 15).expect("date");
+
+        assert!(is_membership_active(
+            MembershipStatus::Pending,
+            NaiveDate::from_ymd_opt(2024, 6, 15),
+            today,
+            0
+        ));
+        assert!(!is_membership_active(
+            MembershipStatus::Pending,
+            NaiveDate::from_ymd_opt(2024, 6, 14),
+            today,
+            0
+        ));
+        assert!(!is_membership_active(
+            MembershipStatus::Cleaned,
+            NaiveDate::from_ymd_opt(2025, 6, 14),
+            today,
+            0
+        ));
+    }
+
+    #[test]
+    fn memberships_stay_active_for_the_grace_period() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 15).expect("date");
+        let expires = NaiveDate::from_ymd_opt(2024, 6, 1);
+
+        assert!(is_membership_active(
+            MembershipStatus::Subscribed,
+            expires,
+            today,
+            14
+        ));
+        assert!(!is_membership_active(
+            MembershipStatus::Subscribed,
+            expires,
+            today,
+            13
+        ));
+    }
+
+    #[async_std::test]
+    async fn members_without_an_expiry_are_told_it_is_unknown() {
+        for merge_fields in [
+            json!({"FNAME": "Ada", "EXPIRES": ""}),
+            json!({"FNAME": "Ada"}),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            insert_member(
+                &members,
+                LIST_ID,
+                "ada@example.com",
+                "subscribed",
+                merge_fields,
+            );
+            let email = MockEmail::default();
+
+            let res: tide::http::Response = server(test_state(&http, &email))
+                .respond(post(
+                    "/Membership-Check",
+                    "application/x-www-form-urlencoded",
+                    "email=ada%40example.com",
+                ))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::SeeOther);
+            let sent = email.sent();
+            assert_eq!(sent.len(), 1);
+            let data = &sent[0]["personalizations"][0]["dynamic_template_data"];
+            assert_eq!(data["status"], "expired");
+            assert_eq!(data["expires"], "unknown");
+        }
+    }
+
+    #[async_std::test]
+    async fn disabled_membership_check_is_unavailable() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let email = MockEmail::default();
+        let state = test_state(&http, &email);
+        let mut config = test_config();
+        config.membership_check_enabled = false;
+        state.set_config(config);
+
+        let res: tide::http::Response = server(state)
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert!(http.sent().is_empty());
+        assert!(email.sent().is_empty());
+    }
+
+    #[test]
+    fn templates_are_selected_by_membership_status() {
+        let mut config = test_config();
+        config.template_membership_active = None;
+        config.template_membership_expired = None;
+        assert_eq!(select_template(&config, None, true), "d-check");
+        assert_eq!(select_template(&config, None, false), "d-check");
+
+        config.template_membership_active = Some("d-active".to_string());
+        assert_eq!(select_template(&config, None, true), "d-active");
+        assert_eq!(select_template(&config, None, false), "d-check");
+
+        config.template_membership_expired = Some("d-expired".to_string());
+        assert_eq!(select_template(&config, None, true), "d-active");
+        assert_eq!(select_template(&config, None, false), "d-expired");
+    }
+
+    #[async_std::test]
+    async fn expired_members_get_the_expired_template() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"FNAME": "Ada", "EXPIRES": "2024-01-01"}),
+        );
+        let email = MockEmail::default();
+        let state = test_state(&http, &email);
+        let mut config = test_config();
+        config.template_membership_active = Some("d-active".to_string());
+        config.template_membership_expired = Some("d-expired".to_string());
+        state.set_config(config);
+
+        let res: tide::http::Response = server(state)
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::SeeOther);
+        let sent = email.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["template_id"], "d-expired");
+    }
+
+    #[async_std::test]
+    async fn suppressed_addresses_are_not_sent_email() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+        let state = test_state(&http, &email);
+        let mut config = test_config();
+        config.suppressed_emails = ["ada@example.com".to_string()].into();
+        state.set_config(config);
+
+        let res: tide::http::Response = server(state)
+            .respond(check("Ada@Example.com", "key-1"))
+            .await
+            .expect("response");
+
+        // The same response as anyone else gets.
+        assert_eq!(res.status(), StatusCode::SeeOther);
+        assert!(res.header(LOCATION).is_some());
+        assert!(email.sent().is_empty());
+        assert!(http.sent().is_empty());
+    }
+
+    #[async_std::test]
+    async fn rate_limits_ask_the_client_to_back_off() {
+        for (retry_after, expected) in [(Some("30"), "30"), (None, MAILCHIMP_RETRY_AFTER)] {
+            let http = MockHttp::new(move |_| {
+                let mut res = text(StatusCode::TooManyRequests, "slow down");
+                if let Some(retry_after) = retry_after {
+                    res.insert_header(RETRY_AFTER, retry_after);
+                }
+                res
+            });
+            let email = MockEmail::default();
+            let server = server(test_state(&http, &email));
+
+            let res: tide::http::Response = server
+                .respond(check("ada@example.com", "key-1"))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+            assert_eq!(
+                res.header(RETRY_AFTER).map(|value| value.as_str()),
+                Some(expected)
+            );
+            assert_eq!(http.sent().len(), 1);
+            assert!(email.sent().is_empty());
+            // Nothing was sent, so the client may retry with the same key.
+            assert!(server
+                .state()
+                .idempotency_keys
+                .lock()
+                .expect("keys")
+                .is_empty());
+        }
+    }
+
+    #[async_std::test]
+    async fn emails_are_redirected_to_qa_only_when_configured() {
+        for qa_redirect_email in [None, Some("qa@squamishaccess.ca")] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            insert_member(
+                &members,
+                LIST_ID,
+                "ada@example.com",
+                "subscribed",
+                json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+            );
+            let email = MockEmail::default();
+            let state = test_state(&http, &email);
+            let mut config = test_config();
+            config.qa_redirect_email = qa_redirect_email.map(str::to_string);
+            state.set_config(config);
+
+            let res: tide::http::Response = server(state)
+                .respond(check("ada@example.com", "key-1"))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::SeeOther);
+            let sent = email.sent();
+            assert_eq!(sent.len(), 1);
+            let personalization = &sent[0]["personalizations"][0];
+            assert_eq!(
+                personalization["to"],
+                json!([{"email": qa_redirect_email.unwrap_or("ada@example.com")}])
+            );
+            // Still about the real member.
+            assert_eq!(
+                personalization["dynamic_template_data"]["member_name"],
+                "Ada"
+            );
+        }
+    }
+
+    #[test]
+    fn forms_are_decoded_in_their_charset() {
+        // "josé@example.com", percent-encoded in Windows-1252.
+        let body = b"first=x&email=jos%E9%40example.com";
+        assert_eq!(
+            form_field(body, "email", Some("windows-1252")),
+            "jos\u{e9}@example.com"
+        );
+        // Unencoded too, as some clients send.
+        assert_eq!(
+            form_field(b"email=jos\xe9@example.com", "email", Some("windows-1252")),
+            "jos\u{e9}@example.com"
+        );
+        assert_eq!(
+            form_field(b"email=jos%C3%A9%40example.com", "email", None),
+            "jos\u{e9}@example.com"
+        );
+    }
+
+    #[test]
+    fn undecodable_bytes_are_replaced() {
+        assert_eq!(
+            form_field(b"email=jos%E9%40example.com", "email", None),
+            "jos\u{fffd}@example.com"
+        );
+        assert_eq!(
+            form_field(
+                b"email=+ada%40example.com+",
+                "email",
+                Some("no-such-charset")
+            ),
+            "ada@example.com"
+        );
+        assert_eq!(form_field(b"name=Ada", "email", None), "");
+    }
+
+    #[async_std::test]
+    async fn windows_1252_forms_are_checked() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let email = MockEmail::default();
+
+        let res: tide::http::Response = server(test_state(&http, &email))
+            .respond(post(
+                "/Membership-Check",
+                "application/x-www-form-urlencoded; charset=windows-1252",
+                "email=jos%E9%40example.com",
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::SeeOther);
+        let sent = email.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0]["personalizations"][0]["to"][0]["email"],
+            "jos\u{e9}@example.com"
+        );
+    }
+
+    #[async_std::test]
+    async fn members_without_a_name_are_still_sent_their_check() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+
+        let mut res: tide::http::Response = azure_server(test_state(&http, &email))
+            .respond(invocation(
+                "/Membership-Check",
+                "POST",
+                json!({"Content-Type": ["application/x-www-form-urlencoded"]}),
+                "email=ada%40example.com",
+            ))
+            .await
+            .expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 303);
+        assert!(out["Logs"].as_array().expect("logs").iter().any(|line| line
+            .as_str()
+            .is_some_and(
+                |line| line.contains("template data is empty") && line.ends_with(": member_name")
+            )));
+        let sent = email.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0]["personalizations"][0]["dynamic_template_data"]["member_name"],
+            ""
+        );
+    }
+
+    #[async_std::test]
+    async fn membership_checks_work_when_read_only() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+        let state = test_state(&http, &email);
+        let mut config = test_config();
+        config.read_only = true;
+        state.set_config(config);
+
+        let res: tide::http::Response = server(state)
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::SeeOther);
+        assert_eq!(email.sent().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn from_name_and_reply_to_are_sent_only_when_configured() {
+        for configured in [false, true] {
+            let (http, _) = fake_upstreams("VERIFIED");
+            let email = MockEmail::default();
+            let mut state = test_state(&http, &email);
+            if configured {
+                state.sendgrid_from_name = Some("Squamish Access Society".to_string());
+                state.sendgrid_reply_to = Some(EmailAddress {
+                    email: "board@squamishaccess.ca".to_string(),
+                    name: Some("SAS Board".to_string()),
+                });
+            }
+
+            let res: tide::http::Response = server(state)
+                .respond(check("ada@example.com", "key-1"))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::SeeOther);
+            let sent = email.sent();
+            if configured {
+                assert_eq!(
+                    sent[0]["from"],
+                    json!({"email": FROM_EMAIL, "name": "Squamish Access Society"})
+                );
+                assert_eq!(
+                    sent[0]["reply_to"],
+                    json!({"email": "board@squamishaccess.ca", "name": "SAS Board"})
+                );
+            } else {
+                assert_eq!(sent[0]["from"], json!({"email": FROM_EMAIL}));
+                assert!(sent[0].get("reply_to").is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn form_fields_are_trimmed() {
+        assert_eq!(form_field(b"email=", "email", None), "");
+        assert_eq!(form_field(b"email=+++", "email", None), "");
+        assert_eq!(form_field(b"email=%20%09%20", "email", None), "");
+        assert_eq!(form_field(b"email=+a%40b.com+", "email", None), "a@b.com");
+    }
+
+    #[async_std::test]
+    async fn blank_emails_are_sent_to_the_membership_page() {
+        for body in ["email=", "email=+++"] {
+            let (http, _) = fake_upstreams("VERIFIED");
+            let email = MockEmail::default();
+
+            let res: tide::http::Response = server(test_state(&http, &email))
+                .respond(post(
+                    "/Membership-Check",
+                    "application/x-www-form-urlencoded",
+                    body,
+                ))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::SeeOther);
+            assert_eq!(
+                res.header(LOCATION).expect("redirect").as_str(),
+                "https://squamishaccess.ca/membership"
+            );
+            assert!(http.sent().is_empty(), "{}", body);
+            assert!(email.sent().is_empty());
+        }
+    }
+
+    #[async_std::test]
+    async fn emails_with_surrounding_whitespace_are_looked_up_trimmed() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "a@b.com",
+            "subscribed",
+            json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+
+        let res: tide::http::Response = server(test_state(&http, &email))
+            .respond(post(
+                "/Membership-Check",
+                "application/x-www-form-urlencoded",
+                "email=+a%40b.com+",
+            ))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::SeeOther);
+        let sent = email.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["personalizations"][0]["to"][0]["email"], "a@b.com");
+        assert_eq!(
+            sent[0]["personalizations"][0]["dynamic_template_data"]["member_name"],
+            "Ada"
+        );
+    }
+
+    /// Splits a result token into its signed payload & signature, as the website would.
+    fn split_token(token: &str) -> (&str, &str) {
+        let at = token.rfind('.').expect("signature") + 1;
+        token.split_at(at)
+    }
+
+    #[test]
+    fn result_tokens_are_signed() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        assert_eq!(result_token(&state, "active", None), None);
+
+        state.membership_token_secret = Some("token-secret".to_string());
+        let expires = NaiveDate::from_ymd_opt(2025, 1, 1);
+        let token = result_token(&state, "active", expires).expect("token");
+
+        let (payload, signature) = split_token(&token);
+        // 2024-06-15T12:00:00Z, plus ten minutes.
+        assert_eq!(payload, "active.2025-01-01.1718453400.");
+        assert_eq!(signature, sign("token-secret", payload.as_bytes()));
+        assert_eq!(signature.len(), 64);
+        let token = result_token(&state, "notfound", None).expect("token");
+        assert!(token.starts_with("notfound..1718453400."));
+    }
+
+    #[test]
+    fn tampered_result_tokens_do_not_verify() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.membership_token_secret = Some("token-secret".to_string());
+        let token =
+            result_token(&state, "expired", NaiveDate::from_ymd_opt(2024, 1, 1)).expect("token");
+        let (_, signature) = split_token(&token);
+
+        for tampered in [
+            token.replacen("expired", "active", 1),
+            token.replacen("2024-01-01", "2099-01-01", 1),
+            token.replacen("1718453400", "9999999999", 1),
+        ] {
+            let (payload, _) = split_token(&tampered);
+            assert_ne!(sign("token-secret", payload.as_bytes()), signature);
+        }
+        let (payload, _) = split_token(&token);
+        assert_ne!(sign("other-secret", payload.as_bytes()), signature);
+    }
+
+    #[async_std::test]
+    async fn redirects_carry_the_result_token_only_if_configured() {
+        for secret in [None, Some("token-secret")] {
+            let (http, _) = fake_upstreams("VERIFIED");
+            let mut state = test_state(&http, &MockEmail::default());
+            state.membership_token_secret = secret.map(str::to_string);
+
+            let res: tide::http::Response = server(state)
+                .respond(check("ada@example.com", "key-1"))
+                .await
+                .expect("response");
+
+            let location = res.header(LOCATION).expect("redirect").as_str();
+            match secret {
+                Some(_) => assert!(location.contains("?token=notfound..1718453400.")),
+                None => assert!(!location.contains("token="), "{}", location),
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn over_long_member_names_are_truncated() {
+        let (http, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"FNAME": "Ada".repeat(1000), "EXPIRES": "2025-01-01"}),
+        );
+        let email = MockEmail::default();
+
+        let mut res: tide::http::Response = azure_server(test_state(&http, &email))
+            .respond(invocation(
+                "/Membership-Check",
+                "POST",
+                json!({"Content-Type": ["application/x-www-form-urlencoded"]}),
+                "email=ada%40example.com",
+            ))
+            .await
+            .expect("response");
+
+        let out: Value = res.body_json().await.expect("envelope");
+        assert_eq!(out["Outputs"]["res"]["statusCode"], 303);
+        assert!(out["Logs"].as_array().expect("logs").iter().any(|line| line
+            .as_str()
+            .is_some_and(|line| line.contains("too long for SendGrid")
+                && line.ends_with("truncated: member_name"))));
+        let sent = email.sent();
+        let member_name = sent[0]["personalizations"][0]["dynamic_template_data"]["member_name"]
+            .as_str()
+            .expect("member_name");
+        assert_eq!(member_name.chars().count(), MAX_TEMPLATE_VALUE_CHARS);
+        assert!(member_name.starts_with("AdaAda"));
+    }
+
+    #[async_std::test]
+    async fn the_configured_redirect_keeps_its_query() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        let mut config = test_config();
+        config.membership_check_redirect =
+            Url::parse("https://squamishaccess.ca/checked?lang=fr").expect("url");
+        state.set_config(config);
+        state.membership_token_secret = Some("token-secret".to_string());
+
+        let res: tide::http::Response = server(state)
+            .respond(check("ada@example.com", "key-1"))
+            .await
+            .expect("response");
+
+        let location = res.header(LOCATION).expect("redirect").as_str();
+        assert!(
+            location.starts_with("https://squamishaccess.ca/checked?lang=fr&token=notfound."),
+            "{}",
+            location
+        );
+    }
+
+    #[test]
+    fn language_templates_are_preferred_over_the_defaults() {
+        let mut config = test_config();
+        config.template_membership_active = Some("d-active".to_string());
+        let french = LocaleTemplates {
+            membership_check: Some("d-fr-check".to_string()),
+            membership_expired: Some("d-fr-expired".to_string()),
+            ..LocaleTemplates::default()
+        };
+
+        assert_eq!(select_template(&config, Some(&french), true), "d-fr-check");
+        assert_eq!(
+            select_template(&config, Some(&french), false),
+            "d-fr-expired"
+        );
+        let english = LocaleTemplates::default();
+        assert_eq!(select_template(&config, Some(&english), true), "d-active");
+        assert_eq!(select_template(&config, Some(&english), false), "d-check");
+    }
+
+    #[async_std::test]
+    async fn emails_are_in_the_preferred_language() {
+        for (accept_language, found, template) in [
+            (None, true, "d-check"),
+            (Some("fr-CA,fr;q=0.9,en;q=0.8"), true, "d-fr-check"),
+            (Some("fr-CA"), false, "d-fr-notfound"),
+            (Some("en-CA,fr;q=0.5"), true, "d-check"),
+            (Some("de"), false, "d-notfound"),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            if found {
+                insert_member(
+                    &members,
+                    LIST_ID,
+                    "ada@example.com",
+                    "subscribed",
+                    json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}),
+                );
+            }
+            let email = MockEmail::default();
+            let state = test_state(&http, &email);
+            let mut config = test_config();
+            config.template_locales = serde_json::from_value(json!({
+                "fr": {"membership_check": "d-fr-check", "membership_notfound": "d-fr-notfound"},
+                "en": {},
+            }))
+            .expect("TEMPLATE_LOCALES");
+            state.set_config(config);
+            let mut req = check("ada@example.com", "key-1");
+            if let Some(accept_language) = accept_language {
+                req.insert_header(ACCEPT_LANGUAGE, accept_language);
+            }
+
+            let res: tide::http::Response = server(state).respond(req).await.expect("response");
+
+            assert_eq!(res.status(), StatusCode::SeeOther);
+            assert_eq!(
+                email.sent()[0]["template_id"],
+                template,
+                "{:?}",
+                accept_language
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn the_membership_type_is_in_the_template_data() {
+        for (merge_fields, membership_type) in [
+            (
+                json!({"FNAME": "Ada", "EXPIRES": "2025-01-01", "MEMBERTYPE": "family"}),
+                "family",
+            ),
+            (json!({"FNAME": "Ada", "EXPIRES": "2025-01-01"}), ""),
+        ] {
+            let (http, members) = fake_upstreams("VERIFIED");
+            insert_member(
+                &members,
+                LIST_ID,
+                "ada@example.com",
+                "subscribed",
+                merge_fields,
+            );
+            let email = MockEmail::default();
+            let state = test_state(&http, &email);
+
+            let mut res: tide::http::Response = azure_server(state)
+                .respond(invocation(
+                    "/Membership-Check",
+                    "POST",
+                    json!({"Content-Type": ["application/x-www-form-urlencoded"]}),
+                    "email=ada%40example.com",
+                ))
+                .await
+                .expect("response");
+
+            let out: Value = res.body_json().await.expect("envelope");
+            assert_eq!(out["Outputs"]["res"]["statusCode"], 303);
+            let data = &email.sent()[0]["personalizations"][0]["dynamic_template_data"];
+            assert_eq!(data["membership_type"], membership_type);
+            assert_eq!(data["status"], "active");
+            // Members without a type aren't warned about, unlike those without a name.
+            assert!(!out["Logs"]
+                .as_array()
+                .expect("logs")
+                .iter()
+                .any(|line| line.as_str().is_some_and(|line| line.contains("empty"))));
+            let lookup = &http.sent_to("mailchimp.test")[0];
+            assert!(lookup
+                .url
+                .query_pairs()
+                .any(|(key, field)| key.starts_with("fields") && field == "MEMBERTYPE"));
         }
     }
 }
@@ -0,0 +1,330 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use http_types::auth::BasicAuth;
+use http_types::headers::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+use surf::Client;
+use tide::http::mime;
+use tide::StatusCode;
+
+use crate::truncate_body;
+
+/// The longest date range the PayPal transaction search API accepts per request.
+const MAX_SEARCH_DAYS: i64 = 31;
+
+/// Credentials for the PayPal REST API, which IPNs themselves do not need.
+pub struct PaypalRestCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl fmt::Debug for PaypalRestCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PaypalRestCredentials")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"(redacted)")
+            .finish()
+    }
+}
+
+/// How IPNs are verified with PayPal, selected by `IPN_VERIFY_METHOD`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpnVerifyMethod {
+    /// Posting the IPN back with `cmd=_notify-validate`. The default.
+    Postback,
+    /// Finding the IPN's transaction with the REST api, which needs REST credentials.
+    Rest,
+}
+
+impl FromStr for IpnVerifyMethod {
+    type Err = ();
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method {
+            "postback" => Ok(Self::Postback),
+            "rest" => Ok(Self::Rest),
+            _ => Err(()),
+        }
+    }
+}
+
+/// PayPal's answer to verifying an IPN.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IpnVerification {
+    Verified,
+    Invalid,
+    Unexpected(String), // any other response body
+}
+
+impl IpnVerification {
+    /// From the body of a verification response, which is `VERIFIED` or `INVALID`.
+    pub fn from_body(body: &str) -> Self {
+        match body {
+            "VERIFIED" => Self::Verified,
+            "INVALID" => Self::Invalid,
+            unexpected => Self::Unexpected(unexpected.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for IpnVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Verified => f.write_str("VERIFIED"),
+            Self::Invalid => f.write_str("INVALID"),
+            Self::Unexpected(body) => f.write_str(body),
+        }
+    }
+}
+
+/// A PayPal transaction, from the transaction search API.
+///
+/// A successful incoming payment, unless found by [`find_transaction`], which may be pending or a refund.
+#[derive(Clone, Debug, Serialize)]
+pub struct PaypalPayment {
+    pub transaction_id: String,
+    pub email: String,
+    pub date: Option<String>,
+    pub amount: String,
+    pub currency: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchQuery<'dates> {
+    start_date: &'dates str,
+    end_date: &'dates str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction_id: Option<&'dates str>,
+    fields: &'static str,
+    page_size: u32,
+    page: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    transaction_details: Vec<TransactionDetail>,
+    #[serde(default)]
+    total_pages: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransactionDetail {
+    transaction_info: TransactionInfo,
+    #[serde(default)]
+    payer_info: PayerInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransactionInfo {
+    transaction_id: String,
+    transaction_status: Option<String>,
+    transaction_initiation_date: Option<String>,
+    transaction_amount: Option<Money>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Money {
+    currency_code: String,
+    value: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PayerInfo {
+    email_address: Option<String>,
+}
+
+/// Get an OAuth access token for the PayPal REST API.
+pub async fn access_token(
+    client: &Client,
+    credentials: &PaypalRestCredentials,
+    log_body_max_len: usize,
+) -> tide::Result<String> {
+    let auth = BasicAuth::new(&credentials.client_id, &credentials.client_secret);
+    let mut res = client
+        .post("v1/oauth2/token")
+        .header(auth.name(), auth.value())
+        .content_type(mime::FORM)
+        .body_string("grant_type=client_credentials".to_string())
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            format!(
+                "PayPal OAuth token request failed - status: {} - body: \"{}\"",
+                res.status(),
+                truncate_body(&res.body_string().await?, log_body_max_len)
+            ),
+        ));
+    }
+
+    let TokenResponse { access_token } = res.body_json().await?;
+    Ok(access_token)
+}
+
+/// Search for successful incoming payments between `start` and `end`.
+///
+/// Longer ranges than PayPal allows per request are split up, and all pages are fetched.
+pub async fn search_payments(
+    client: &Client,
+    access_token: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    log_body_max_len: usize,
+) -> tide::Result<Vec<PaypalPayment>> {
+    let transactions = search(client, access_token, start, end, None, log_body_max_len).await?;
+    Ok(transactions.into_iter().filter_map(payment).collect())
+}
+
+/// Find a transaction by its id, made between `start` and `end`, whatever its status or sign.
+///
+/// For verifying IPNs, which are also sent for pending payments & refunds.
+pub async fn find_transaction(
+    client: &Client,
+    access_token: &str,
+    transaction_id: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    log_body_max_len: usize,
+) -> tide::Result<Option<PaypalPayment>> {
+    let transactions = search(
+        client,
+        access_token,
+        start,
+        end,
+        Some(transaction_id),
+        log_body_max_len,
+    )
+    .await?;
+    Ok(transactions
+        .into_iter()
+        .filter_map(transaction)
+        .find(|transaction| transaction.transaction_id == transaction_id))
+}
+
+async fn search(
+    client: &Client,
+    access_token: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    transaction_id: Option<&str>,
+    log_body_max_len: usize,
+) -> tide::Result<Vec<TransactionDetail>> {
+    let mut transactions = vec![];
+
+    let mut window_start = start;
+    while window_start < end {
+        let window_end = (window_start + Duration::days(MAX_SEARCH_DAYS)).min(end);
+        let start_date = window_start.format("%Y-%m-%dT%H:%M:%S%z").to_string();
+        let end_date = window_end.format("%Y-%m-%dT%H:%M:%S%z").to_string();
+
+        let mut page = 1;
+        loop {
+            let query = SearchQuery {
+                start_date: &start_date,
+                end_date: &end_date,
+                transaction_id,
+                fields: "transaction_info,payer_info",
+                page_size: 500,
+                page,
+            };
+            let mut res = client
+                .get("v1/reporting/transactions")
+                .header(AUTHORIZATION, format!("Bearer {}", access_token))
+                .query(&query)?
+                .await?;
+
+            if !res.status().is_success() {
+                return Err(tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!(
+                        "PayPal transaction search failed - status: {} - body: \"{}\"",
+                        res.status(),
+                        truncate_body(&res.body_string().await?, log_body_max_len)
+                    ),
+                ));
+            }
+
+            let search: SearchResponse = res.body_json().await?;
+            transactions.extend(search.transaction_details);
+
+            if page >= search.total_pages {
+                break;
+            }
+            page += 1;
+        }
+
+        window_start = window_end;
+    }
+
+    Ok(transactions)
+}
+
+/// Successful incoming payments only, i.e. not refunds, fees, or pending transactions.
+fn payment(detail: TransactionDetail) -> Option<PaypalPayment> {
+    let info = &detail.transaction_info;
+    if info.transaction_status.as_deref() != Some("S")
+        || info
+            .transaction_amount
+            .as_ref()
+            .is_none_or(|amount| amount.value.starts_with('-'))
+    {
+        return None;
+    }
+    transaction(detail)
+}
+
+/// Any transaction with an amount & a payer.
+fn transaction(detail: TransactionDetail) -> Option<PaypalPayment> {
+    let info = detail.transaction_info;
+    let amount = info.transaction_amount?;
+
+    Some(PaypalPayment {
+        transaction_id: info.transaction_id,
+        email: detail.payer_info.email_address?,
+        date: info.transaction_initiation_date,
+        amount: amount.value,
+        currency: amount.currency_code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verification_bodies_are_typed() {
+        assert_eq!(
+            IpnVerification::from_body("VERIFIED"),
+            IpnVerification::Verified
+        );
+        assert_eq!(
+            IpnVerification::from_body("INVALID"),
+            IpnVerification::Invalid
+        );
+        // Only exact answers count, as PayPal documents.
+        assert_eq!(
+            IpnVerification::from_body("verified"),
+            IpnVerification::Unexpected("verified".to_string())
+        );
+        assert_eq!(
+            IpnVerification::from_body(""),
+            IpnVerification::Unexpected(String::new())
+        );
+    }
+
+    #[test]
+    fn verifications_display_as_paypal_sent_them() {
+        for body in ["VERIFIED", "INVALID", "<html>maintenance</html>"] {
+            assert_eq!(IpnVerification::from_body(body).to_string(), body);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use tide::{Body, Response, StatusCode};
+
+// The info! logging macro comes from crate::azure_function::logger
+use crate::admin::check_admin_secret;
+use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt, AzureFnRequestExt};
+use crate::mailchimp::{get_member, parse_expires, MailchimpMember, MailchimpQuery};
+use crate::paypal::{self, PaypalPayment, PaypalRestCredentials};
+use crate::{AppRequest, AppState};
+
+/// Why a PayPal payment has no matching valid membership.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyReason {
+    /// The payer is not in MailChimp at all.
+    NotInMailchimp,
+    /// The payer has a blank or unparseable `EXPIRES`.
+    NoExpiry,
+    /// The payer's `EXPIRES` has passed.
+    Expired,
+}
+
+/// The longest date range for `Admin-Reconcile`, as each payer is looked up in MailChimp.
+const MAX_RECONCILE_DAYS: i64 = 366;
+
+/// A PayPal payment without a matching valid membership.
+#[derive(Clone, Debug, Serialize)]
+pub struct Discrepancy {
+    #[serde(flatten)]
+    pub payment: PaypalPayment,
+    pub reason: DiscrepancyReason,
+}
+
+/// Timer triggered: log payers from the lookback window who paid, but have no valid membership in MailChimp.
+///
+/// This is a safety net for silently lost IPNs.
+pub async fn reconciliation(req: AppRequest) -> tide::Result<Response> {
+    let mut logger = req.logger();
+
+    let state = req.state();
+
+    let Some(credentials) = &state.paypal_rest_credentials else {
+        info!(
+            logger,
+            "Reconciliation: skipped, PayPal REST credentials are not configured"
+        );
+        return Ok(StatusCode::Ok.into());
+    };
+
+    let end = state.clock.now();
+    let start = end - Duration::days(state.config().reconciliation_lookback_days);
+
+    let discrepancies = reconcile(&mut logger, state, credentials, start, end).await?;
+
+    for discrepancy in &discrepancies {
+        info!(
+            logger,
+            "Reconciliation: paid but no valid membership ({:?}) - txn: \"{}\" - email: {} - date: {}",
+            discrepancy.reason,
+            discrepancy.payment.transaction_id,
+            discrepancy.payment.email,
+            discrepancy.payment.date.as_deref().unwrap_or("(none)")
+        );
+    }
+    info!(
+        logger,
+        "Reconciliation: {} discrepancies since {}",
+        discrepancies.len(),
+        start
+    );
+
+    Ok(StatusCode::Ok.into())
+}
+
+/// On demand: report payers within a date range who paid, but have no valid membership in MailChimp, as JSON.
+///
+/// Takes `{ "start": "YYYY-MM-DD", "end": "YYYY-MM-DD" }`, both inclusive, with `end` defaulting to today.
+pub async fn admin_reconcile(mut req: AppRequest) -> tide::Result<Response> {
+    let mut logger = req.logger();
+
+    check_admin_secret(&req)?;
+
+    #[derive(Debug, Deserialize)]
+    struct Incoming {
+        start: NaiveDate,
+        end: Option<NaiveDate>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct Outgoing {
+        start: NaiveDate,
+        end: NaiveDate,
+        discrepancies: Vec<Discrepancy>,
+    }
+
+    let Incoming { start, end } = req.body_json().await?;
+
+    let state = req.state();
+
+    let Some(credentials) = &state.paypal_rest_credentials else {
+        return Err(tide::Error::from_str(
+            StatusCode::NotImplemented,
+            "Admin reconcile: PayPal REST credentials are not configured",
+        ));
+    };
+
+    let end = end.unwrap_or_else(|| state.clock.today());
+    if start > end
+        || start > state.clock.today()
+        || end - start >= Duration::days(MAX_RECONCILE_DAYS)
+    {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!(
+                "Admin reconcile: start must be no later than end or today, and the range at most {} days",
+                MAX_RECONCILE_DAYS
+            ),
+        ));
+    }
+
+    // Whole days, but no later than now, which PayPal's search requires.
+    let start_time = start.and_time(NaiveTime::MIN).and_utc();
+    let end_time = (end + Duration::days(1))
+        .and_time(NaiveTime::MIN)
+        .and_utc()
+        .min(state.clock.now());
+
+    let discrepancies = reconcile(&mut logger, state, credentials, start_time, end_time).await?;
+
+    info!(
+        logger,
+        "Admin reconcile: {} discrepancies from {} to {}",
+        discrepancies.len(),
+        start,
+        end
+    );
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&Outgoing {
+            start,
+            end,
+            discrepancies,
+        })?)
+        .into())
+}
+
+/// Compare PayPal payments between `start` and `end` against MailChimp membership records.
+pub(crate) async fn reconcile(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+    credentials: &PaypalRestCredentials,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> tide::Result<Vec<Discrepancy>> {
+    let access_token =
+        paypal::access_token(&state.paypal_rest, credentials, state.log_body_max_len).await?;
+    let mut payments = paypal::search_payments(
+        &state.paypal_rest,
+        &access_token,
+        start,
+        end,
+        state.log_body_max_len,
+    )
+    .await?;
+
+    info!(
+        logger,
+        "Reconciliation: {} PayPal payments between {} and {}",
+        payments.len(),
+        start,
+        end
+    );
+
+    // Donations & other payments too small for a membership never got one, so aren't discrepancies.
+    let all_payments = payments.len();
+    payments.retain(|payment| is_membership_payment(state, payment));
+    if payments.len() < all_payments {
+        info!(
+            logger,
+            "Reconciliation: skipped {} payments below the membership minimum",
+            all_payments - payments.len()
+        );
+    }
+
+    let mc_query = MailchimpQuery {
+        fields: &["EXPIRES"],
+    };
+
+    let mut members = HashMap::new();
+    for payment in &payments {
+        let email = payment.email.to_lowercase();
+        if members.contains_key(&email) {
+            continue;
+        }
+        let mc_list_id = state.mc_list_id_for(Some(&payment.currency));
+        let member = get_member(
+            &state.mailchimp,
+            mc_list_id,
+            &email,
+            &mc_query,
+            state.log_body_max_len,
+        )
+        .await?;
+        members.insert(email, member);
+    }
+
+    Ok(find_discrepancies(payments, &members, state.clock.today()))
+}
+
+/// Whether a payment is enough for a membership, as the IPN handler would judge it.
+///
+/// The transaction search has no exchange rate, so foreign payments are held to their own currency's minimum,
+/// as an IPN without one is.
+fn is_membership_payment(state: &AppState, payment: &PaypalPayment) -> bool {
+    payment
+        .amount
+        .parse()
+        .is_ok_and(|amount| state.meets_min_amount(amount, Some(&payment.currency), None))
+}
+
+/// Payments whose payer is missing from MailChimp, or whose membership is not valid as of `today`.
+///
+/// `members` is keyed by lowercase email.
+fn find_discrepancies(
+    payments: Vec<PaypalPayment>,
+    members: &HashMap<String, Option<MailchimpMember>>,
+    today: NaiveDate,
+) -> Vec<Discrepancy> {
+    payments
+        .into_iter()
+        .filter_map(|payment| {
+            let member = members
+                .get(&payment.email.to_lowercase())
+                .and_then(Option::as_ref);
+            let reason = match member {
+                None => DiscrepancyReason::NotInMailchimp,
+                Some(member) => match parse_expires(&member.merge_fields.expires) {
+                    Ok(Some(expires)) if expires >= today => return None,
+                    Ok(Some(_)) => DiscrepancyReason::Expired,
+                    Ok(None) | Err(_) => DiscrepancyReason::NoExpiry,
+                },
+            };
+            Some(Discrepancy { payment, reason })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use http_types::headers::AUTHORIZATION;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::azure_function::detached_logger;
+    use crate::test_support::{
+        fake_upstreams, insert_member, json, post, server, test_config, test_now, test_state,
+        MockEmail, MockHttp, LIST_ID,
+    };
+
+    fn paid(amount: &str, currency: &str) -> PaypalPayment {
+        PaypalPayment {
+            transaction_id: "TXN1".to_string(),
+            email: "ada@example.com".to_string(),
+            date: Some("2024-06-01T12:00:00+0000".to_string()),
+            amount: amount.to_string(),
+            currency: currency.to_string(),
+        }
+    }
+
+    #[test]
+    fn payments_below_the_minimum_are_not_memberships() {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.base_currency = Some("CAD".to_string());
+        let mut config = test_config();
+        config.min_amounts = HashMap::from([("USD".to_string(), 8.0)]);
+        state.set_config(config);
+
+        assert!(is_membership_payment(&state, &paid("10.00", "CAD")));
+        assert!(!is_membership_payment(&state, &paid("5.00", "CAD")));
+        // Without an exchange rate, foreign payments are held to their own currency's minimum.
+        assert!(is_membership_payment(&state, &paid("8.00", "USD")));
+        assert!(!is_membership_payment(&state, &paid("7.00", "USD")));
+        assert!(!is_membership_payment(&state, &paid("ten", "CAD")));
+    }
+
+    fn transaction(txn_id: &str, email: &str, amount: &str) -> Value {
+        json!({
+            "transaction_info": {
+                "transaction_id": txn_id,
+                "transaction_status": "S",
+                "transaction_initiation_date": "2024-06-10T12:00:00+0000",
+                "transaction_amount": {"currency_code": "CAD", "value": amount},
+            },
+            "payer_info": {"email_address": email},
+        })
+    }
+
+    /// PayPal's REST api, with `transactions` as the whole search result, and anything else to `mailchimp`.
+    fn transaction_search(mailchimp: MockHttp, transactions: Vec<Value>) -> MockHttp {
+        MockHttp::new(move |sent| match sent.url.path() {
+            "/v1/oauth2/token" => json(StatusCode::Ok, &json!({"access_token": "token"})),
+            "/v1/reporting/transactions" => json(
+                StatusCode::Ok,
+                &json!({"transaction_details": transactions, "total_pages": 1}),
+            ),
+            _ => mailchimp.respond(sent),
+        })
+    }
+
+    fn credentials() -> PaypalRestCredentials {
+        PaypalRestCredentials {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+        }
+    }
+
+    #[async_std::test]
+    async fn payers_without_a_valid_membership_are_flagged() {
+        let (mailchimp, members) = fake_upstreams("VERIFIED");
+        for (email, merge_fields) in [
+            ("ada@example.com", json!({"EXPIRES": "2025-01-01"})),
+            ("bob@example.com", json!({"EXPIRES": "2024-01-01"})),
+            ("dee@example.com", json!({"EXPIRES": ""})),
+        ] {
+            insert_member(&members, LIST_ID, email, "subscribed", merge_fields);
+        }
+        let http = transaction_search(
+            mailchimp,
+            vec![
+                transaction("TXN1", "Ada@example.com", "20.00"),
+                transaction("TXN2", "bob@example.com", "20.00"),
+                transaction("TXN3", "cy@example.com", "20.00"),
+                transaction("TXN4", "dee@example.com", "20.00"),
+                // A donation, and a refund.
+                transaction("TXN5", "eve@example.com", "2.00"),
+                transaction("TXN6", "fay@example.com", "-20.00"),
+            ],
+        );
+        let state = test_state(&http, &MockEmail::default());
+        let end = test_now();
+
+        let discrepancies = reconcile(
+            &mut detached_logger("test"),
+            &state,
+            &credentials(),
+            end - Duration::days(7),
+            end,
+        )
+        .await
+        .expect("reconciled");
+
+        let flagged: Vec<_> = discrepancies
+            .iter()
+            .map(|discrepancy| {
+                (
+                    discrepancy.payment.transaction_id.as_str(),
+                    discrepancy.reason,
+                )
+            })
+            .collect();
+        assert_eq!(
+            flagged,
+            [
+                ("TXN2", DiscrepancyReason::Expired),
+                ("TXN3", DiscrepancyReason::NotInMailchimp),
+                ("TXN4", DiscrepancyReason::NoExpiry),
+            ]
+        );
+    }
+
+    #[test]
+    fn memberships_expiring_today_are_still_valid() {
+        let member: MailchimpMember = serde_json::from_value(json!({
+            "email_address": "ada@example.com",
+            "status": "subscribed",
+            "merge_fields": {"EXPIRES": "2024-06-15"},
+        }))
+        .expect("member");
+        let members = HashMap::from([("ada@example.com".to_string(), Some(member))]);
+        let today = NaiveDate::from_ymd_opt(2024, 6, 15).expect("date");
+
+        assert!(find_discrepancies(vec![paid("20.00", "CAD")], &members, today).is_empty());
+        let tomorrow = today + Duration::days(1);
+        assert_eq!(
+            find_discrepancies(vec![paid("20.00", "CAD")], &members, tomorrow)[0].reason,
+            DiscrepancyReason::Expired
+        );
+    }
+
+    fn admin_reconcile_request(body: Value) -> tide::http::Request {
+        let mut req = post("/Admin-Reconcile", "application/json", &body.to_string());
+        req.insert_header(AUTHORIZATION, "Bearer admin-secret");
+        req
+    }
+
+    #[async_std::test]
+    async fn admin_reconcile_responds_with_the_discrepancies() {
+        let (mailchimp, members) = fake_upstreams("VERIFIED");
+        insert_member(
+            &members,
+            LIST_ID,
+            "ada@example.com",
+            "subscribed",
+            json!({"EXPIRES": "2025-01-01"}),
+        );
+        let http = transaction_search(
+            mailchimp,
+            vec![
+                transaction("TXN1", "ada@example.com", "20.00"),
+                transaction("TXN2", "bob@example.com", "20.00"),
+            ],
+        );
+        let mut state = test_state(&http, &MockEmail::default());
+        state.paypal_rest_credentials = Some(credentials());
+
+        let mut res: tide::http::Response = server(state)
+            .respond(admin_reconcile_request(json!({"start": "2024-06-01"})))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: Value = res.body_json().await.expect("JSON");
+        assert_eq!(
+            body,
+            json!({
+                "start": "2024-06-01",
+                "end": "2024-06-15",
+                "discrepancies": [{
+                    "transaction_id": "TXN2",
+                    "email": "bob@example.com",
+                    "date": "2024-06-10T12:00:00+0000",
+                    "amount": "20.00",
+                    "currency": "CAD",
+                    "reason": "not_in_mailchimp",
+                }],
+            })
+        );
+        // From the start of the first day, to now rather than the end of today.
+        let search = &http
+            .sent()
+            .into_iter()
+            .find(|sent| sent.url.path() == "/v1/reporting/transactions")
+            .expect("search");
+        let query: HashMap<_, _> = search.url.query_pairs().into_owned().collect();
+        assert_eq!(query["start_date"], "2024-06-01T00:00:00+0000");
+        assert_eq!(query["end_date"], "2024-06-15T12:00:00+0000");
+    }
+
+    #[async_std::test]
+    async fn admin_reconcile_rejects_invalid_ranges() {
+        for body in [
+            json!({"start": "2024-06-10", "end": "2024-06-01"}),
+            json!({"start": "2024-06-16"}),
+            json!({"start": "2023-06-15", "end": "2024-06-15"}),
+        ] {
+            let http = transaction_search(fake_upstreams("VERIFIED").0, vec![]);
+            let mut state = test_state(&http, &MockEmail::default());
+            state.paypal_rest_credentials = Some(credentials());
+
+            let res: tide::http::Response = server(state)
+                .respond(admin_reconcile_request(body.clone()))
+                .await
+                .expect("response");
+
+            assert_eq!(res.status(), StatusCode::BadRequest, "{}", body);
+            assert!(http.sent().is_empty());
+        }
+    }
+
+    #[async_std::test]
+    async fn admin_reconcile_needs_paypal_rest_credentials() {
+        let (http, _) = fake_upstreams("VERIFIED");
+
+        let res: tide::http::Response = server(test_state(&http, &MockEmail::default()))
+            .respond(admin_reconcile_request(json!({"start": "2024-06-01"})))
+            .await
+            .expect("response");
+
+        assert_eq!(res.status(), StatusCode::NotImplemented);
+        assert!(http.sent().is_empty());
+    }
+}
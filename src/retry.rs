@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+/// Time which retries within one invocation may use up in total, see `RETRY_BUDGET_SECS`.
+///
+/// Shared by every retry in an invocation, so that retrying one slow upstream doesn't leave too
+/// little time for the rest before Azure times the function out.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryBudget {
+    deadline: Option<Instant>, // no limit if `None`
+}
+
+impl RetryBudget {
+    /// A budget starting now, unlimited if `secs` is `0`.
+    pub fn start(secs: u64) -> Self {
+        Self {
+            deadline: (secs > 0).then(|| Instant::now() + Duration::from_secs(secs)),
+        }
+    }
+
+    /// Whether a retry after waiting `delay` would still be within the budget.
+    pub fn allows(&self, delay: Duration) -> bool {
+        self.deadline
+            .is_none_or(|deadline| Instant::now() + delay < deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budgets_allow_retries_within_them() {
+        let budget = RetryBudget::start(60);
+        assert!(budget.allows(Duration::ZERO));
+        assert!(budget.allows(Duration::from_secs(59)));
+        assert!(!budget.allows(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn no_budget_is_unlimited() {
+        assert!(RetryBudget::start(0).allows(Duration::from_secs(u64::from(u32::MAX))));
+    }
+}
@@ -0,0 +1,224 @@
+use serde::Serialize;
+
+/// The longest template data value we send, in characters.
+///
+/// SendGrid rejects a mail whose `dynamic_template_data` is over 10,000 bytes, so no single value may take it over.
+pub const MAX_TEMPLATE_VALUE_CHARS: usize = 1000;
+
+/// A SendGrid v3 `mail/send` request using a dynamic template.
+///
+/// See <https://docs.sendgrid.com/api-reference/mail-send/mail-send>.
+#[derive(Debug, Serialize)]
+pub struct SendGridMail {
+    pub personalizations: Vec<Personalization>,
+    pub from: EmailAddress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<EmailAddress>,
+    pub template_id: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+}
+
+/// The recipients of a SendGrid mail, and their template data.
+#[derive(Debug, Serialize)]
+pub struct Personalization {
+    pub to: Vec<EmailAddress>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<EmailAddress>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bcc: Vec<EmailAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamic_template_data: Option<MembershipTemplateData>,
+}
+
+/// An email address, with an optional display name.
+#[derive(Clone, Debug, Serialize)]
+pub struct EmailAddress {
+    pub email: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Template data for our membership emails.
+#[derive(Debug, Serialize)]
+pub struct MembershipTemplateData {
+    pub member_name: String,
+    pub expires: String,
+    pub status: String,
+    pub membership_type: String, // the MEMBERTYPE merge field, which may well be empty
+}
+
+impl SendGridMail {
+    /// A templated mail to a single recipient.
+    pub fn template(
+        to: &str,
+        from: &str,
+        template_id: &str,
+        dynamic_template_data: Option<MembershipTemplateData>,
+    ) -> Self {
+        Self {
+            personalizations: vec![Personalization {
+                to: vec![EmailAddress::new(to)],
+                cc: vec![],
+                bcc: vec![],
+                dynamic_template_data,
+            }],
+            from: EmailAddress::new(from),
+            reply_to: None,
+            template_id: template_id.to_string(),
+            categories: vec![],
+        }
+    }
+
+    /// Send to a single address instead of the original recipients, keeping the template data as-is.
+    pub fn redirect_to(&mut self, email: &str) {
+        for personalization in &mut self.personalizations {
+            personalization.to = vec![EmailAddress::new(email)];
+            personalization.cc.clear();
+            personalization.bcc.clear();
+        }
+    }
+}
+
+impl MembershipTemplateData {
+    /// Fields which are empty, which would leave blanks in the email, as SendGrid doesn't check template data.
+    pub fn empty_fields(&self) -> Vec<&'static str> {
+        [
+            ("member_name", &self.member_name),
+            ("expires", &self.expires),
+            ("status", &self.status),
+        ]
+        .into_iter()
+        .filter(|(_, value)| value.trim().is_empty())
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// Truncate fields over [`MAX_TEMPLATE_VALUE_CHARS`], such as a very long name, returning which were truncated.
+    pub fn truncate_long_fields(&mut self) -> Vec<&'static str> {
+        [
+            ("member_name", &mut self.member_name),
+            ("expires", &mut self.expires),
+            ("status", &mut self.status),
+            ("membership_type", &mut self.membership_type),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let (end, _) = value.char_indices().nth(MAX_TEMPLATE_VALUE_CHARS)?;
+            value.truncate(end);
+            Some(name)
+        })
+        .collect()
+    }
+}
+
+impl EmailAddress {
+    /// An email address without a display name.
+    pub fn new(email: &str) -> Self {
+        Self {
+            email: email.to_string(),
+            name: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn template_data() -> MembershipTemplateData {
+        MembershipTemplateData {
+            member_name: "Ada".to_string(),
+            expires: "2025-06-15".to_string(),
+            status: "active".to_string(),
+            membership_type: String::new(),
+        }
+    }
+
+    #[test]
+    fn template_mails_match_sendgrid_schema() {
+        let mail = SendGridMail::template(
+            "ada@example.com",
+            "info@squamishaccess.ca",
+            "d-check",
+            Some(template_data()),
+        );
+
+        assert_eq!(
+            serde_json::to_value(&mail).expect("JSON"),
+            json!({
+                "personalizations": [{
+                    "to": [{"email": "ada@example.com"}],
+                    "dynamic_template_data": {
+                        "member_name": "Ada",
+                        "expires": "2025-06-15",
+                        "status": "active",
+                        "membership_type": "",
+                    },
+                }],
+                "from": {"email": "info@squamishaccess.ca"},
+                "template_id": "d-check",
+            })
+        );
+    }
+
+    #[test]
+    fn optional_fields_are_sent_when_set() {
+        let mut mail = SendGridMail::template(
+            "ada@example.com",
+            "info@squamishaccess.ca",
+            "d-notfound",
+            None,
+        );
+        mail.personalizations[0].bcc = vec![EmailAddress::new("records@squamishaccess.ca")];
+        mail.reply_to = Some(EmailAddress {
+            email: "board@squamishaccess.ca".to_string(),
+            name: Some("Squamish Access Society".to_string()),
+        });
+        mail.categories = vec!["membership-check".to_string()];
+
+        assert_eq!(
+            serde_json::to_value(&mail).expect("JSON"),
+            json!({
+                "personalizations": [{
+                    "to": [{"email": "ada@example.com"}],
+                    "bcc": [{"email": "records@squamishaccess.ca"}],
+                }],
+                "from": {"email": "info@squamishaccess.ca"},
+                "reply_to": {"email": "board@squamishaccess.ca", "name": "Squamish Access Society"},
+                "template_id": "d-notfound",
+                "categories": ["membership-check"],
+            })
+        );
+    }
+
+    #[test]
+    fn empty_template_fields_are_found() {
+        assert!(template_data().empty_fields().is_empty());
+
+        let data = MembershipTemplateData {
+            member_name: " ".to_string(),
+            ..template_data()
+        };
+        // Only `membership_type` may be empty.
+        assert_eq!(data.empty_fields(), ["member_name"]);
+    }
+
+    #[test]
+    fn long_template_fields_are_truncated_by_char() {
+        let mut data = template_data();
+        assert!(data.truncate_long_fields().is_empty());
+        assert_eq!(data.member_name, "Ada");
+
+        let mut data = MembershipTemplateData {
+            member_name: "é".repeat(MAX_TEMPLATE_VALUE_CHARS + 1),
+            membership_type: "x".repeat(MAX_TEMPLATE_VALUE_CHARS),
+            ..template_data()
+        };
+        assert_eq!(data.truncate_long_fields(), ["member_name"]);
+        assert_eq!(data.member_name, "é".repeat(MAX_TEMPLATE_VALUE_CHARS));
+        assert_eq!(data.membership_type.len(), MAX_TEMPLATE_VALUE_CHARS);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::sync::PoisonError;
+use std::time::Instant;
+
+use chrono::{Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use tide::{Body, Response, StatusCode};
+
+// The info! logging macro comes from crate::azure_function::logger
+use crate::admin::check_admin_secret;
+use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt, AzureFnRequestExt};
+use crate::mailchimp::{list_members_path, parse_expires, MergeFields};
+use crate::timing::TimedExt;
+use crate::{AppRequest, AppState};
+
+/// Members per page when paging through the MailChimp list, which is MailChimp's maximum.
+const PAGE_SIZE: u32 = 1000;
+
+/// Memberships expiring within this many days are counted as expiring rather than active.
+const EXPIRING_WITHIN_DAYS: i64 = 30;
+
+/// Counts of members by `EXPIRES`, as of a date.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MembershipStats {
+    pub as_of: NaiveDate,
+    pub total: u64,
+    pub active: u64,
+    pub expiring: u64, // within `expiring_within_days`, and still active
+    pub expired: u64,
+    pub no_expiry: u64, // blank or unparseable EXPIRES
+    pub expiring_within_days: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct MembersQuery {
+    fields: &'static str,
+    count: u32,
+    offset: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct MembersPage {
+    #[serde(default)]
+    members: Vec<MemberExpires>,
+    #[serde(default)]
+    total_items: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct MemberExpires {
+    email_address: String,
+    #[serde(default)]
+    merge_fields: MergeFields,
+}
+
+/// Aggregate membership counts for the board, from every MailChimp list.
+///
+/// Paging the whole list is slow, so the result is cached for `STATS_CACHE_SECS`.
+pub async fn admin_stats(req: AppRequest) -> tide::Result<Response> {
+    let mut logger = req.logger();
+
+    check_admin_secret(&req)?;
+
+    let state = req.state();
+
+    let cached = state
+        .stats_cache
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .filter(|(cached_at, _)| cached_at.elapsed() < state.stats_cache_ttl)
+        .map(|(_, stats)| stats.clone());
+    let stats = match cached {
+        Some(stats) => stats,
+        None => {
+            let stats = membership_stats(&mut logger, state).await?;
+            *state
+                .stats_cache
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some((Instant::now(), stats.clone()));
+            stats
+        }
+    };
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&stats)?)
+        .into())
+}
+
+/// Page through the MailChimp lists & count members by `EXPIRES`.
+///
+/// Someone on several lists, e.g. per-currency lists, is counted once by their latest `EXPIRES`.
+async fn membership_stats(
+    logger: &mut AzureFnLogger,
+    state: &AppState,
+) -> tide::Result<MembershipStats> {
+    let mut members: HashMap<String, Option<NaiveDate>> = HashMap::new();
+    for mc_list_id in state.mc_list_ids() {
+        for member in list_members(logger, state, mc_list_id).await? {
+            let expires = parse_expires(&member.merge_fields.expires).ok().flatten();
+            let latest = members
+                .entry(member.email_address.to_lowercase())
+                .or_default();
+            *latest = (*latest).max(expires);
+        }
+    }
+
+    info!(logger, "Stats: counted {} MailChimp members", members.len());
+
+    let expires: Vec<_> = members.into_values().collect();
+    Ok(bucket_by_expiry(&expires, state.clock.today()))
+}
+
+/// Page through a MailChimp list.
+async fn list_members(
+    logger: &AzureFnLogger,
+    state: &AppState,
+    mc_list_id: &str,
+) -> tide::Result<Vec<MemberExpires>> {
+    let mc_path = list_members_path(mc_list_id);
+
+    let mut members = vec![];
+    let mut offset = 0;
+    loop {
+        let query = MembersQuery {
+            fields: "members.email_address,members.merge_fields.EXPIRES,total_items",
+            count: PAGE_SIZE,
+            offset,
+        };
+        let mut res = state
+            .mailchimp
+            .get(&mc_path)
+            .query(&query)?
+            .timed(logger, state, "MailChimp")
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!(
+                    "Mailchimp members: error body: \"{}\"",
+                    state.log_body(&res.body_string().await?)
+                ),
+            ));
+        }
+
+        let page: MembersPage = res.body_json().await?;
+        let fetched = page.members.len() as u32;
+        members.extend(page.members);
+
+        offset += fetched;
+        if fetched == 0 || offset >= page.total_items {
+            break;
+        }
+    }
+    Ok(members)
+}
+
+/// Count memberships as active, expiring soon, expired, or without an expiry, as of `today`.
+fn bucket_by_expiry(expires: &[Option<NaiveDate>], today: NaiveDate) -> MembershipStats {
+    let expiring_by = today + Duration::days(EXPIRING_WITHIN_DAYS);
+
+    let mut stats = MembershipStats {
+        as_of: today,
+        expiring_within_days: EXPIRING_WITHIN_DAYS,
+        ..MembershipStats::default()
+    };
+    for expires in expires {
+        stats.total += 1;
+        match expires {
+            None => stats.no_expiry += 1,
+            Some(expires) if *expires < today => stats.expired += 1,
+            Some(expires) if *expires <= expiring_by => stats.expiring += 1,
+            Some(_) => stats.active += 1,
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::azure_function::detached_logger;
+    use crate::test_support::{json, post, server, test_state, MockEmail, MockHttp, LIST_ID};
+
+    #[async_std::test]
+    async fn members_of_every_list_are_counted_once() {
+        let http = MockHttp::new(|sent| {
+            let members = match sent.url.path() {
+                "/3.0/lists/list_usd/members" => json!([
+                    {"email_address": "Ada@example.com", "merge_fields": {"EXPIRES": "2025-01-01"}},
+                ]),
+                _ => json!([
+                    {"email_address": "ada@example.com", "merge_fields": {"EXPIRES": "2024-01-01"}},
+                    {"email_address": "bob@example.com", "merge_fields": {"EXPIRES": ""}},
+                ]),
+            };
+            let total_items = members.as_array().map_or(0, Vec::len);
+            json(
+                StatusCode::Ok,
+                &json!({"members": members, "total_items": total_items}),
+            )
+        });
+        let mut state = test_state(&http, &MockEmail::default());
+        state.mc_currency_list_ids = HashMap::from([("USD".to_string(), "list_usd".to_string())]);
+
+        let stats = membership_stats(&mut detached_logger("stats"), &state)
+            .await
+            .expect("stats");
+
+        assert_eq!(http.sent().len(), 2);
+        assert!(http.sent()[0].url.path().contains(LIST_ID));
+        assert_eq!(stats.total, 2);
+        assert_eq!(stats.active, 1);
+        assert_eq!(stats.expired, 0);
+        assert_eq!(stats.no_expiry, 1);
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).expect("valid date")
+    }
+
+    #[test]
+    fn members_are_bucketed_by_expiry() {
+        let today = date(2024, 6, 15);
+        let expires = [
+            Some(date(2024, 6, 14)), // yesterday
+            Some(date(2024, 6, 15)), // today, still active
+            Some(date(2024, 7, 15)), // the last day counted as expiring
+            Some(date(2024, 7, 16)), // the first day counted as active
+            Some(date(2025, 6, 15)),
+            None,
+        ];
+
+        assert_eq!(
+            bucket_by_expiry(&expires, today),
+            MembershipStats {
+                as_of: today,
+                total: 6,
+                active: 2,
+                expiring: 2,
+                expired: 1,
+                no_expiry: 1,
+                expiring_within_days: EXPIRING_WITHIN_DAYS,
+            }
+        );
+    }
+
+    #[async_std::test]
+    async fn stats_are_cached() {
+        let http =
+            MockHttp::new(|_| json(StatusCode::Ok, &json!({"members": [], "total_items": 0})));
+        let server = server(test_state(&http, &MockEmail::default()));
+        let stats = || {
+            let mut req = post("/Admin-Stats", "application/json", "");
+            req.insert_header("Authorization", "Bearer admin-secret");
+            req
+        };
+
+        let first: http_types::Response = server.respond(stats()).await.expect("response");
+        let second: http_types::Response = server.respond(stats()).await.expect("response");
+
+        assert_eq!(first.status(), StatusCode::Ok);
+        assert_eq!(second.status(), StatusCode::Ok);
+        assert_eq!(http.sent().len(), 1);
+    }
+}
@@ -0,0 +1,497 @@
+//! Shared fakes for tests: upstream apis, email, configuration & application state.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use http_client::{Error, HttpClient, Request, Response};
+use http_types::headers::Headers;
+use http_types::{Method, StatusCode};
+use serde_json::{json, Value};
+use surf::{Client, Config, Url};
+use tide::Server;
+use tracing::dispatcher::DefaultGuard;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Dispatch, Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+use crate::azure_function::{AzureFnLogger, AzureFnMiddleware};
+use crate::clock::FixedClock;
+use crate::config::ReloadableConfig;
+use crate::email::EmailSender;
+use crate::mailchimp::member_path;
+use crate::paypal::IpnVerifyMethod;
+use crate::sendgrid::SendGridMail;
+use crate::{setup_routes, AppState};
+
+/// The default MailChimp list of [`test_state`].
+pub(crate) const LIST_ID: &str = "list0000aa";
+
+/// A request received by a [`MockHttp`].
+#[derive(Clone, Debug)]
+pub(crate) struct Sent {
+    pub method: Method,
+    pub url: Url,
+    pub headers: Headers,
+    pub body: String,
+}
+
+impl Sent {
+    /// Whether this request was to `host`, e.g. `mailchimp.test`.
+    pub fn is_to(&self, host: &str) -> bool {
+        self.url.host_str() == Some(host)
+    }
+}
+
+type Responder = dyn Fn(&Sent) -> Response + Send + Sync;
+
+/// A fake for every upstream api, answering each request with `responder` and recording it.
+#[derive(Clone)]
+pub(crate) struct MockHttp {
+    responder: Arc<Responder>,
+    sent: Arc<Mutex<Vec<Sent>>>,
+}
+
+impl fmt::Debug for MockHttp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockHttp")
+            .field("sent", &self.sent)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MockHttp {
+    pub fn new(responder: impl Fn(&Sent) -> Response + Send + Sync + 'static) -> Self {
+        Self {
+            responder: Arc::new(responder),
+            sent: Arc::default(),
+        }
+    }
+
+    /// Answer a request as this mock would, without recording it, e.g. to wrap it in another mock.
+    pub fn respond(&self, sent: &Sent) -> Response {
+        (self.responder)(sent)
+    }
+
+    /// The requests received so far, in order.
+    pub fn sent(&self) -> Vec<Sent> {
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The requests received so far by `host`.
+    pub fn sent_to(&self, host: &str) -> Vec<Sent> {
+        self.sent()
+            .into_iter()
+            .filter(|sent| sent.is_to(host))
+            .collect()
+    }
+
+    /// A surf client which sends to this mock, relative to `base_url`.
+    pub fn client(&self, base_url: &str) -> Client {
+        Config::new()
+            .set_base_url(Url::parse(base_url).expect("test base url"))
+            .set_http_client(self.clone())
+            .try_into()
+            .expect("infallible without a default client")
+    }
+}
+
+#[http_client::async_trait]
+impl HttpClient for MockHttp {
+    async fn send(&self, mut req: Request) -> Result<Response, Error> {
+        let sent = Sent {
+            method: req.method(),
+            url: req.url().clone(),
+            headers: req.as_ref().clone(),
+            body: req.body_string().await?,
+        };
+        let res = (self.responder)(&sent);
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sent);
+        Ok(res)
+    }
+}
+
+/// A JSON response.
+pub(crate) fn json(status: StatusCode, body: &Value) -> Response {
+    let mut res = Response::new(status);
+    res.set_body(body.clone());
+    res
+}
+
+/// A plain text response.
+pub(crate) fn text(status: StatusCode, body: &str) -> Response {
+    let mut res = Response::new(status);
+    res.set_body(body);
+    res
+}
+
+/// MailChimp list members by api path, as kept by [`fake_upstreams`].
+pub(crate) type Members = Arc<Mutex<HashMap<String, Value>>>;
+
+/// Fake PayPal & MailChimp apis.
+///
+/// PayPal answers every IPN verification with `verification`. MailChimp keeps `members` by api path,
+/// answering a GET with the member or a `404`, a PUT as MailChimp would, by creating or updating them,
+/// and a PATCH by updating them.
+pub(crate) fn fake_upstreams(verification: &'static str) -> (MockHttp, Members) {
+    let members = Members::default();
+    let http = MockHttp::new({
+        let members = members.clone();
+        move |sent| {
+            if sent.is_to("paypal.test") {
+                return text(StatusCode::Ok, verification);
+            }
+            let mut members = members.lock().unwrap_or_else(PoisonError::into_inner);
+            let path = sent.url.path().to_string();
+            match sent.method {
+                Method::Get => match members.get(&path) {
+                    Some(member) => json(StatusCode::Ok, member),
+                    None => json(StatusCode::NotFound, &json!({"status": 404})),
+                },
+                Method::Put => {
+                    let req: Value = serde_json::from_str(&sent.body).expect("MailChimp PUT JSON");
+                    let member = members.entry(path).or_insert_with(|| {
+                        json!({
+                            "email_address": req["email_address"],
+                            "status": req["status_if_new"],
+                            "merge_fields": {},
+                        })
+                    });
+                    if let Some(status) = req.get("status") {
+                        member["status"] = status.clone();
+                    }
+                    if let (Some(Value::Object(merge_fields)), Some(Value::Object(updates))) =
+                        (member.get_mut("merge_fields"), req.get("merge_fields"))
+                    {
+                        merge_fields.extend(updates.clone());
+                    }
+                    json(StatusCode::Ok, member)
+                }
+                Method::Patch => {
+                    let req: Value =
+                        serde_json::from_str(&sent.body).expect("MailChimp PATCH JSON");
+                    let Some(member) = members.get_mut(&path) else {
+                        return json(StatusCode::NotFound, &json!({"status": 404}));
+                    };
+                    if let (Some(Value::Object(merge_fields)), Some(Value::Object(updates))) =
+                        (member.get_mut("merge_fields"), req.get("merge_fields"))
+                    {
+                        merge_fields.extend(updates.clone());
+                    }
+                    json(StatusCode::Ok, member)
+                }
+                _ => text(StatusCode::NotFound, ""),
+            }
+        }
+    });
+    (http, members)
+}
+
+/// Add a member to a [`fake_upstreams`] list.
+pub(crate) fn insert_member(
+    members: &Members,
+    list_id: &str,
+    email: &str,
+    status: &str,
+    merge_fields: Value,
+) {
+    members
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(
+            format!("/{}", member_path(list_id, email)),
+            json!({
+                "email_address": email,
+                "status": status,
+                "merge_fields": merge_fields,
+            }),
+        );
+}
+
+/// A member of a [`fake_upstreams`] list, if they are on it.
+pub(crate) fn get_member(members: &Members, list_id: &str, email: &str) -> Option<Value> {
+    members
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&format!("/{}", member_path(list_id, email)))
+        .cloned()
+}
+
+/// Records mails rather than sending them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MockEmail {
+    pub sent: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockEmail {
+    /// The mails sent so far, as their SendGrid JSON.
+    pub fn sent(&self) -> Vec<Value> {
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[surf::utils::async_trait]
+impl EmailSender for MockEmail {
+    async fn send(
+        &self,
+        _logger: &AzureFnLogger,
+        _state: &AppState,
+        mail: &SendGridMail,
+    ) -> tide::Result<()> {
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(serde_json::to_value(mail)?);
+        Ok(())
+    }
+
+    async fn ping(&self) -> tide::Result<()> {
+        Ok(())
+    }
+}
+
+/// The instant [`test_state`]'s clock is fixed at.
+pub(crate) fn test_now() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 6, 15, 12, 0, 0)
+        .single()
+        .expect("valid date")
+}
+
+/// The default reloadable configuration, with only the required variables set.
+pub(crate) fn test_config() -> ReloadableConfig {
+    ReloadableConfig::from_vars(test_vars(&[])).expect("default test configuration")
+}
+
+/// Configuration variables with only the required ones set, and `vars` on top.
+///
+/// Tests don't read the environment, which is shared by tests running at the same time.
+pub(crate) fn test_vars<'vars>(
+    vars: &'vars [(&'static str, &'static str)],
+) -> impl Fn(&str) -> Option<String> + 'vars {
+    move |name| {
+        vars.iter()
+            .chain(&[
+                ("TEMPLATE_MEMBERSHIP_CHECK", "d-check"),
+                ("TEMPLATE_MEMBERSHIP_NOTFOUND", "d-notfound"),
+            ])
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value.to_string())
+    }
+}
+
+/// Application state as `main` would set it up with defaults, with every upstream at `http`.
+///
+/// Upstreams are told apart by host: `mailchimp.test`, `paypal.test`, `paypal-rest.test` & `callback.test`.
+pub(crate) fn test_state(http: &MockHttp, email: &MockEmail) -> AppState {
+    AppState {
+        admin_secret: Some("admin-secret".to_string()),
+        base_currency: None,
+        callback: http.client("https://callback.test/"),
+        clock: Box::new(FixedClock(test_now())),
+        config: RwLock::new(Arc::new(test_config())),
+        cors_allowed_origins: vec![],
+        idempotency_keys: Mutex::default(),
+        ipn_queue: None,
+        ipn_verify_method: IpnVerifyMethod::Postback,
+        log_body_max_len: 1000,
+        log_raw_ipn: false,
+        log_upstream_timing: false,
+        mailchimp: http.client("https://mailchimp.test/"),
+        mc_custom_merge_field: "CUSTOM".to_string(),
+        mc_list_id: LIST_ID.to_string(),
+        mc_currency_list_ids: HashMap::new(),
+        membership_check_html: false,
+        membership_token_secret: None,
+        paypal: http.client("https://paypal.test/"),
+        paypal_rest: http.client("https://paypal-rest.test/"),
+        paypal_rest_credentials: None,
+        paypal_sandbox: false,
+        paypal_verify_timeout: Duration::from_secs(5),
+        ping_body: false,
+        sendgrid_from_name: None,
+        sendgrid_reply_to: None,
+        skip_paypal_verify: false,
+        stats_cache: Mutex::default(),
+        stats_cache_ttl: Duration::from_secs(60),
+        subscription_callback: None,
+        test_ipn_dry_run: false,
+        zero_decimal_currencies: vec![],
+        email: Box::new(email.clone()),
+    }
+}
+
+/// A `web_accept` IPN for a completed membership payment.
+pub(crate) fn ipn(txn_id: &str, email: &str, amount: &str) -> String {
+    format!(
+        "txn_type=web_accept&payment_status=Completed&txn_id={}&payer_email={}&mc_gross={}&mc_currency=CAD&first_name=Ada&last_name=Lovelace",
+        txn_id,
+        email.replace('@', "%40"),
+        amount
+    )
+}
+
+/// A server with all of our routes, as `main` sets up, but without the Azure middleware.
+pub(crate) fn server(state: AppState) -> Server<Arc<AppState>> {
+    let mut server = tide::with_state(Arc::new(state));
+    setup_routes(&mut server);
+    server
+}
+
+/// A server with all of our routes behind the Azure middleware, for the envelope & its logs.
+pub(crate) fn azure_server(state: AppState) -> Server<Arc<AppState>> {
+    let mut server = tide::with_state(Arc::new(state));
+    server.with(AzureFnMiddleware::new());
+    setup_routes(&mut server);
+    server
+}
+
+/// An invocation of one of our routes, with the external request in the envelope, as Azure sends it.
+pub(crate) fn invocation(
+    path: &str,
+    method: &str,
+    headers: Value,
+    body: &str,
+) -> http_types::Request {
+    let envelope = json!({
+        "Data": {
+            "req": {
+                "Method": method,
+                "Headers": headers,
+                "Body": body,
+            },
+        },
+        "Metadata": {},
+    });
+    post(path, "application/json", &envelope.to_string())
+}
+
+/// A POST to one of our routes, as Azure would forward it, without the envelope.
+pub(crate) fn post(path: &str, content_type: &str, body: &str) -> http_types::Request {
+    let mut req = http_types::Request::new(
+        Method::Post,
+        Url::parse("http://localhost/")
+            .and_then(|base| base.join(path))
+            .expect("test url"),
+    );
+    req.insert_header("Content-Type", content_type);
+    req.set_body(body);
+    req
+}
+
+/// Records the fields of every span & event, for asserting on what is traced.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TraceRecorder {
+    spans: Arc<Mutex<Vec<(&'static Metadata<'static>, Fields)>>>,
+    events: Arc<Mutex<Vec<Fields>>>,
+    entered: Arc<Mutex<Vec<Id>>>,
+}
+
+/// Field values by name, formatted with `Debug` unless they are strings.
+pub(crate) type Fields = HashMap<String, String>;
+
+impl TraceRecorder {
+    /// Record everything traced on this thread, until the guard is dropped.
+    pub fn set_default(&self) -> DefaultGuard {
+        tracing::dispatcher::set_default(&Dispatch::new(self.clone()))
+    }
+
+    /// The fields of the first span named `name`.
+    pub fn span(&self, name: &str) -> Option<Fields> {
+        self.spans
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|(metadata, _)| metadata.name() == name)
+            .map(|(_, fields)| fields.clone())
+    }
+
+    /// The fields of every event, in order, including the `message`.
+    pub fn events(&self) -> Vec<Fields> {
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+struct FieldVisitor<'fields>(&'fields mut Fields);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+}
+
+impl Subscriber for TraceRecorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = HashMap::new();
+        span.record(&mut FieldVisitor(&mut fields));
+        let mut spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        spans.push((span.metadata(), fields));
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, fields)) = spans.get_mut(span.into_u64() as usize - 1) {
+            values.record(&mut FieldVisitor(fields));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = HashMap::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(fields);
+    }
+
+    fn enter(&self, span: &Id) {
+        self.entered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(span.clone());
+    }
+
+    fn exit(&self, _span: &Id) {
+        self.entered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+    }
+
+    // For `Span::current()`.
+    fn current_span(&self) -> Current {
+        let entered = self.entered.lock().unwrap_or_else(PoisonError::into_inner);
+        let spans = self.spans.lock().unwrap_or_else(PoisonError::into_inner);
+        match entered.last() {
+            Some(id) => Current::new(id.clone(), spans[id.into_u64() as usize - 1].0),
+            None => Current::none(),
+        }
+    }
+}
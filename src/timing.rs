@@ -0,0 +1,120 @@
+use std::time::Instant;
+
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, RequestBuilder, Response};
+
+// The info! logging macro comes from crate::azure_function::logger
+use crate::azure_function::{AzureFnLogger, AzureFnLoggerExt};
+use crate::AppState;
+
+/// Logs the method, path, status & elapsed time of a request to an upstream api.
+///
+/// Usually added via [`TimedExt::timed`].
+#[derive(Debug)]
+pub struct UpstreamTiming {
+    upstream: &'static str,
+    logger: AzureFnLogger,
+}
+
+#[surf::utils::async_trait]
+impl Middleware for UpstreamTiming {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        let method = req.method();
+        let path = req.url().path().to_string();
+
+        let start = Instant::now();
+        let res = next.run(req, client).await;
+        let elapsed = start.elapsed();
+
+        let mut logger = self.logger.clone();
+        match &res {
+            Ok(res) => info!(
+                logger,
+                "Upstream {}: {} {} - status: {} - {:?}",
+                self.upstream,
+                method,
+                path,
+                res.status(),
+                elapsed
+            ),
+            Err(error) => info!(
+                logger,
+                "Upstream {}: {} {} - error: {} - {:?}",
+                self.upstream,
+                method,
+                path,
+                error,
+                elapsed
+            ),
+        }
+        res
+    }
+}
+
+/// Time requests to upstream apis, if `LOG_UPSTREAM_TIMING` is set.
+pub trait TimedExt {
+    fn timed(self, logger: &AzureFnLogger, state: &AppState, upstream: &'static str) -> Self;
+}
+
+impl TimedExt for RequestBuilder {
+    fn timed(self, logger: &AzureFnLogger, state: &AppState, upstream: &'static str) -> Self {
+        if state.log_upstream_timing {
+            self.middleware(UpstreamTiming {
+                upstream,
+                logger: logger.clone(),
+            })
+        } else {
+            self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::azure_function::{detached_logger, take_logs};
+    use crate::ipn_handler::process_ipn;
+    use crate::test_support::{fake_upstreams, ipn, test_state, MockEmail};
+
+    async fn upstream_logs(log_upstream_timing: bool) -> Vec<String> {
+        let (http, _) = fake_upstreams("VERIFIED");
+        let mut state = test_state(&http, &MockEmail::default());
+        state.log_upstream_timing = log_upstream_timing;
+        let logger = detached_logger("test");
+
+        process_ipn(
+            logger.clone(),
+            &state,
+            ipn("TXN1", "ada@example.com", "20.00"),
+            false,
+        )
+        .await
+        .expect("processed");
+
+        take_logs(&logger)
+            .await
+            .into_iter()
+            .filter(|line| line.contains("Upstream "))
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn each_upstream_call_is_timed() {
+        let logs = upstream_logs(true).await;
+
+        assert_eq!(logs.len(), 3, "{:#?}", logs);
+        assert!(logs
+            .iter()
+            .any(|line| line.contains("Upstream PayPal: POST /cgi-bin/webscr - status: 200")));
+        for method in ["GET", "PUT"] {
+            assert!(logs.iter().any(|line| line.contains(&format!(
+                "Upstream MailChimp: {} /3.0/lists/list0000aa/members/",
+                method
+            ))));
+        }
+    }
+
+    #[async_std::test]
+    async fn timing_is_off_by_default() {
+        assert!(upstream_logs(false).await.is_empty());
+    }
+}